@@ -28,4 +28,28 @@ impl GameState {
     pub fn reset(&mut self) {
         self.spatulas.clear();
     }
+
+    /// A cheap fingerprint of this state, used to notice when a client's copy has drifted from
+    /// the server's.
+    ///
+    /// This is stable across builds and platforms, and doesn't depend on the order of `spatulas`.
+    pub fn checksum(&self) -> u64 {
+        self.spatulas.iter().fold(0, |sum, (&spat, state)| {
+            let hash = fnv1a(FNV_OFFSET, &(spat as u32).to_le_bytes());
+            let hash = state
+                .collection_vec
+                .iter()
+                .fold(hash, |hash, id| fnv1a(hash, &id.0.to_le_bytes()));
+            sum ^ hash
+        })
+    }
+}
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, &b| {
+        (hash ^ u64::from(b)).wrapping_mul(FNV_PRIME)
+    })
 }
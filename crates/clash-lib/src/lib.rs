@@ -1,9 +1,11 @@
 use std::{
     borrow::Borrow,
     fmt::{Debug, Display},
+    str::FromStr,
 };
 
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
 
 pub mod game_state;
 pub mod lobby;
@@ -24,14 +26,6 @@ macro_rules! decl_id {
                 <Self as Display>::fmt(self, f)
             }
         }
-        impl Display for $name {
-            #[inline]
-            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-                // Always diplay IDs in hex
-                write!(f, "{:#X}", self.0)
-            }
-        }
-
         impl From<u32> for $name {
             #[inline]
             fn from(v: u32) -> Self {
@@ -62,3 +56,94 @@ macro_rules! decl_id {
 
 decl_id!(PlayerId);
 decl_id!(LobbyId);
+
+impl Display for PlayerId {
+    #[inline]
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Always diplay IDs in hex
+        write!(f, "{:#X}", self.0)
+    }
+}
+
+/// Crockford's Base32 alphabet, which leaves out I, L, O and U so lobby codes can't be misread.
+const LOBBY_CODE_ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+impl LobbyId {
+    /// Number of characters in the code players use to share a lobby.
+    pub const CODE_LEN: usize = 6;
+    /// Lobby ids are always less than this so that they fit in a lobby code.
+    pub const MAX: u32 = 1 << (5 * Self::CODE_LEN);
+}
+
+/// Lobby ids are displayed as a short code, see [`LobbyId::CODE_LEN`].
+impl Display for LobbyId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let code = (0..Self::CODE_LEN)
+            .rev()
+            .map(|i| LOBBY_CODE_ALPHABET[(self.0 >> (5 * i)) as usize & 0x1F] as char);
+        for c in code {
+            write!(f, "{c}")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Error, Clone, Copy, PartialEq, Eq)]
+#[error("Lobby codes are {} letters and numbers", LobbyId::CODE_LEN)]
+pub struct ParseLobbyIdError;
+
+/// Parses a lobby code, ignoring case, dashes and whitespace. Letters that are easily confused for
+/// digits are read as those digits.
+impl FromStr for LobbyId {
+    type Err = ParseLobbyIdError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut id = 0;
+        let mut len = 0;
+        for c in s.chars().filter(|c| *c != '-' && !c.is_whitespace()) {
+            let c = match c.to_ascii_uppercase() {
+                'O' => '0',
+                'I' | 'L' => '1',
+                c => c,
+            };
+            let digit = LOBBY_CODE_ALPHABET
+                .iter()
+                .position(|&a| a as char == c)
+                .ok_or(ParseLobbyIdError)?;
+            id = (id << 5) | digit as u32;
+            len += 1;
+            if len > Self::CODE_LEN {
+                return Err(ParseLobbyIdError);
+            }
+        }
+        match len == Self::CODE_LEN {
+            true => Ok(Self(id)),
+            false => Err(ParseLobbyIdError),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{LobbyId, ParseLobbyIdError};
+
+    #[test]
+    fn lobby_code() {
+        assert_eq!(LobbyId(0).to_string(), "000000");
+        assert_eq!(LobbyId(63).to_string(), "00001Z");
+        assert_eq!(LobbyId(LobbyId::MAX - 1).to_string(), "ZZZZZZ");
+
+        for id in [0, 63, 0x1234567, LobbyId::MAX - 1] {
+            assert_eq!(LobbyId(id).to_string().parse(), Ok(LobbyId(id)));
+        }
+    }
+
+    #[test]
+    fn parse_lobby_code() {
+        assert_eq!("k7m-2qx".parse(), Ok(LobbyId(0x267A_0AFD)));
+        assert_eq!("oil0l1".parse::<LobbyId>(), "011011".parse());
+        assert_eq!("K7M2Q".parse::<LobbyId>(), Err(ParseLobbyIdError));
+        assert_eq!("K7M2QXX".parse::<LobbyId>(), Err(ParseLobbyIdError));
+        assert_eq!("K7M2QU".parse::<LobbyId>(), Err(ParseLobbyIdError));
+    }
+}
@@ -1,14 +1,36 @@
 use std::collections::HashMap;
 
-use crate::{game_state::GameState, player::NetworkedPlayer, LobbyId, PlayerId, MAX_PLAYERS};
+use crate::{
+    game_state::GameState,
+    net::{Item, LobbyDelta, LobbyMessage},
+    player::NetworkedPlayer,
+    LobbyId, PlayerId, MAX_PLAYERS,
+};
+use bfbb::Spatula;
 use serde::{Deserialize, Serialize};
 
+/// Longest lobby name, in characters, that the server will accept.
+pub const MAX_LOBBY_NAME_LEN: usize = 32;
+
 #[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+#[serde(default)]
 pub struct LobbyOptions {
     pub ng_plus: bool,
     pub lab_door_cost: u8,
     pub tier_count: u8,
     pub spat_scores: [u32; MAX_PLAYERS],
+    /// Name shown in the lobby browser, the host's name is used when this is empty.
+    pub name: String,
+    /// Whether the lobby is shown in the lobby browser.
+    pub public: bool,
+    /// Password needed to join the lobby as a player.
+    pub password: Password,
+    /// Password needed to spectate the lobby, separate from `password`.
+    pub spectate_password: Password,
+    /// Whether the lobby can be spectated at all.
+    pub allow_spectators: bool,
+    /// How players with the same score are placed in the final standings.
+    pub tie_breaker: TieBreaker,
 }
 
 impl Default for LobbyOptions {
@@ -18,10 +40,91 @@ impl Default for LobbyOptions {
             ng_plus: false,
             tier_count: 3,
             spat_scores: [100, 75, 50, 30, 20, 10],
+            name: String::new(),
+            public: false,
+            password: Password::None,
+            spectate_password: Password::None,
+            allow_spectators: true,
+            tie_breaker: TieBreaker::default(),
         }
     }
 }
 
+/// A lobby password as it appears in [`LobbyOptions`].
+///
+/// The host sets a password by sending [`Password::New`]. The server only keeps a hash of it and
+/// shares the lobby's options with [`Password::Protected`] in its place, which the host can send
+/// back to keep the current password.
+#[derive(Default, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub enum Password {
+    #[default]
+    None,
+    Protected,
+    New(String),
+}
+
+impl Password {
+    /// True if a password is needed.
+    pub fn is_set(&self) -> bool {
+        !matches!(self, Password::None)
+    }
+}
+
+impl std::fmt::Debug for Password {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Keep passwords out of logs
+        match self {
+            Password::None => f.write_str("None"),
+            Password::Protected => f.write_str("Protected"),
+            Password::New(_) => f.write_str("New(..)"),
+        }
+    }
+}
+
+/// How the server orders players who finish a game with the same score.
+#[derive(Debug, Default, Copy, Clone, Deserialize, Serialize, PartialEq, Eq)]
+pub enum TieBreaker {
+    /// Tied players share their place.
+    None,
+    /// The player who was first to collect the most spatulas places higher.
+    #[default]
+    MostGold,
+    /// The player who collected their last spatula earliest places higher. Spatulas that aren't
+    /// worth any points, such as the one that ends the game, don't count.
+    EarliestFinish,
+    /// The player who collected "The Small Shall Rule... Or Not" places higher.
+    SmallShallRule,
+}
+
+impl TieBreaker {
+    pub const ALL: [TieBreaker; 4] = [
+        TieBreaker::None,
+        TieBreaker::MostGold,
+        TieBreaker::EarliestFinish,
+        TieBreaker::SmallShallRule,
+    ];
+}
+
+impl std::fmt::Display for TieBreaker {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            TieBreaker::None => "None",
+            TieBreaker::MostGold => "Most Gold Spatulas",
+            TieBreaker::EarliestFinish => "Earliest Finish",
+            TieBreaker::SmallShallRule => "Small Shall Rule",
+        })
+    }
+}
+
+/// A player's place in a finished game, see [`NetworkedLobby::standings`].
+#[derive(Debug, Copy, Clone, Deserialize, Serialize, PartialEq, Eq)]
+pub struct Standing {
+    pub player_id: PlayerId,
+    /// Starts at 1. Players that are still tied after the lobby's [`TieBreaker`] share a place,
+    /// and the following place is skipped.
+    pub place: usize,
+}
+
 #[derive(Debug, Copy, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub enum GamePhase {
     Setup,
@@ -39,6 +142,33 @@ pub struct NetworkedLobby {
     // TODO: Refactor this option out, we don't create a lobby until a player has connected to the server
     //       so we should be able to specify them as the host. When the last player leaves we close the lobby.
     pub host_id: Option<PlayerId>,
+    /// Number of deltas that have been applied to this lobby, used to notice missed updates.
+    pub sequence: u64,
+    /// Players in the order they finished, decided by the server when the game ends. Empty until
+    /// then.
+    pub standings: Vec<Standing>,
+}
+
+/// What the lobby browser shows about a public lobby, see [`NetworkedLobby::summary`].
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct LobbySummary {
+    pub lobby_id: LobbyId,
+    pub name: String,
+    pub player_count: usize,
+    pub game_phase: GamePhase,
+    pub options: LobbyOptions,
+}
+
+/// The result of [`NetworkedLobby::apply_stamped`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DeltaOutcome {
+    /// The delta was applied and the lobby still matches the server's.
+    Applied,
+    /// The delta is already reflected in this lobby and was ignored.
+    Stale,
+    /// An earlier delta was missed or the game state no longer matches the server's, so this lobby
+    /// needs to be replaced with a new snapshot.
+    Desync,
 }
 
 impl NetworkedLobby {
@@ -50,32 +180,207 @@ impl NetworkedLobby {
             players: HashMap::new(),
             game_phase: GamePhase::Setup,
             host_id: None,
+            sequence: 0,
+            standings: Vec::new(),
         }
     }
 
     pub fn reset(&mut self) {
         self.game_state.reset();
         self.game_phase = GamePhase::Setup;
+        self.standings.clear();
         self.players.values_mut().for_each(NetworkedPlayer::reset);
     }
 
+    /// Describe this lobby for the lobby browser.
+    pub fn summary(&self) -> LobbySummary {
+        let name = match self.options.name.is_empty() {
+            false => self.options.name.clone(),
+            true => {
+                let host = self.host_id.and_then(|id| self.players.get(&id));
+                match host {
+                    Some(host) if !host.options.name.is_empty() => {
+                        format!("{}'s Lobby", host.options.name)
+                    }
+                    _ => format!("Lobby {}", self.lobby_id),
+                }
+            }
+        };
+        LobbySummary {
+            lobby_id: self.lobby_id,
+            name,
+            player_count: self.players.len(),
+            game_phase: self.game_phase,
+            options: self.options.clone(),
+        }
+    }
+
     /// True when all connected players are on the Main Menu
     pub fn can_start(&self) -> bool {
         // TODO: Now find a way to skip/remove the demo cutscene to make it easier to start a game
         self.players.values().all(|p| p.ready_to_start)
     }
+
+    /// Records `player_id` as the next collector of `spat` and awards them the points for the tier
+    /// it was collected at.
+    ///
+    /// This does no validation, it is up to the server to decide whether a collection is allowed.
+    pub fn collect_spatula(&mut self, player_id: PlayerId, spat: Spatula) {
+        let state = self.game_state.spatulas.entry(spat).or_default();
+        state.collection_vec.push(player_id);
+        let tier = state.collection_vec.len();
+
+        // CBL spatulas aren't worth any points
+        if spat == Spatula::KahRahTae || spat == Spatula::TheSmallShallRuleOrNot {
+            return;
+        }
+        if let Some(player) = self.players.get_mut(&player_id) {
+            player.score += self.options.spat_scores.get(tier - 1).unwrap_or(&0);
+        }
+    }
+
+    /// Apply a delta stamped with the server's `sequence` and game state `checksum` after applying it.
+    pub fn apply_stamped(
+        &mut self,
+        delta: &LobbyDelta,
+        sequence: u64,
+        checksum: u64,
+    ) -> DeltaOutcome {
+        if sequence <= self.sequence {
+            return DeltaOutcome::Stale;
+        }
+        if sequence != self.sequence + 1 {
+            return DeltaOutcome::Desync;
+        }
+
+        self.apply(delta);
+        match self.game_state.checksum() == checksum {
+            true => DeltaOutcome::Applied,
+            false => DeltaOutcome::Desync,
+        }
+    }
+
+    /// Apply an incremental update to this lobby.
+    pub fn apply(&mut self, delta: &LobbyDelta) {
+        self.sequence += 1;
+        let (&player_id, action) = match delta {
+            LobbyDelta::Action { player_id, action } => (player_id, action),
+            LobbyDelta::PlayerJoined { player_id, player } => {
+                self.players.insert(*player_id, player.clone());
+                return;
+            }
+            LobbyDelta::PlayerLeft { player_id } => {
+                self.players.remove(player_id);
+                return;
+            }
+            LobbyDelta::PlayerConnection {
+                player_id,
+                connected,
+            } => {
+                if let Some(p) = self.players.get_mut(player_id) {
+                    p.connected = *connected;
+                    if !connected {
+                        p.latency = None;
+                    }
+                }
+                return;
+            }
+            LobbyDelta::PlayerLatency { player_id, latency } => {
+                if let Some(p) = self.players.get_mut(player_id) {
+                    p.latency = Some(*latency);
+                }
+                return;
+            }
+            LobbyDelta::HostChanged { host_id } => {
+                self.host_id = *host_id;
+                return;
+            }
+            LobbyDelta::Standings { standings } => {
+                self.standings = standings.clone();
+                return;
+            }
+        };
+
+        match action {
+            LobbyMessage::ResetLobby => self.reset(),
+            LobbyMessage::GameBegin => {
+                self.reset();
+                self.game_phase = GamePhase::Playing;
+            }
+            LobbyMessage::GameEnd => self.game_phase = GamePhase::Finished,
+            LobbyMessage::GameOptions { options } => self.options = options.clone(),
+            LobbyMessage::GameItemCollected {
+                item: Item::Spatula(spat),
+            } => self.collect_spatula(player_id, *spat),
+            LobbyMessage::PlayerOptions { options } => {
+                if let Some(p) = self.players.get_mut(&player_id) {
+                    p.options = options.clone();
+                }
+            }
+            LobbyMessage::PlayerCanStart(can_start) => {
+                if let Some(p) = self.players.get_mut(&player_id) {
+                    p.ready_to_start = *can_start;
+                }
+            }
+            LobbyMessage::GameCurrentLevel { level } => {
+                if let Some(p) = self.players.get_mut(&player_id) {
+                    p.current_level = *level;
+                }
+            }
+            LobbyMessage::TransferHost { player_id } => {
+                if self.players.contains_key(player_id) {
+                    self.host_id = Some(*player_id);
+                }
+            }
+            // Removed players are broadcast as `PlayerLeft` instead
+            LobbyMessage::KickPlayer { .. } | LobbyMessage::BanPlayer { .. } => {}
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::time::Duration;
+
     use bfbb::Spatula;
 
-    use super::{GamePhase, NetworkedLobby};
+    use super::{DeltaOutcome, GamePhase, NetworkedLobby, Password, Standing};
     use crate::{
         game_state::SpatulaState,
+        net::{LobbyDelta, LobbyMessage},
         player::{NetworkedPlayer, PlayerOptions},
     };
 
+    #[test]
+    fn password_debug() {
+        let password = Password::New("hunter2".to_owned());
+        assert!(!format!("{password:?}").contains("hunter2"));
+        assert_eq!(format!("{:?}", Password::Protected), "Protected");
+    }
+
+    #[test]
+    fn summary() {
+        let mut lobby = NetworkedLobby::new(0xAB);
+        assert_eq!(lobby.summary().name, "Lobby 00005B");
+
+        lobby.host_id = Some(1.into());
+        lobby.players.insert(
+            1.into(),
+            NetworkedPlayer::new(
+                PlayerOptions {
+                    name: "Patrick".to_owned(),
+                    ..Default::default()
+                },
+                0,
+            ),
+        );
+        assert_eq!(lobby.summary().name, "Patrick's Lobby");
+        assert_eq!(lobby.summary().player_count, 1);
+
+        lobby.options.name = "Pickup".to_owned();
+        assert_eq!(lobby.summary().name, "Pickup");
+    }
+
     #[test]
     fn can_start() {
         let mut lobby = NetworkedLobby::new(0);
@@ -120,4 +425,137 @@ mod tests {
         assert_eq!(lobby.players.len(), 1);
         assert_eq!(lobby.players.get(&0).unwrap().score, 0);
     }
+
+    #[test]
+    fn apply_player_deltas() {
+        let mut lobby = NetworkedLobby::new(0);
+        lobby.apply(&LobbyDelta::PlayerJoined {
+            player_id: 0.into(),
+            player: NetworkedPlayer::new(PlayerOptions::default(), 0),
+        });
+        lobby.apply(&LobbyDelta::PlayerJoined {
+            player_id: 1.into(),
+            player: NetworkedPlayer::new(PlayerOptions::default(), 1),
+        });
+        assert_eq!(lobby.players.len(), 2);
+
+        lobby.apply(&LobbyDelta::Action {
+            player_id: 1.into(),
+            action: LobbyMessage::PlayerCanStart(true),
+        });
+        assert!(lobby.players.get(&1).unwrap().ready_to_start);
+        assert!(!lobby.players.get(&0).unwrap().ready_to_start);
+
+        lobby.apply(&LobbyDelta::PlayerConnection {
+            player_id: 1.into(),
+            connected: false,
+        });
+        assert!(!lobby.players.get(&1).unwrap().connected);
+        assert!(lobby.players.get(&0).unwrap().connected);
+
+        lobby.apply(&LobbyDelta::PlayerLatency {
+            player_id: 0.into(),
+            latency: Duration::from_millis(30),
+        });
+        assert_eq!(
+            lobby.players.get(&0).unwrap().latency,
+            Some(Duration::from_millis(30))
+        );
+
+        lobby.apply(&LobbyDelta::PlayerLeft {
+            player_id: 0.into(),
+        });
+        assert!(!lobby.players.contains_key(&0));
+        assert!(lobby.can_start());
+    }
+
+    #[test]
+    fn apply_game_deltas() {
+        let mut lobby = NetworkedLobby::new(0);
+        lobby
+            .players
+            .insert(0.into(), NetworkedPlayer::new(PlayerOptions::default(), 0));
+
+        lobby.apply(&LobbyDelta::Action {
+            player_id: 0.into(),
+            action: LobbyMessage::GameBegin,
+        });
+        assert_eq!(lobby.game_phase, GamePhase::Playing);
+
+        lobby.apply(&LobbyDelta::Action {
+            player_id: 0.into(),
+            action: LobbyMessage::GameItemCollected {
+                item: Spatula::SpongebobsCloset.into(),
+            },
+        });
+        assert_eq!(
+            lobby.players.get(&0).unwrap().score,
+            lobby.options.spat_scores[0]
+        );
+
+        let standings = vec![Standing {
+            player_id: 0.into(),
+            place: 1,
+        }];
+        lobby.apply(&LobbyDelta::Standings {
+            standings: standings.clone(),
+        });
+        lobby.apply(&LobbyDelta::Action {
+            player_id: 0.into(),
+            action: LobbyMessage::GameEnd,
+        });
+        assert_eq!(lobby.game_phase, GamePhase::Finished);
+        assert_eq!(lobby.standings, standings);
+
+        // Standings only last until the next game
+        lobby.apply(&LobbyDelta::Action {
+            player_id: 0.into(),
+            action: LobbyMessage::GameBegin,
+        });
+        assert!(lobby.standings.is_empty());
+    }
+
+    #[test]
+    fn apply_stamped() {
+        let mut server = NetworkedLobby::new(0);
+        let mut client = server.clone();
+        let collect = |id: u32, spat: Spatula| LobbyDelta::Action {
+            player_id: id.into(),
+            action: LobbyMessage::GameItemCollected { item: spat.into() },
+        };
+        let stamp = |server: &mut NetworkedLobby, delta: &LobbyDelta| {
+            server.apply(delta);
+            (server.sequence, server.game_state.checksum())
+        };
+
+        let first = collect(0, Spatula::SpongebobsCloset);
+        let (sequence, checksum) = stamp(&mut server, &first);
+        assert_eq!(
+            client.apply_stamped(&first, sequence, checksum),
+            DeltaOutcome::Applied
+        );
+        assert_eq!(
+            client.apply_stamped(&first, sequence, checksum),
+            DeltaOutcome::Stale
+        );
+
+        // Missing a delta is noticed by the next one
+        stamp(&mut server, &collect(1, Spatula::CowaBungee));
+        let third = collect(1, Spatula::SpongebobsCloset);
+        let (sequence, checksum) = stamp(&mut server, &third);
+        assert_eq!(
+            client.apply_stamped(&third, sequence, checksum),
+            DeltaOutcome::Desync
+        );
+
+        // As is a game state that doesn't match, even if no deltas were missed
+        let mut client = server.clone();
+        client.game_state.spatulas.remove(&Spatula::CowaBungee);
+        let fourth = collect(0, Spatula::CowaBungee);
+        let (sequence, checksum) = stamp(&mut server, &fourth);
+        assert_eq!(
+            client.apply_stamped(&fourth, sequence, checksum),
+            DeltaOutcome::Desync
+        );
+    }
 }
@@ -0,0 +1,103 @@
+use std::fmt::Debug;
+use std::ops::{BitAnd, BitOr};
+
+use serde::{Deserialize, Serialize};
+
+/// A set of optional protocol features.
+///
+/// This is a bitset rather than a list of enum variants so that a peer can advertise features
+/// that the other side doesn't know about yet without failing to deserialize.
+#[derive(Copy, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    /// Lobby changes are sent as [`LobbyDelta`](super::LobbyDelta)s rather than full lobby snapshots.
+    pub const DELTAS: Self = Self(1 << 0);
+    /// Players that lose their connection can reclaim their place in a lobby with a
+    /// [`ResumeToken`](super::ResumeToken).
+    pub const RESUME: Self = Self(1 << 1);
+    /// The server periodically pings the client to measure latency and detect dead connections.
+    pub const HEARTBEAT: Self = Self(1 << 2);
+    /// Frames sent by the server after [`ConnectionAccept`](super::ServerMessage::ConnectionAccept)
+    /// carry flags and large ones are compressed.
+    pub const COMPRESSION: Self = Self(1 << 3);
+
+    /// Every capability known to this build.
+    pub const ALL: Self =
+        Self(Self::DELTAS.0 | Self::RESUME.0 | Self::HEARTBEAT.0 | Self::COMPRESSION.0);
+
+    const NAMES: &'static [(Self, &'static str)] = &[
+        (Self::DELTAS, "DELTAS"),
+        (Self::RESUME, "RESUME"),
+        (Self::HEARTBEAT, "HEARTBEAT"),
+        (Self::COMPRESSION, "COMPRESSION"),
+    ];
+
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    /// True when every capability in `other` is also in `self`.
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitAnd for Capabilities {
+    type Output = Self;
+
+    fn bitand(self, rhs: Self) -> Self::Output {
+        Self(self.0 & rhs.0)
+    }
+}
+
+impl BitOr for Capabilities {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self::Output {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl Debug for Capabilities {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut set = f.debug_set();
+        set.entries(
+            Self::NAMES
+                .iter()
+                .filter(|(cap, _)| self.contains(*cap))
+                .map(|(_, name)| name),
+        );
+        let unknown = self.0 & !Self::ALL.0;
+        if unknown != 0 {
+            set.entry(&format_args!("{unknown:#X}"));
+        }
+        set.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Capabilities;
+
+    #[test]
+    fn negotiate() {
+        // A newer peer may advertise capabilities we don't know about
+        let remote = Capabilities::DELTAS | Capabilities(1 << 31);
+        let negotiated = remote & Capabilities::ALL;
+        assert!(negotiated.contains(Capabilities::DELTAS));
+        assert_eq!(negotiated, Capabilities::DELTAS);
+
+        assert!(!Capabilities::empty().contains(Capabilities::DELTAS));
+        assert!(Capabilities::DELTAS.contains(Capabilities::empty()));
+    }
+
+    #[test]
+    fn debug() {
+        assert_eq!(format!("{:?}", Capabilities::empty()), "{}");
+        assert_eq!(
+            format!("{:?}", Capabilities::DELTAS | Capabilities(1 << 31)),
+            "{\"DELTAS\", 0x80000000}"
+        );
+    }
+}
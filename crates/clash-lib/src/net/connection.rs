@@ -1,57 +1,192 @@
-use bytes::{Buf, Bytes, BytesMut};
-use std::io::Cursor;
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
-use tokio::{io::AsyncReadExt, io::AsyncWriteExt, io::BufWriter, net::TcpStream};
+use bytes::{Buf, BytesMut};
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use serde::{de::DeserializeOwned, Serialize};
+use std::io::{Cursor, Read, Write};
+use std::marker::PhantomData;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter};
+use tokio::net::TcpStream;
 
-use super::{FrameError, Message};
+use super::FrameError;
 
-pub fn from_socket(socket: TcpStream) -> (ConnectionTx, ConnectionRx) {
+/// Largest frame that will be sent or received unless a different limit is set.
+pub const DEFAULT_MAX_FRAME_LEN: u32 = 16 * 1024 * 1024;
+
+/// Frames at least this many bytes long are compressed unless a different threshold is set.
+pub const DEFAULT_COMPRESSION_THRESHOLD: usize = 512;
+
+/// Set in a frame's flags when its payload is deflate compressed.
+const FLAG_COMPRESSED: u8 = 1 << 0;
+
+type Reader = Box<dyn AsyncRead + Send + Unpin>;
+type Writer = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// Split a socket into a pair of connection halves.
+///
+/// Frames are prefixed with their length as a big-endian `u32`, except for the first frame in each
+/// direction which uses a `u16`. This keeps the `Hello` and the server's response to it readable by
+/// peers of any protocol version, so that they can always be told why they can't connect.
+///
+/// Once compression is enabled, every frame starts with a byte of flags describing how its payload
+/// is encoded. Both halves must agree on when this happens.
+///
+/// `Tx` is the type of frame we're able to send to the remote and `Rx` is the type of frame the remote
+/// is able to send to us.
+pub fn from_socket<Tx, Rx>(socket: TcpStream) -> (ConnectionTx<Tx>, ConnectionRx<Rx>) {
     let (read_stream, write_stream) = socket.into_split();
+    from_split(read_stream, write_stream)
+}
 
+/// Split any bidirectional stream into a pair of connection halves.
+///
+/// Prefer [`from_socket`] or [`from_split`] when the stream can be split natively, since this
+/// has to synchronize the two halves.
+pub fn from_stream<Tx, Rx>(
+    stream: impl AsyncRead + AsyncWrite + Send + 'static,
+) -> (ConnectionTx<Tx>, ConnectionRx<Rx>) {
+    let (read_stream, write_stream) = tokio::io::split(stream);
+    from_split(read_stream, write_stream)
+}
+
+/// Create a pair of connection halves from an already split transport.
+pub fn from_split<Tx, Rx>(
+    read_stream: impl AsyncRead + Send + Unpin + 'static,
+    write_stream: impl AsyncWrite + Send + Unpin + 'static,
+) -> (ConnectionTx<Tx>, ConnectionRx<Rx>) {
     (
         ConnectionTx {
-            write_stream: BufWriter::new(write_stream),
+            write_stream: BufWriter::new(Box::new(write_stream)),
+            first_frame: true,
+            max_frame_len: DEFAULT_MAX_FRAME_LEN,
+            compression_threshold: None,
+            _frame: PhantomData,
         },
         ConnectionRx {
-            read_stream,
+            read_stream: Box::new(read_stream),
             buffer: BytesMut::with_capacity(64),
+            first_frame: true,
+            max_frame_len: DEFAULT_MAX_FRAME_LEN,
+            compression: false,
+            skip: 0,
+            _frame: PhantomData,
         },
     )
 }
 
-#[derive(Debug)]
-pub struct ConnectionTx {
-    write_stream: BufWriter<OwnedWriteHalf>,
+pub struct ConnectionTx<M> {
+    write_stream: BufWriter<Writer>,
+    first_frame: bool,
+    max_frame_len: u32,
+    /// Frames are compressed once they reach this size, if compression is enabled.
+    compression_threshold: Option<usize>,
+    // `fn(M)` so that our auto-traits don't depend on `M`, we never actually store one.
+    _frame: PhantomData<fn(M)>,
 }
-pub struct ConnectionRx {
-    read_stream: OwnedReadHalf,
+pub struct ConnectionRx<M> {
+    read_stream: Reader,
     buffer: BytesMut,
+    first_frame: bool,
+    max_frame_len: u32,
+    compression: bool,
+    /// Bytes remaining of an oversized frame that is being discarded.
+    skip: usize,
+    _frame: PhantomData<fn() -> M>,
 }
 
-impl ConnectionTx {
-    pub async fn write_frame(&mut self, frame: Message) -> Result<(), FrameError> {
-        let mut bytes: Bytes = bincode::serialize(&frame)?.into();
-        if bytes.len() > u16::MAX.into() {
-            return Err(FrameError::FrameLength);
+impl<M> std::fmt::Debug for ConnectionTx<M> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ConnectionTx").finish_non_exhaustive()
+    }
+}
+
+impl<M> ConnectionTx<M> {
+    /// Set the largest frame the remote is willing to receive.
+    pub fn set_max_frame_len(&mut self, max_frame_len: u32) {
+        self.max_frame_len = max_frame_len;
+    }
+
+    /// Start sending frames with flags, compressing those that are at least `threshold` bytes.
+    ///
+    /// The remote must call [`ConnectionRx::enable_compression`] before reading the next frame.
+    pub fn enable_compression(&mut self, threshold: usize) {
+        self.compression_threshold = Some(threshold);
+    }
+}
+
+impl<M: Serialize> ConnectionTx<M> {
+    /// Send a frame to the remote.
+    ///
+    /// Frames that are too large to send fail with [`FrameError::FrameLength`] before anything
+    /// is written, so the connection can still be used afterwards.
+    pub async fn write_frame(&mut self, frame: M) -> Result<(), FrameError> {
+        let mut bytes = bincode::serialize(&frame)?;
+        let flags = match self.compression_threshold {
+            Some(threshold) if bytes.len() >= threshold => {
+                let compressed = compress(&bytes)?;
+                // Not everything gets smaller
+                if compressed.len() < bytes.len() {
+                    bytes = compressed;
+                    Some(FLAG_COMPRESSED)
+                } else {
+                    Some(0)
+                }
+            }
+            Some(_) => Some(0),
+            None => None,
+        };
+
+        let len = bytes.len() + usize::from(flags.is_some());
+        let max = match self.first_frame {
+            true => self.max_frame_len.min(u16::MAX.into()),
+            false => self.max_frame_len,
+        };
+        if len > max as usize {
+            return Err(FrameError::FrameLength { len, max });
+        }
+        if self.first_frame {
+            self.write_stream.write_u16(len as u16).await?;
+            self.first_frame = false;
+        } else {
+            self.write_stream.write_u32(len as u32).await?;
+        }
+        if let Some(flags) = flags {
+            self.write_stream.write_u8(flags).await?;
         }
-        let len = bytes.len() as u16;
-        let len = len.to_be_bytes();
-        self.write_stream.write_all(&len).await?;
-        self.write_stream.write_buf(&mut bytes).await?;
+        self.write_stream.write_all(&bytes).await?;
         self.write_stream.flush().await?;
         Ok(())
     }
 }
 
-impl ConnectionRx {
-    pub async fn read_frame(&mut self) -> Result<Option<Message>, FrameError> {
+impl<M> ConnectionRx<M> {
+    /// Set the largest frame we're willing to receive. Larger frames are discarded.
+    pub fn set_max_frame_len(&mut self, max_frame_len: u32) {
+        self.max_frame_len = max_frame_len;
+    }
+
+    /// Expect every frame from now on to start with flags, see [`ConnectionTx::enable_compression`].
+    pub fn enable_compression(&mut self) {
+        self.compression = true;
+    }
+}
+
+impl<M: DeserializeOwned> ConnectionRx<M> {
+    /// Receive the next frame from the remote, or `None` if the remote closed the connection.
+    ///
+    /// Frames larger than the limit set with [`set_max_frame_len`](Self::set_max_frame_len)
+    /// are skipped and reported with [`FrameError::FrameLength`]. Frames that fail to deserialize
+    /// are also skipped, so in both cases the connection can still be used afterwards.
+    ///
+    /// This method is cancel safe.
+    pub async fn read_frame(&mut self) -> Result<Option<M>, FrameError> {
         loop {
             if let Some(frame) = self.parse_frame()? {
                 return Ok(Some(frame));
             }
 
             if self.read_stream.read_buf(&mut self.buffer).await? == 0 {
-                if self.buffer.is_empty() {
+                if self.buffer.is_empty() && self.skip == 0 {
                     // Remote closed Connection
                     return Ok(None);
                 } else {
@@ -62,26 +197,198 @@ impl ConnectionRx {
         }
     }
 
-    fn parse_frame(&mut self) -> Result<Option<Message>, std::io::Error> {
+    fn parse_frame(&mut self) -> Result<Option<M>, FrameError> {
+        // Throw away as much of a skipped frame as we have
+        if self.skip > 0 {
+            let skipped = self.skip.min(self.buffer.len());
+            self.buffer.advance(skipped);
+            self.skip -= skipped;
+            if self.skip > 0 {
+                return Ok(None);
+            }
+        }
+
         // Use a Cursor to avoid advancing the internal cursor of self.buffer
         let mut buf = Cursor::new(&self.buffer[..]);
+        let (prefix_len, message_len) = match self.first_frame {
+            true if buf.remaining() >= 2 => (2, buf.get_u16().into()),
+            false if buf.remaining() >= 4 => (4, buf.get_u32()),
+            _ => return Ok(None),
+        };
 
-        if self.buffer.len() < 2 {
-            return Ok(None);
+        if message_len > self.max_frame_len {
+            self.first_frame = false;
+            self.buffer.advance(prefix_len);
+            self.skip = message_len as usize;
+            return Err(FrameError::FrameLength {
+                len: message_len as usize,
+                max: self.max_frame_len,
+            });
         }
 
         // Check if the buffer contains the full message yet
-        let message_len = buf.get_u16().into();
-        if self.buffer.remaining() < message_len + std::mem::size_of::<u16>() {
+        if self.buffer.remaining() < prefix_len + message_len as usize {
             return Ok(None);
         }
 
         // Consume the frame from the buffer and deserialize a message
-        self.buffer.advance(std::mem::size_of::<u16>());
-        let message = bincode::deserialize::<Message>(&self.buffer)
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
-        self.buffer.advance(message_len);
+        self.first_frame = false;
+        self.buffer.advance(prefix_len);
+        let mut frame = self.buffer.split_to(message_len as usize);
+        if !self.compression {
+            return Ok(Some(bincode::deserialize::<M>(&frame)?));
+        }
+
+        if !frame.has_remaining() {
+            return Err(invalid_data("Frame is missing its flags").into());
+        }
+        match frame.get_u8() {
+            0 => Ok(Some(bincode::deserialize::<M>(&frame)?)),
+            FLAG_COMPRESSED => {
+                let bytes = decompress(&frame, self.max_frame_len)?;
+                Ok(Some(bincode::deserialize::<M>(&bytes)?))
+            }
+            flags => Err(invalid_data(format!("Unknown frame flags {flags:#X}")).into()),
+        }
+    }
+}
+
+fn compress(bytes: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut encoder = DeflateEncoder::new(Vec::with_capacity(bytes.len() / 2), Compression::fast());
+    encoder.write_all(bytes)?;
+    encoder.finish()
+}
+
+/// Decompress a frame, refusing to produce more than `max_len` bytes.
+fn decompress(bytes: &[u8], max_len: u32) -> std::io::Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(bytes.len() * 2);
+    DeflateDecoder::new(bytes)
+        .take(u64::from(max_len) + 1)
+        .read_to_end(&mut out)?;
+    if out.len() > max_len as usize {
+        return Err(invalid_data("Decompressed frame exceeds maximum length"));
+    }
+    Ok(out)
+}
+
+fn invalid_data(msg: impl Into<String>) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    use super::{from_split, from_stream, FrameError};
+
+    #[tokio::test]
+    async fn round_trip() {
+        let (client, server) = duplex(64);
+        let (mut client_tx, _) = from_stream::<String, ()>(client);
+        let (_, mut server_rx) = from_stream::<(), String>(server);
+
+        // Larger than the duplex buffer, so it will have to be read in pieces
+        let long = "a".repeat(200);
+        let send = tokio::spawn(async move {
+            client_tx.write_frame("hello".to_owned()).await.unwrap();
+            client_tx.write_frame(long).await.unwrap();
+        });
+
+        assert_eq!(server_rx.read_frame().await.unwrap().unwrap(), "hello");
+        assert_eq!(server_rx.read_frame().await.unwrap().unwrap().len(), 200);
+        send.await.unwrap();
+
+        // Remote closed cleanly between frames
+        assert!(server_rx.read_frame().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn large_frames() {
+        let (client, server) = duplex(1024);
+        let (mut client_tx, _) = from_stream::<String, ()>(client);
+        let (_, mut server_rx) = from_stream::<(), String>(server);
+
+        // Only the first frame is limited by its shorter length prefix
+        let long = "a".repeat(100_000);
+        assert!(matches!(
+            client_tx.write_frame(long.clone()).await,
+            Err(FrameError::FrameLength { max: 65535, .. })
+        ));
+        let send = tokio::spawn(async move {
+            client_tx.write_frame("hello".to_owned()).await.unwrap();
+            client_tx.write_frame(long).await.unwrap();
+        });
+
+        assert_eq!(server_rx.read_frame().await.unwrap().unwrap(), "hello");
+        assert_eq!(
+            server_rx.read_frame().await.unwrap().unwrap().len(),
+            100_000
+        );
+        send.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn oversized_frames() {
+        let (client, server) = duplex(64);
+        let (mut client_tx, _) = from_stream::<String, ()>(client);
+        let (_, mut server_rx) = from_stream::<(), String>(server);
+        server_rx.set_max_frame_len(100);
+
+        client_tx.set_max_frame_len(100);
+        assert!(matches!(
+            client_tx.write_frame("a".repeat(200)).await,
+            Err(FrameError::FrameLength { max: 100, .. })
+        ));
+
+        // The receiver skips frames that are too large and carries on with the next one
+        client_tx.set_max_frame_len(1000);
+        let send = tokio::spawn(async move {
+            client_tx.write_frame("a".repeat(200)).await.unwrap();
+            client_tx.write_frame("hello".to_owned()).await.unwrap();
+        });
+        assert!(matches!(
+            server_rx.read_frame().await,
+            Err(FrameError::FrameLength { len: 208, max: 100 })
+        ));
+        assert_eq!(server_rx.read_frame().await.unwrap().unwrap(), "hello");
+        send.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn compression() {
+        let (client, mut server) = duplex(64 * 1024);
+        let (mut client_tx, _) = from_stream::<String, ()>(client);
+        client_tx.enable_compression(100);
+        client_tx.write_frame("hello".to_owned()).await.unwrap();
+        client_tx.write_frame("a".repeat(10_000)).await.unwrap();
+        drop(client_tx);
+
+        // Only the large frame was compressed, and shrank considerably
+        let mut raw = Vec::new();
+        server.read_to_end(&mut raw).await.unwrap();
+        assert_eq!(&raw[..3], &[0, 14, 0]);
+        assert!(raw.len() < 1000);
+
+        let (_, mut server_rx) =
+            from_split::<(), String>(std::io::Cursor::new(raw), tokio::io::sink());
+        server_rx.enable_compression();
+        assert_eq!(server_rx.read_frame().await.unwrap().unwrap(), "hello");
+        assert_eq!(server_rx.read_frame().await.unwrap().unwrap().len(), 10_000);
+        assert!(server_rx.read_frame().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn closed_mid_frame() {
+        let (mut client, server) = duplex(64);
+        let (_, mut server_rx) = from_stream::<(), String>(server);
+
+        // Announce a 10 byte frame but only send part of it
+        client.write_all(&[0, 10, 1, 2, 3]).await.unwrap();
+        drop(client);
 
-        Ok(Some(message))
+        assert!(matches!(
+            server_rx.read_frame().await,
+            Err(FrameError::ConnectionReset)
+        ));
     }
 }
@@ -1,4 +1,4 @@
-use crate::LobbyId;
+use crate::{LobbyId, PlayerId};
 
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
@@ -12,10 +12,61 @@ pub enum ProtocolError {
     // TODO: This probably shouldn't be an error
     #[error("Player disconnected")]
     Disconnected,
-    #[error("Client version '{0}' does not match server version '{1}'")]
+    /// The client's and the server's protocol versions.
+    #[error("Client protocol version {0} is not compatible with server protocol version {1}")]
     VersionMismatch(String, String),
     #[error("{0}")]
     Message(String),
+    // NOTE: Only add new variants after this point, and never change the shape of the ones
+    // before it. Clients from before protocol versions existed decode these with their own copy
+    // of this enum, and need `VersionMismatch` to tell their users why they can't connect.
+    #[error("Session could not be resumed")]
+    InvalidResumeToken,
+    #[error("Timed out waiting for a response")]
+    Timeout,
+    #[error("Server is too busy, try again later")]
+    ServerBusy,
+    #[error(transparent)]
+    Lobby(LobbyError),
+    #[error("Too many messages sent, slow down or you will be disconnected")]
+    RateLimited,
+    #[error("The server can't open any more lobbies right now, try again later")]
+    LobbyLimit,
+    #[error("Incorrect lobby password")]
+    IncorrectPassword,
+    #[error("This lobby can't be spectated")]
+    SpectatingDisabled,
+    #[error("You were removed from the lobby")]
+    Kicked,
+    #[error("The lobby was closed by the server")]
+    LobbyClosed,
+}
+
+/// Reasons the server refused to perform an action in a lobby.
+#[derive(Copy, Clone, Debug, Error, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LobbyError {
+    #[error("Attempted to add a player to a full lobby")]
+    LobbyFull,
+    #[error("Action attempted by Player {0:#} who is not in this lobby")]
+    PlayerInvalid(PlayerId),
+    #[error("Action attempted by Player {0:#} was invalid.")]
+    InvalidAction(PlayerId),
+    #[error("Non-host attempted a host-only action")]
+    NeedsHost,
+    #[error("The Lobby Handle is no longer connected to a lobby.")]
+    HandleInvalid,
+    #[error("You are banned from this lobby")]
+    Banned,
+    #[error("Too many requests sent, slow down or you will be disconnected")]
+    RateLimited,
+    #[error("The player's IP address is unknown, so it can't be banned")]
+    AddressUnknown,
+}
+
+impl From<LobbyError> for ProtocolError {
+    fn from(v: LobbyError) -> Self {
+        Self::Lobby(v)
+    }
 }
 
 impl From<FrameError> for ProtocolError {
@@ -26,8 +77,10 @@ impl From<FrameError> for ProtocolError {
 
 #[derive(Debug, Error)]
 pub enum FrameError {
-    #[error("Frame exceeded max length")]
-    FrameLength,
+    /// A frame was larger than allowed. The frame was not sent or was skipped, so the connection
+    /// can still be used.
+    #[error("Frame of {len} bytes exceeds the maximum length of {max} bytes")]
+    FrameLength { len: usize, max: u32 },
     #[error("Full frame is not available yet.")]
     FrameIncomplete,
     #[error("Connection reset by peer")]
@@ -38,6 +91,16 @@ pub enum FrameError {
     Bincode(bincode::Error),
 }
 
+#[derive(Debug, Error)]
+pub enum TlsError {
+    #[error("No usable certificates found")]
+    NoCertificates,
+    #[error("Failed to read PEM file: {0}")]
+    Pem(#[from] tokio_rustls::rustls::pki_types::pem::Error),
+    #[error("TLS Error: {0}")]
+    Rustls(#[from] tokio_rustls::rustls::Error),
+}
+
 impl From<std::io::Error> for FrameError {
     fn from(e: std::io::Error) -> Self {
         Self::Io(e)
@@ -1,39 +1,196 @@
-use crate::lobby::{LobbyOptions, NetworkedLobby};
-use crate::player::PlayerOptions;
+use crate::lobby::{LobbyOptions, LobbySummary, NetworkedLobby, Standing};
+use crate::player::{NetworkedPlayer, PlayerOptions};
 use crate::{LobbyId, PlayerId};
 use bfbb::{Level, Spatula};
 use serde::{Deserialize, Serialize};
+use std::time::Duration;
 
-use super::ProtocolError;
+use super::{Capabilities, LobbyError, ProtocolError};
 
-// TODO: Take more advantage of the type system (e.g. Client/Server messages)
+/// Messages sent from a client to the server.
 #[derive(Debug, Deserialize, Serialize, Clone)]
-pub enum Message {
-    Error { error: ProtocolError },
-    Version { version: String },
-    ConnectionAccept { player_id: PlayerId },
+pub enum ClientMessage {
+    /// The first message sent by a client after connecting.
+    ///
+    /// NOTE: This must remain the first variant and never change shape, otherwise we won't be able
+    /// to tell clients with an incompatible protocol version why they can't connect.
+    Hello {
+        protocol_version: u16,
+        /// Informational build version of the client, not used for compatibility checks.
+        client_version: String,
+        capabilities: Capabilities,
+    },
     GameHost,
-    GameJoin { lobby_id: LobbyId, spectate: bool },
+    GameJoin {
+        lobby_id: LobbyId,
+        spectate: bool,
+        /// Checked against the lobby's [`password`](LobbyOptions::password), or its
+        /// [`spectate_password`](LobbyOptions::spectate_password) when spectating.
+        password: Option<String>,
+    },
+    /// Reclaim a place in a lobby that was held after losing connection.
+    Resume {
+        token: ResumeToken,
+    },
     Lobby(LobbyMessage),
-    GameLobbyInfo { lobby: NetworkedLobby },
+    /// Sent before intentionally disconnecting so the server doesn't hold our place in the lobby.
+    Leave,
+    /// Response to a [`ServerMessage::Ping`].
+    Pong {
+        nonce: u32,
+    },
+    /// A [`LobbyMessage`] that the server will answer with a [`ServerMessage::Response`]
+    /// carrying the same `id`.
+    Request {
+        id: u32,
+        message: LobbyMessage,
+    },
+    /// Ask for a new [`ServerMessage::GameLobbyInfo`] after our copy of the lobby fell out of sync.
+    Resync,
+    /// Ask for the server's public lobbies instead of entering one. May be sent in place of
+    /// [`ClientMessage::GameHost`] or [`ClientMessage::GameJoin`], and is answered with a
+    /// [`ServerMessage::LobbyList`]. The client can then enter a lobby or disconnect.
+    ListLobbies,
 }
 
-impl From<LobbyMessage> for Message {
+impl From<LobbyMessage> for ClientMessage {
     fn from(msg: LobbyMessage) -> Self {
         Self::Lobby(msg)
     }
 }
 
+/// Messages sent from the server to a client.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub enum ServerMessage {
+    Error {
+        error: ProtocolError,
+    },
+    ConnectionAccept {
+        player_id: PlayerId,
+        /// The optional features that will be used for this connection.
+        capabilities: Capabilities,
+        /// Present when [`Capabilities::RESUME`] was negotiated and the client joined as a player.
+        /// A new token is issued for every connection.
+        resume_token: Option<ResumeToken>,
+        /// The largest frame the server is willing to receive.
+        max_frame_len: u32,
+        /// The server's message of the day, to be shown to the player.
+        motd: Option<String>,
+    },
+    LobbyDelta {
+        delta: LobbyDelta,
+        /// The lobby's [`sequence`](NetworkedLobby::sequence) after applying `delta`.
+        sequence: u64,
+        /// The [`checksum`](crate::game_state::GameState::checksum) of the lobby's game state
+        /// after applying `delta`.
+        checksum: u64,
+    },
+    GameLobbyInfo {
+        lobby: NetworkedLobby,
+    },
+    /// Sent periodically when [`Capabilities::HEARTBEAT`] was negotiated, clients must respond with
+    /// a [`ClientMessage::Pong`] carrying the same `nonce`.
+    Ping {
+        nonce: u32,
+    },
+    /// The outcome of the [`ClientMessage::Request`] with the same `id`.
+    Response {
+        id: u32,
+        result: Result<(), LobbyError>,
+    },
+    /// Answer to [`ClientMessage::ListLobbies`].
+    LobbyList {
+        lobbies: Vec<LobbySummary>,
+    },
+    /// A message from the server's operator, to be shown to the player.
+    Announcement {
+        message: String,
+    },
+}
+
 #[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
 pub enum LobbyMessage {
-    PlayerOptions { options: PlayerOptions },
+    PlayerOptions {
+        options: PlayerOptions,
+    },
     PlayerCanStart(bool),
     ResetLobby,
     GameBegin,
     GameEnd,
-    GameOptions { options: LobbyOptions },
-    GameCurrentLevel { level: Option<Level> },
-    GameItemCollected { item: Item },
+    GameOptions {
+        options: LobbyOptions,
+    },
+    GameCurrentLevel {
+        level: Option<Level>,
+    },
+    GameItemCollected {
+        item: Item,
+    },
+    /// Remove a player from the lobby. Host only, and broadcast as a [`LobbyDelta::PlayerLeft`].
+    KickPlayer {
+        player_id: PlayerId,
+    },
+    /// Remove a player from the lobby and stop them from joining it again, along with anyone at
+    /// the same IP address when `ban_ip` is set. Host only, and broadcast as a
+    /// [`LobbyDelta::PlayerLeft`].
+    BanPlayer {
+        player_id: PlayerId,
+        ban_ip: bool,
+    },
+    /// Make another player the host. Host only.
+    TransferHost {
+        player_id: PlayerId,
+    },
+}
+
+/// An incremental change to a [`NetworkedLobby`].
+///
+/// The server applies each change to its own lobby before broadcasting it, so clients that start
+/// from a [`ServerMessage::GameLobbyInfo`] snapshot can stay in sync by applying deltas in the order
+/// they are received. See [`NetworkedLobby::apply`].
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub enum LobbyDelta {
+    /// A [`LobbyMessage`] that was accepted by the server on behalf of `player_id`
+    Action {
+        player_id: PlayerId,
+        action: LobbyMessage,
+    },
+    PlayerJoined {
+        player_id: PlayerId,
+        player: NetworkedPlayer,
+    },
+    PlayerLeft {
+        player_id: PlayerId,
+    },
+    /// A player lost their connection or resumed their session.
+    PlayerConnection {
+        player_id: PlayerId,
+        connected: bool,
+    },
+    /// The server measured a new round-trip time to a player.
+    PlayerLatency {
+        player_id: PlayerId,
+        latency: Duration,
+    },
+    /// The host left and `host_id` took over, or there is nobody left to be host.
+    HostChanged {
+        host_id: Option<PlayerId>,
+    },
+    /// The final standings of the game that is about to end.
+    Standings {
+        standings: Vec<Standing>,
+    },
+}
+
+/// A secret handed to a player that allows them to resume their session after losing connection.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub struct ResumeToken(pub u128);
+
+impl std::fmt::Debug for ResumeToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Keep tokens out of logs
+        f.write_str("ResumeToken(..)")
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
@@ -1,6 +1,23 @@
-pub use error::{FrameError, ProtocolError};
-pub use message::{Item, LobbyMessage, Message};
+pub use capabilities::Capabilities;
+pub use error::{FrameError, LobbyError, ProtocolError, TlsError};
+pub use message::{ClientMessage, Item, LobbyDelta, LobbyMessage, ResumeToken, ServerMessage};
 
+mod capabilities;
 pub mod connection;
 mod error;
 mod message;
+pub mod tls;
+
+/// Version of the network protocol spoken by this build.
+///
+/// This must be incremented whenever a change is made that older peers can't understand.
+/// Optional additions should be negotiated with a [`Capabilities`] flag instead.
+pub const PROTOCOL_VERSION: u16 = 2;
+
+/// Oldest protocol version that this build is still able to communicate with.
+pub const MIN_PROTOCOL_VERSION: u16 = 2;
+
+/// Check whether a peer speaking protocol version `version` is able to communicate with us.
+pub fn is_compatible(version: u16) -> bool {
+    (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version)
+}
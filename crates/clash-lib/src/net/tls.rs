@@ -0,0 +1,204 @@
+//! TLS configuration shared by the client and server.
+//!
+//! Encrypted streams can be turned into a connection with [`connection::from_stream`](super::connection::from_stream).
+
+use std::path::Path;
+use std::sync::Arc;
+
+use tokio_rustls::rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use tokio_rustls::rustls::crypto::{self, ring, CryptoProvider};
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, UnixTime};
+use tokio_rustls::rustls::{
+    CertificateError, ClientConfig, DigitallySignedStruct, RootCertStore, ServerConfig,
+    SignatureScheme,
+};
+
+pub use tokio_rustls::rustls::pki_types::ServerName;
+pub use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+use super::TlsError;
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+/// Read every certificate from a PEM file.
+pub fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, TlsError> {
+    let certs = CertificateDer::pem_file_iter(path)?.collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(TlsError::NoCertificates);
+    }
+    Ok(certs)
+}
+
+/// Create an acceptor for the server from a PEM encoded certificate chain and private key.
+pub fn acceptor(cert_path: &Path, key_path: &Path) -> Result<TlsAcceptor, TlsError> {
+    let certs = load_certs(cert_path)?;
+    let key = PrivateKeyDer::from_pem_file(key_path)?;
+    let config = ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    Ok(Arc::new(config).into())
+}
+
+/// Create a connector that verifies servers against the given root certificates.
+pub fn connector(
+    roots: impl IntoIterator<Item = CertificateDer<'static>>,
+) -> Result<TlsConnector, TlsError> {
+    let mut store = RootCertStore::empty();
+    let (added, ignored) = store.add_parsable_certificates(roots);
+    tracing::debug!("Loaded {added} root certificates, ignored {ignored}");
+    if store.is_empty() {
+        return Err(TlsError::NoCertificates);
+    }
+
+    let config = ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(store)
+        .with_no_client_auth();
+    Ok(Arc::new(config).into())
+}
+
+/// Create a connector that only trusts a server presenting exactly `cert`.
+///
+/// This allows self-signed certificates to be used without setting up a certificate authority.
+pub fn pinned_connector(cert: CertificateDer<'static>) -> Result<TlsConnector, TlsError> {
+    let verifier = PinnedCertVerifier {
+        cert,
+        provider: provider(),
+    };
+    let config = ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth();
+    Ok(Arc::new(config).into())
+}
+
+#[derive(Debug)]
+struct PinnedCertVerifier {
+    cert: CertificateDer<'static>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, tokio_rustls::rustls::Error> {
+        // The certificate itself is what we trust, so its names and validity period don't matter
+        if end_entity.as_ref() == self.cert.as_ref() {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(CertificateError::UnknownIssuer.into())
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use tokio::io::duplex;
+
+    use super::{acceptor, pinned_connector, ServerName};
+    use crate::net::connection::from_stream;
+
+    struct TestCert {
+        cert: PathBuf,
+        key: PathBuf,
+        der: Vec<u8>,
+    }
+
+    fn test_cert(name: &str) -> TestCert {
+        let generated = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let dir = std::env::temp_dir();
+        let cert = dir.join(format!("clash-test-{name}-{}.pem", std::process::id()));
+        let key = dir.join(format!("clash-test-{name}-{}.key", std::process::id()));
+        std::fs::write(&cert, generated.cert.pem()).unwrap();
+        std::fs::write(&key, generated.signing_key.serialize_pem()).unwrap();
+        TestCert {
+            cert,
+            key,
+            der: generated.cert.der().to_vec(),
+        }
+    }
+
+    #[tokio::test]
+    async fn pinned_round_trip() {
+        let server_cert = test_cert("pinned");
+        let other_cert = test_cert("other");
+        let acceptor = acceptor(&server_cert.cert, &server_cert.key).unwrap();
+
+        // The pinned certificate is accepted
+        let (client, server) = duplex(4096);
+        let server = tokio::spawn({
+            let acceptor = acceptor.clone();
+            async move {
+                let (_, mut rx) = from_stream::<(), String>(acceptor.accept(server).await?);
+                Ok::<_, std::io::Error>(rx.read_frame().await.unwrap())
+            }
+        });
+        let connector = pinned_connector(server_cert.der.clone().into()).unwrap();
+        let name = ServerName::try_from("localhost").unwrap();
+        let stream = connector.connect(name.clone(), client).await.unwrap();
+        let (mut tx, _) = from_stream::<String, ()>(stream);
+        tx.write_frame("secret".to_owned()).await.unwrap();
+        assert_eq!(server.await.unwrap().unwrap().as_deref(), Some("secret"));
+
+        // Any other certificate is rejected
+        let (client, server) = duplex(4096);
+        tokio::spawn(async move { acceptor.accept(server).await });
+        let connector = pinned_connector(other_cert.der.into()).unwrap();
+        assert!(connector.connect(name, client).await.is_err());
+    }
+
+    #[test]
+    fn mismatched_key() {
+        let cert = test_cert("mismatched-cert");
+        let other = test_cert("mismatched-key");
+        assert!(acceptor(&cert.cert, &other.key).is_err());
+    }
+}
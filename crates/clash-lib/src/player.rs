@@ -1,5 +1,6 @@
 use ecolor::Color32;
 use serde::{Deserialize, Serialize};
+use std::time::Duration;
 
 use bfbb::Level;
 
@@ -25,6 +26,10 @@ pub struct NetworkedPlayer {
     pub score: u32,
     pub menu_order: u8,
     pub ready_to_start: bool,
+    /// False while the player has lost connection but their place in the lobby is being held.
+    pub connected: bool,
+    /// Round-trip time between the server and this player, if it has been measured.
+    pub latency: Option<Duration>,
 }
 
 impl NetworkedPlayer {
@@ -35,6 +40,8 @@ impl NetworkedPlayer {
             score: 0,
             menu_order,
             ready_to_start: false,
+            connected: true,
+            latency: None,
         }
     }
 
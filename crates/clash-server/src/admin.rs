@@ -0,0 +1,271 @@
+//! A console for server operators to inspect and manage lobbies while the server is running.
+//!
+//! When `admin_port` is configured the console is served over TCP on the loopback interface only,
+//! so it can be reached with something like `nc localhost <ADMIN_PORT>` from the server itself.
+//! Commands are sent one per line and each is answered with its output. Send `help` for a list of
+//! commands.
+
+use std::collections::HashSet;
+use std::fmt::Write;
+
+use anyhow::{anyhow, bail, Context};
+use clash_lib::{LobbyId, PlayerId, MAX_PLAYERS};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use tracing::instrument;
+
+use crate::state::ServerState;
+
+const HELP: &str = "\
+help                    Show this message
+stats                   Show connection and lobby counts
+lobbies                 List open lobbies
+players                 List players and the lobbies they are in
+show <LOBBY>            Show everything about a lobby
+kick <LOBBY> <PLAYER>   Remove a player from a lobby and disconnect them
+close <LOBBY>           Disconnect everyone in a lobby and close it
+announce <MESSAGE>      Show a message to everyone in every lobby";
+
+/// Accept admin connections from `listener` forever.
+pub async fn serve(state: ServerState, listener: TcpListener) {
+    loop {
+        let (socket, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                tracing::error!("Failed to accept admin connection: {e}");
+                continue;
+            }
+        };
+        tracing::info!("Admin console opened from {addr}");
+        tokio::spawn(handle_connection(state.clone(), socket));
+    }
+}
+
+#[instrument(skip_all)]
+async fn handle_connection(state: ServerState, socket: impl AsyncRead + AsyncWrite + Unpin) {
+    let (rx, mut tx) = tokio::io::split(socket);
+    let mut lines = BufReader::new(rx).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        tracing::info!("Admin command: {line}");
+        let mut output = match run_command(&state, line).await {
+            Ok(output) => output,
+            Err(e) => format!("Error: {e:#}"),
+        };
+        output.push('\n');
+        if tx.write_all(output.as_bytes()).await.is_err() {
+            break;
+        }
+    }
+    tracing::info!("Admin console closed");
+}
+
+async fn run_command(state: &ServerState, line: &str) -> anyhow::Result<String> {
+    let (command, args) = line.split_once(' ').unwrap_or((line, ""));
+    let args = args.trim();
+    match command {
+        "help" => Ok(HELP.to_owned()),
+        "stats" => Ok(stats(state)),
+        "lobbies" => lobbies(state).await,
+        "players" => players(state).await,
+        "show" => {
+            let lobby = state.get_lobby_handle_provider(parse_lobby(args)?)?;
+            let snapshot = lobby.snapshot().await?;
+            Ok(serde_json::to_string_pretty(&snapshot)?)
+        }
+        "kick" => {
+            let (lobby_id, player_id) = args
+                .split_once(' ')
+                .context("Expected a lobby and a player")?;
+            let lobby = state.get_lobby_handle_provider(parse_lobby(lobby_id)?)?;
+            let player_id = parse_player(player_id.trim())?;
+            lobby.kick(player_id).await?;
+            Ok(format!("Kicked player {player_id}"))
+        }
+        "close" => {
+            let lobby_id = parse_lobby(args)?;
+            state.get_lobby_handle_provider(lobby_id)?.close().await?;
+            Ok(format!("Closed lobby {lobby_id}"))
+        }
+        "announce" => {
+            if args.is_empty() {
+                bail!("Expected a message");
+            }
+            let mut sent = 0;
+            for (_, lobby) in state.lobby_providers() {
+                if lobby.announce(args.to_owned()).await.is_ok() {
+                    sent += 1;
+                }
+            }
+            Ok(format!("Sent to {sent} lobbies"))
+        }
+        _ => Err(anyhow!("Unknown command '{command}', try 'help'")),
+    }
+}
+
+fn stats(state: &ServerState) -> String {
+    let metrics = state.metrics();
+    format!(
+        "connections: {}\nlobbies: {}\nplayers: {}\nlagged: {}\nlag disconnects: {}",
+        state.connection_count(),
+        state.lobby_providers().len(),
+        state.player_ids().len(),
+        metrics.lagged(),
+        metrics.lag_disconnects(),
+    )
+}
+
+async fn lobbies(state: &ServerState) -> anyhow::Result<String> {
+    let mut output = String::new();
+    for (id, lobby) in state.lobby_providers() {
+        // Lobbies can close between listing and asking for a snapshot
+        let Ok(snapshot) = lobby.snapshot().await else {
+            continue;
+        };
+        let summary = snapshot.summary();
+        let visibility = match summary.options.public {
+            true => "public",
+            false => "private",
+        };
+        writeln!(
+            output,
+            "{id}  {}  {}/{MAX_PLAYERS} players  {:?}  {visibility}",
+            summary.name, summary.player_count, summary.game_phase,
+        )?;
+    }
+    if output.is_empty() {
+        output.push_str("No open lobbies");
+    }
+    Ok(output.trim_end().to_owned())
+}
+
+async fn players(state: &ServerState) -> anyhow::Result<String> {
+    let mut output = String::new();
+    let mut listed = HashSet::new();
+    for (lobby_id, lobby) in state.lobby_providers() {
+        let Ok(snapshot) = lobby.snapshot().await else {
+            continue;
+        };
+        let mut players = snapshot.players.iter().collect::<Vec<_>>();
+        players.sort_by_key(|(_, p)| p.menu_order);
+        for (&id, player) in players {
+            listed.insert(id);
+            write!(output, "{id}  {}  lobby {lobby_id}", player.options.name)?;
+            if snapshot.host_id == Some(id) {
+                output.push_str("  host");
+            }
+            if !player.connected {
+                output.push_str("  offline");
+            }
+            output.push('\n');
+        }
+    }
+    // Spectators and players who haven't finished connecting aren't part of any lobby
+    for id in state.player_ids() {
+        if !listed.contains(&id) {
+            writeln!(output, "{id}  not in a lobby")?;
+        }
+    }
+    if output.is_empty() {
+        output.push_str("No players");
+    }
+    Ok(output.trim_end().to_owned())
+}
+
+fn parse_lobby(s: &str) -> anyhow::Result<LobbyId> {
+    s.parse().with_context(|| format!("Invalid lobby '{s}'"))
+}
+
+/// Player ids are written in hex, the same way they are displayed.
+fn parse_player(s: &str) -> anyhow::Result<PlayerId> {
+    let hex = s
+        .strip_prefix("0x")
+        .or_else(|| s.strip_prefix("0X"))
+        .unwrap_or(s);
+    u32::from_str_radix(hex, 16)
+        .map(PlayerId)
+        .with_context(|| format!("Invalid player '{s}'"))
+}
+
+#[cfg(test)]
+mod test {
+    use clash_lib::net::ServerMessage;
+
+    use crate::lobby::LobbyEvent;
+    use crate::state::ServerState;
+
+    use super::run_command;
+
+    #[tokio::test]
+    async fn commands() {
+        let state = ServerState::default();
+        let host = state.add_player();
+        let handle = state.open_lobby(*host).unwrap();
+        let mut subscription = handle.join_lobby(None).await.unwrap();
+        let lobby_id = subscription.snapshot.lobby_id;
+
+        let lobbies = run_command(&state, "lobbies").await.unwrap();
+        assert!(lobbies.starts_with(&lobby_id.to_string()));
+        let players = run_command(&state, "players").await.unwrap();
+        assert!(players.contains(&format!("lobby {lobby_id}  host")));
+        let show = run_command(&state, &format!("show {lobby_id}"))
+            .await
+            .unwrap();
+        assert!(show.contains("\"lobby_id\""));
+
+        run_command(&state, "announce Server restarting soon")
+            .await
+            .unwrap();
+        assert!(matches!(
+            subscription.events.recv().await,
+            Ok(LobbyEvent::Message(ServerMessage::Announcement { message }))
+                if message == "Server restarting soon"
+        ));
+
+        assert!(run_command(&state, "kick ZZZZZZ 0x1").await.is_err());
+        assert!(run_command(&state, &format!("kick {lobby_id} 0x1"))
+            .await
+            .is_err());
+        assert!(run_command(&state, "frobnicate").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn kick_and_close() {
+        let state = ServerState::default();
+        let host = state.add_player();
+        let handle = state.open_lobby(*host).unwrap();
+        let lobby_id = handle.join_lobby(None).await.unwrap().snapshot.lobby_id;
+        let player = state.add_player();
+        let player_handle = state
+            .get_lobby_handle_provider(lobby_id)
+            .unwrap()
+            .into_handle(*player)
+            .unwrap();
+        let mut subscription = player_handle.join_lobby(None).await.unwrap();
+
+        run_command(&state, &format!("kick {lobby_id} {}", *player))
+            .await
+            .unwrap();
+        let snapshot = handle.snapshot().await.unwrap();
+        assert!(!snapshot.players.contains_key(&*player));
+        loop {
+            match subscription.events.recv().await.unwrap() {
+                LobbyEvent::Disconnect { player_id, .. } => {
+                    assert_eq!(player_id, Some(*player));
+                    break;
+                }
+                LobbyEvent::Message(_) => continue,
+            }
+        }
+
+        // Closing stops the lobby even though the host still holds a handle to it
+        run_command(&state, &format!("close {lobby_id}"))
+            .await
+            .unwrap();
+        assert!(handle.snapshot().await.is_err());
+        assert!(state.get_lobby_handle_provider(lobby_id).is_err());
+    }
+}
@@ -1,101 +1,287 @@
+use std::net::IpAddr;
+use std::time::Duration;
+
 use abort_on_drop::ChildTask;
+use clash_lib::lobby::DeltaOutcome;
 use clash_lib::net::connection::{self, ConnectionRx, ConnectionTx};
-use clash_lib::net::{LobbyMessage, Message, ProtocolError};
-use clash_lib::PlayerId;
+use clash_lib::net::tls::TlsAcceptor;
+use clash_lib::net::{
+    Capabilities, ClientMessage, FrameError, LobbyMessage, ProtocolError, ResumeToken,
+    ServerMessage,
+};
+use clash_lib::{net, PlayerId};
 use tokio::net::TcpStream;
 use tokio::select;
-use tokio::sync::{broadcast, mpsc};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc;
+use tokio::sync::OwnedSemaphorePermit;
+use tokio::time::Instant;
 use tracing::instrument;
 
-use crate::lobby::lobby_handle::LobbyHandle;
-use crate::lobby::LobbyError;
-use crate::state::{OwnedId, ServerState};
+use crate::heartbeat::Heartbeat;
+use crate::lag::LagTracker;
+use crate::lobby::lobby_handle::{LobbyHandle, LobbyHandleProvider};
+use crate::lobby::{LobbyError, LobbyEvent, LobbySubscription};
+use crate::rate_limit::{RateLimiter, Verdict};
+use crate::state::{HeldSession, OwnedId, ServerState};
+
+/// How long to wait for queued messages to be sent to a client that is being disconnected.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Set up the transport for a newly accepted socket, then begin serving it.
+pub async fn accept_connection(state: ServerState, tls: Option<TlsAcceptor>, socket: TcpStream) {
+    let addr = socket.peer_addr().ok().map(|addr| addr.ip());
+    let Some(acceptor) = tls else {
+        return handle_new_connection(state, connection::from_socket(socket), addr).await;
+    };
+
+    // The TLS handshake counts towards the connection limits and the handshake deadline like the
+    // rest of the handshake. Clients turned away before it's done can't be told why.
+    let deadline = Instant::now() + state.config().handshake_timeout;
+    let permits = match begin(&state) {
+        Ok(permits) => permits,
+        Err(error) => {
+            tracing::warn!(%error, "Rejecting TLS connection");
+            return;
+        }
+    };
+    let (conn_tx, conn_rx) = match tokio::time::timeout_at(deadline, acceptor.accept(socket)).await
+    {
+        Ok(Ok(stream)) => connection::from_stream(stream),
+        Ok(Err(e)) => {
+            tracing::warn!("TLS handshake failed: {e}");
+            return;
+        }
+        Err(_) => {
+            tracing::warn!("TLS handshake timed out");
+            return;
+        }
+    };
+    let client = ConnectingClient::new(state, conn_tx, conn_rx, addr, deadline);
+    serve(client, permits).await;
+}
 
-/// Take a socket for a newly connected client and begin serving it.
-pub async fn handle_new_connection(state: ServerState, socket: TcpStream) {
-    let client = match ConnectingClient::new(state, socket).handshake().await {
+/// Take a connection for a newly connected client from `addr` and begin serving it.
+pub async fn handle_new_connection(
+    state: ServerState,
+    (conn_tx, conn_rx): (ConnectionTx<ServerMessage>, ConnectionRx<ClientMessage>),
+    addr: Option<IpAddr>,
+) {
+    let deadline = Instant::now() + state.config().handshake_timeout;
+    let client = ConnectingClient::new(state, conn_tx, conn_rx, addr, deadline);
+    match begin(&client.state) {
+        Ok(permits) => serve(client, permits).await,
+        Err(error) => client.reject(error).await,
+    }
+}
+
+/// Reserve a connection slot and a handshake slot for a new connection.
+fn begin(
+    state: &ServerState,
+) -> Result<(OwnedSemaphorePermit, OwnedSemaphorePermit), ProtocolError> {
+    Ok((state.begin_connection()?, state.begin_handshake()?))
+}
+
+async fn serve(
+    client: ConnectingClient,
+    (_connection, permit): (OwnedSemaphorePermit, OwnedSemaphorePermit),
+) {
+    // Hold a handshake slot until the client has told us what they want to do
+    let client = match client.handshake().await {
         Some(c) => c,
         None => return,
     };
+    drop(permit);
     client.run().await;
 }
 
 /// Represents a client who just connected and still needs to tell the server what they want to do.
+///
+/// No [`PlayerId`] is allocated until the handshake has completed.
 struct ConnectingClient {
     state: ServerState,
-    player_id: OwnedId<PlayerId>,
-    conn_tx: ConnectionTx,
-    conn_rx: ConnectionRx,
+    capabilities: Capabilities,
+    conn_tx: ConnectionTx<ServerMessage>,
+    conn_rx: ConnectionRx<ClientMessage>,
+    /// Where the client connected from, if known.
+    addr: Option<IpAddr>,
+    /// When the client must have finished the handshake by, no matter how many messages it takes.
+    deadline: Instant,
 }
 
 impl ConnectingClient {
-    fn new(state: ServerState, socket: TcpStream) -> Self {
-        // Add new player
-        let player_id = state.add_player();
-
-        let (conn_tx, conn_rx) = connection::from_socket(socket);
+    fn new(
+        state: ServerState,
+        conn_tx: ConnectionTx<ServerMessage>,
+        mut conn_rx: ConnectionRx<ClientMessage>,
+        addr: Option<IpAddr>,
+        deadline: Instant,
+    ) -> Self {
+        conn_rx.set_max_frame_len(state.config().max_frame_len);
         Self {
+            deadline,
             state,
-            player_id,
+            capabilities: Capabilities::empty(),
             conn_tx,
             conn_rx,
+            addr,
         }
     }
 
     async fn handshake(mut self) -> Option<ConnectedClient> {
         match self.try_handshake().await {
             Ok(it) => Some(it.construct(self)),
+            // Browsing lobbies ends with the client hanging up, which isn't worth complaining about
+            Err(ProtocolError::Disconnected) => {
+                tracing::debug!("Client disconnected during handshake");
+                None
+            }
             Err(error) => {
-                tracing::error!(%error);
-                let _ = self.conn_tx.write_frame(Message::Error { error }).await;
+                self.reject(error).await;
                 None
             }
         }
     }
 
+    /// Tell the client why we're closing their connection.
+    async fn reject(mut self, error: ProtocolError) {
+        tracing::error!(%error);
+        // The client may not be reading from their socket at all, don't let them hold us up
+        let _ = tokio::time::timeout(
+            self.state.config().handshake_timeout,
+            self.conn_tx.write_frame(ServerMessage::Error { error }),
+        )
+        .await;
+    }
+
+    /// Read the next message of the handshake, giving up if the handshake is taking too long.
+    async fn read_handshake(&mut self) -> Result<ClientMessage, ProtocolError> {
+        match tokio::time::timeout_at(self.deadline, self.conn_rx.read_frame()).await {
+            Ok(frame) => frame?.ok_or(ProtocolError::Disconnected),
+            Err(_) => Err(ProtocolError::Timeout),
+        }
+    }
+
     async fn try_handshake(&mut self) -> Result<ClientConstructor, ProtocolError> {
-        let version = match self.conn_rx.read_frame().await? {
-            Some(Message::Version { version }) => version,
-            Some(_) => return Err(ProtocolError::InvalidMessage),
-            None => return Err(ProtocolError::Disconnected),
+        let ClientMessage::Hello {
+            protocol_version,
+            client_version,
+            capabilities,
+        } = self.read_handshake().await?
+        else {
+            // Every client since protocol version 2 starts with a `Hello`, so anything else comes
+            // from a client that predates it. Their first message decodes as some other variant.
+            return Err(ProtocolError::VersionMismatch(
+                "1".to_owned(),
+                net::PROTOCOL_VERSION.to_string(),
+            ));
         };
 
-        if version != crate::VERSION {
+        if !net::is_compatible(protocol_version) {
             return Err(ProtocolError::VersionMismatch(
-                version,
-                crate::VERSION.to_owned(),
+                protocol_version.to_string(),
+                net::PROTOCOL_VERSION.to_string(),
             ));
         }
+        // Only use the features that we both know about
+        self.capabilities = capabilities & Capabilities::ALL;
+        tracing::info!(
+            "New connection opened. Client version '{client_version}' with capabilities {:?}",
+            self.capabilities
+        );
 
-        // Inform player of their PlayerId
-        self.conn_tx
-            .write_frame(Message::ConnectionAccept {
-                player_id: *self.player_id,
-            })
-            .await?;
-        tracing::info!("New connection for player id {} opened", *self.player_id);
-
-        let lobby_handle = match self.conn_rx.read_frame().await? {
-            Some(Message::GameHost) => self.state.open_lobby(*self.player_id),
-            Some(Message::GameJoin { lobby_id, spectate }) => {
-                let handle_provider = self.state.get_lobby_handle_provider(lobby_id)?;
+        let (player_id, lobby_handle, subscription) = loop {
+            break match self.read_handshake().await? {
+                ClientMessage::ListLobbies => {
+                    let lobbies = self.state.list_lobbies().await;
+                    self.conn_tx
+                        .write_frame(ServerMessage::LobbyList { lobbies })
+                        .await?;
+                    continue;
+                }
+                ClientMessage::GameHost => {
+                    let player_id = self.state.add_player();
+                    let lobby_handle = self.state.open_lobby(*player_id)?;
+                    let subscription = lobby_handle.join_lobby(self.addr).await?;
+                    (player_id, lobby_handle, subscription)
+                }
+                ClientMessage::GameJoin {
+                    lobby_id,
+                    spectate,
+                    password,
+                } => {
+                    let handle_provider = self.state.get_lobby_handle_provider(lobby_id)?;
+                    handle_provider
+                        .authorize(password, spectate, self.addr)
+                        .await?;
 
-                if spectate {
-                    let recv = handle_provider.spectate().await?;
-                    return Ok(ClientConstructor::Spectator(recv));
-                } else {
-                    handle_provider.into_handle(*self.player_id)?
+                    let player_id = self.state.add_player();
+                    if spectate {
+                        let recv = handle_provider.spectate().await?;
+                        self.accept(&player_id, None).await?;
+                        return Ok(ClientConstructor::Spectator(
+                            player_id,
+                            handle_provider,
+                            recv,
+                        ));
+                    }
+                    let lobby_handle = handle_provider.into_handle(*player_id)?;
+                    let subscription = lobby_handle.join_lobby(self.addr).await?;
+                    (player_id, lobby_handle, subscription)
                 }
-            }
-            Some(_) => return Err(ProtocolError::InvalidMessage),
-            None => return Err(ProtocolError::Disconnected),
+                ClientMessage::Resume { token }
+                    if self.capabilities.contains(Capabilities::RESUME) =>
+                {
+                    let session = self.state.resume_session(token)?;
+                    let subscription = session.lobby_handle.reconnect().await?;
+                    tracing::info!("Player id {} resumed their session", session.player_id);
+                    (session.player_id, session.lobby_handle, subscription)
+                }
+                _ => return Err(ProtocolError::InvalidMessage),
+            };
         };
 
-        let lobby_recv = lobby_handle
-            .join_lobby()
+        let resume_token = self
+            .capabilities
+            .contains(Capabilities::RESUME)
+            .then(|| self.state.gen_resume_token());
+        self.accept(&player_id, resume_token).await?;
+        Ok(ClientConstructor::Player(
+            player_id,
+            lobby_handle,
+            subscription,
+            resume_token,
+        ))
+    }
+
+    fn heartbeat(&self) -> Option<Heartbeat> {
+        let config = self.state.config();
+        self.capabilities
+            .contains(Capabilities::HEARTBEAT)
+            .then(|| Heartbeat::new(config.heartbeat_interval, config.heartbeat_misses))
+    }
+
+    /// Inform player of their PlayerId and the features that were negotiated.
+    async fn accept(
+        &mut self,
+        player_id: &OwnedId<PlayerId>,
+        resume_token: Option<ResumeToken>,
+    ) -> Result<(), ProtocolError> {
+        let accept = self.conn_tx.write_frame(ServerMessage::ConnectionAccept {
+            player_id: **player_id,
+            capabilities: self.capabilities,
+            resume_token,
+            max_frame_len: self.state.config().max_frame_len,
+            motd: self.state.config().motd.clone(),
+        });
+        tokio::time::timeout_at(self.deadline, accept)
             .await
-            .map_err(|err| ProtocolError::Message(err.to_string()))?;
-        Ok(ClientConstructor::Player(lobby_handle, lobby_recv))
+            .map_err(|_| ProtocolError::Timeout)??;
+        if self.capabilities.contains(Capabilities::COMPRESSION) {
+            self.conn_tx
+                .enable_compression(self.state.config().compression_threshold);
+        }
+        Ok(())
     }
 }
 
@@ -103,18 +289,30 @@ impl ConnectingClient {
 /// This allows us to return what kind of client to construct from `try_handshake` to the caller,
 /// since the caller needs to retain ownership of `self` for error reporting to the client.
 enum ClientConstructor {
-    Player(LobbyHandle, broadcast::Receiver<Message>),
-    Spectator(broadcast::Receiver<Message>),
+    Player(
+        OwnedId<PlayerId>,
+        LobbyHandle,
+        LobbySubscription,
+        Option<ResumeToken>,
+    ),
+    Spectator(OwnedId<PlayerId>, LobbyHandleProvider, LobbySubscription),
 }
 
 impl ClientConstructor {
     fn construct(self, client: ConnectingClient) -> ConnectedClient {
         match self {
-            ClientConstructor::Player(lobby_handle, lobby_recv) => {
-                PlayerClient::from_connecting(client, lobby_handle, lobby_recv).into()
+            ClientConstructor::Player(player_id, lobby_handle, subscription, resume_token) => {
+                PlayerClient::from_connecting(
+                    client,
+                    player_id,
+                    lobby_handle,
+                    subscription,
+                    resume_token,
+                )
+                .into()
             }
-            ClientConstructor::Spectator(lobby_recv) => {
-                SpectatingClient::from_connecting(client, lobby_recv).into()
+            ClientConstructor::Spectator(player_id, lobby, subscription) => {
+                SpectatingClient::from_connecting(client, player_id, lobby, subscription).into()
             }
         }
     }
@@ -146,48 +344,163 @@ impl ConnectedClient {
     }
 }
 
+/// Waits for the next ping to send to a client, or forever if they don't support heartbeats.
+///
+/// Resolves to `None` if the client has stopped responding.
+async fn next_heartbeat(heartbeat: &mut Option<Heartbeat>) -> Option<ServerMessage> {
+    match heartbeat {
+        Some(h) => h.tick().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Give the send task a moment to write out anything still queued for the client, such as the
+/// error explaining why it's being disconnected.
+async fn flush(local_tx: mpsc::Sender<ServerMessage>, send_task: ChildTask<bool>) {
+    drop(local_tx);
+    // There's nothing left to send once the task has stopped, and it may already have been joined
+    if !send_task.is_finished() {
+        let _ = tokio::time::timeout(FLUSH_TIMEOUT, send_task).await;
+    }
+}
+
+/// Forward lobby events and messages queued on `local_rx` to the client.
+///
+/// Returns `true` if the lobby disconnected the client, in which case they are no longer part of
+/// it.
 async fn send_task(
-    mut conn_tx: ConnectionTx,
-    mut lobby_rx: tokio::sync::broadcast::Receiver<Message>,
-    mut local_rx: tokio::sync::mpsc::Receiver<Message>,
-) {
+    state: ServerState,
+    mut conn_tx: ConnectionTx<ServerMessage>,
+    capabilities: Capabilities,
+    player_id: Option<PlayerId>,
+    lobby_provider: LobbyHandleProvider,
+    subscription: LobbySubscription,
+    mut local_rx: tokio::sync::mpsc::Receiver<ServerMessage>,
+) -> bool {
+    // Start the client off with the full lobby, from here on they will only be sent deltas
+    let LobbySubscription {
+        snapshot,
+        events: mut lobby_rx,
+    } = subscription;
+    // Clients that can't apply deltas themselves are sent a new snapshot after every change instead
+    let mut lobby = (!capabilities.contains(Capabilities::DELTAS)).then(|| snapshot.clone());
+    if conn_tx
+        .write_frame(ServerMessage::GameLobbyInfo { lobby: snapshot })
+        .await
+        .is_err()
+    {
+        return false;
+    }
+
+    let mut lag = LagTracker::new(state);
+    let mut lobby_open = true;
     loop {
         let m = select! {
-            Ok(m) = lobby_rx.recv() => m,
-            Some(m) = local_rx.recv() => m,
-            else => return,
+            event = lobby_rx.recv(), if lobby_open => match event {
+                Ok(LobbyEvent::Message(m)) => m,
+                Ok(LobbyEvent::Disconnect { player_id: target, error }) => {
+                    if target.is_some() && target != player_id {
+                        continue;
+                    }
+                    tracing::info!(%error, "Disconnecting client");
+                    let _ = conn_tx.write_frame(ServerMessage::Error { error }).await;
+                    return true;
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    if !lag.lagged(skipped) {
+                        return false;
+                    }
+                    // The snapshot supersedes everything still queued, so skip straight past it
+                    lobby_rx = lobby_rx.resubscribe();
+                    let Ok(lobby) = lobby_provider.snapshot().await else {
+                        return false;
+                    };
+                    ServerMessage::GameLobbyInfo { lobby }
+                }
+                Err(RecvError::Closed) => {
+                    lobby_open = false;
+                    continue;
+                }
+            },
+            // The client has been closed once nothing else will be queued for it
+            m = local_rx.recv() => match m {
+                Some(m) => m,
+                None => return false,
+            },
+        };
+        let m = match (&mut lobby, m) {
+            (
+                Some(lobby),
+                ServerMessage::LobbyDelta {
+                    delta,
+                    sequence,
+                    checksum,
+                },
+            ) => {
+                // Deltas sent before a new snapshot was taken are already part of it
+                if lobby.apply_stamped(&delta, sequence, checksum) == DeltaOutcome::Stale {
+                    continue;
+                }
+                ServerMessage::GameLobbyInfo {
+                    lobby: lobby.clone(),
+                }
+            }
+            (Some(lobby), ServerMessage::GameLobbyInfo { lobby: new_lobby }) => {
+                *lobby = new_lobby.clone();
+                ServerMessage::GameLobbyInfo { lobby: new_lobby }
+            }
+            (_, m) => m,
         };
 
         if conn_tx.write_frame(m).await.is_err() {
-            return;
+            return false;
         }
     }
 }
 
 /// Used to represent a client who is in a lobby.
 struct PlayerClient {
+    state: ServerState,
     player_id: OwnedId<PlayerId>,
-    conn_rx: ConnectionRx,
-    local_tx: mpsc::Sender<Message>,
-    _send_task: ChildTask<()>,
+    resume_token: Option<ResumeToken>,
+    conn_rx: ConnectionRx<ClientMessage>,
+    local_tx: mpsc::Sender<ServerMessage>,
+    send_task: ChildTask<bool>,
+    heartbeat: Option<Heartbeat>,
+    rate_limiter: RateLimiter,
     lobby_handle: LobbyHandle,
 }
 
 impl PlayerClient {
     pub fn from_connecting(
         client: ConnectingClient,
+        player_id: OwnedId<PlayerId>,
         lobby_handle: LobbyHandle,
-        lobby_recv: broadcast::Receiver<Message>,
+        subscription: LobbySubscription,
+        resume_token: Option<ResumeToken>,
     ) -> Self {
-        let lobby_handle = lobby_handle;
+        let heartbeat = client.heartbeat();
         let (tx, rx) = mpsc::channel(64);
-        let task_handle = tokio::spawn(send_task(client.conn_tx, lobby_recv, rx)).into();
+        let task_handle = tokio::spawn(send_task(
+            client.state.clone(),
+            client.conn_tx,
+            client.capabilities,
+            Some(*player_id),
+            lobby_handle.provider(),
+            subscription,
+            rx,
+        ))
+        .into();
 
         PlayerClient {
-            player_id: client.player_id,
+            heartbeat,
+            rate_limiter: RateLimiter::new(client.state.config()),
+            state: client.state,
+            player_id,
+            resume_token,
             conn_rx: client.conn_rx,
             local_tx: tx,
-            _send_task: task_handle,
+            send_task: task_handle,
             lobby_handle,
         }
     }
@@ -196,14 +509,94 @@ impl PlayerClient {
     /// message loop ends
     #[instrument(skip_all, fields(player_id = %self.player_id))]
     pub async fn run(mut self) {
+        let mut left = false;
         loop {
-            let incoming = match self.conn_rx.read_frame().await {
-                Ok(Some(Message::Lobby(x))) => x,
+            let frame = select! {
+                frame = self.conn_rx.read_frame() => frame,
+                ping = next_heartbeat(&mut self.heartbeat) => {
+                    let Some(ping) = ping else {
+                        tracing::warn!("Client stopped responding to pings, closing connection");
+                        break;
+                    };
+                    let _ = self.local_tx.send(ping).await;
+                    continue;
+                }
+                // The send task stops when the client can't be written to or can't keep up, or
+                // when the lobby disconnects them. Players who were kicked or banned have already
+                // been removed from the lobby, so there's no place to hold for them.
+                _ = self.local_tx.closed() => {
+                    left = matches!((&mut self.send_task).await, Ok(true));
+                    break;
+                }
+            };
+            if let Ok(Some(m)) = &frame {
+                match self.rate_limiter.check(m) {
+                    Verdict::Allow => (),
+                    Verdict::Drop => {
+                        tracing::warn!("Client exceeded its rate limit, dropping {m:?}");
+                        // Requests are still answered so the client knows not to expect a result
+                        let reply = match *m {
+                            ClientMessage::Request { id, .. } => ServerMessage::Response {
+                                id,
+                                result: Err(LobbyError::RateLimited),
+                            },
+                            _ => ServerMessage::Error {
+                                error: ProtocolError::RateLimited,
+                            },
+                        };
+                        let _ = self.local_tx.send(reply).await;
+                        continue;
+                    }
+                    Verdict::Disconnect => {
+                        tracing::warn!("Client kept exceeding its rate limit, closing connection");
+                        // Flooding the lobby forfeits the player's place in it
+                        left = true;
+                        break;
+                    }
+                }
+            }
+            let incoming = match frame {
+                Ok(Some(ClientMessage::Lobby(x))) => x,
+                Ok(Some(ClientMessage::Request { id, message })) => {
+                    tracing::debug!("Received request {id}: {message:#?}");
+                    let result = self.process(message).await;
+                    if let Err(e) = result {
+                        tracing::error!("Encountered error processing request {id}: {e:?}");
+                    }
+                    let _ = self
+                        .local_tx
+                        .send(ServerMessage::Response { id, result })
+                        .await;
+                    continue;
+                }
+                Ok(Some(ClientMessage::Leave)) => {
+                    left = true;
+                    break;
+                }
+                Ok(Some(ClientMessage::Resync)) => {
+                    tracing::warn!(
+                        "Client fell out of sync with the lobby, sending a new snapshot"
+                    );
+                    if let Ok(lobby) = self.lobby_handle.snapshot().await {
+                        let _ = self
+                            .local_tx
+                            .send(ServerMessage::GameLobbyInfo { lobby })
+                            .await;
+                    }
+                    continue;
+                }
+                Ok(Some(ClientMessage::Pong { nonce })) => {
+                    if let Some(rtt) = self.heartbeat.as_mut().and_then(|h| h.pong(nonce)) {
+                        self.lobby_handle.set_player_latency(rtt).await;
+                    }
+                    continue;
+                }
+                // Handshake messages are only valid before joining a lobby
                 Ok(Some(m)) => {
                     tracing::error!("Invalid message received: {m:?}");
                     let _ = self
                         .local_tx
-                        .send(Message::Error {
+                        .send(ServerMessage::Error {
                             error: ProtocolError::InvalidMessage,
                         })
                         .await;
@@ -212,6 +605,15 @@ impl PlayerClient {
                 Ok(None) => {
                     break;
                 }
+                // The oversized frame was skipped, so the connection is still usable
+                Err(e @ FrameError::FrameLength { .. }) => {
+                    tracing::error!("{e}");
+                    let _ = self
+                        .local_tx
+                        .send(ServerMessage::Error { error: e.into() })
+                        .await;
+                    continue;
+                }
                 Err(e) => {
                     tracing::error!("Error reading message, Closing connection\n{e:?}",);
                     break;
@@ -225,13 +627,24 @@ impl PlayerClient {
                     tracing::error!("Encountered error processing message: {e:?}");
                     let _ = self
                         .local_tx
-                        .send(Message::Error {
-                            error: ProtocolError::Message(e.to_string()),
-                        })
+                        .send(ServerMessage::Error { error: e.into() })
                         .await;
                 }
             }
         }
+
+        // Hold on to the player's place in the lobby if they didn't mean to leave
+        if let (Some(token), false) = (self.resume_token, left) {
+            self.lobby_handle.disconnect().await;
+            self.state.hold_session(
+                token,
+                HeldSession {
+                    player_id: self.player_id,
+                    lobby_handle: self.lobby_handle,
+                },
+            );
+        }
+        flush(self.local_tx, self.send_task).await;
     }
 
     async fn process(&mut self, msg: LobbyMessage) -> Result<(), LobbyError> {
@@ -251,7 +664,16 @@ impl PlayerClient {
             LobbyMessage::GameItemCollected { item } => {
                 self.lobby_handle.player_collected_item(item).await
             }
-            LobbyMessage::GameEnd => todo!(),
+            LobbyMessage::GameEnd => self.lobby_handle.end_game().await,
+            LobbyMessage::KickPlayer { player_id } => {
+                self.lobby_handle.kick_player(player_id).await
+            }
+            LobbyMessage::BanPlayer { player_id, ban_ip } => {
+                self.lobby_handle.ban_player(player_id, ban_ip).await
+            }
+            LobbyMessage::TransferHost { player_id } => {
+                self.lobby_handle.transfer_host(player_id).await
+            }
         }
     }
 }
@@ -259,24 +681,42 @@ impl PlayerClient {
 // TODO: Abstract client types and deduplicate code.
 struct SpectatingClient {
     player_id: OwnedId<PlayerId>,
-    conn_rx: ConnectionRx,
-    local_tx: mpsc::Sender<Message>,
-    _send_task: ChildTask<()>,
+    lobby: LobbyHandleProvider,
+    conn_rx: ConnectionRx<ClientMessage>,
+    local_tx: mpsc::Sender<ServerMessage>,
+    send_task: ChildTask<bool>,
+    heartbeat: Option<Heartbeat>,
+    rate_limiter: RateLimiter,
 }
 
 impl SpectatingClient {
     pub fn from_connecting(
         client: ConnectingClient,
-        lobby_recv: broadcast::Receiver<Message>,
+        player_id: OwnedId<PlayerId>,
+        lobby: LobbyHandleProvider,
+        subscription: LobbySubscription,
     ) -> Self {
+        let heartbeat = client.heartbeat();
         let (tx, rx) = mpsc::channel(64);
-        let task_handle = tokio::spawn(send_task(client.conn_tx, lobby_recv, rx)).into();
+        let task_handle = tokio::spawn(send_task(
+            client.state.clone(),
+            client.conn_tx,
+            client.capabilities,
+            None,
+            lobby.clone(),
+            subscription,
+            rx,
+        ))
+        .into();
 
         Self {
-            player_id: client.player_id,
+            heartbeat,
+            rate_limiter: RateLimiter::new(client.state.config()),
+            player_id,
+            lobby,
             conn_rx: client.conn_rx,
             local_tx: tx,
-            _send_task: task_handle,
+            send_task: task_handle,
         }
     }
 
@@ -285,13 +725,62 @@ impl SpectatingClient {
     #[instrument(skip_all, fields(player_id = %self.player_id))]
     pub async fn run(mut self) {
         loop {
-            match self.conn_rx.read_frame().await {
+            let frame = select! {
+                frame = self.conn_rx.read_frame() => frame,
+                ping = next_heartbeat(&mut self.heartbeat) => {
+                    let Some(ping) = ping else {
+                        tracing::warn!("Client stopped responding to pings, closing connection");
+                        break;
+                    };
+                    let _ = self.local_tx.send(ping).await;
+                    continue;
+                }
+                // The send task stops when the client can't be written to or can't keep up
+                _ = self.local_tx.closed() => break,
+            };
+            if let Ok(Some(m)) = &frame {
+                match self.rate_limiter.check(m) {
+                    Verdict::Allow => (),
+                    Verdict::Drop => {
+                        tracing::warn!("Client exceeded its rate limit, dropping {m:?}");
+                        let _ = self
+                            .local_tx
+                            .send(ServerMessage::Error {
+                                error: ProtocolError::RateLimited,
+                            })
+                            .await;
+                        continue;
+                    }
+                    Verdict::Disconnect => {
+                        tracing::warn!("Client kept exceeding its rate limit, closing connection");
+                        break;
+                    }
+                }
+            }
+            match frame {
+                Ok(Some(ClientMessage::Leave)) => break,
+                Ok(Some(ClientMessage::Pong { nonce })) => {
+                    if let Some(h) = self.heartbeat.as_mut() {
+                        h.pong(nonce);
+                    }
+                }
+                Ok(Some(ClientMessage::Resync)) => {
+                    tracing::warn!(
+                        "Client fell out of sync with the lobby, sending a new snapshot"
+                    );
+                    if let Ok(lobby) = self.lobby.snapshot().await {
+                        let _ = self
+                            .local_tx
+                            .send(ServerMessage::GameLobbyInfo { lobby })
+                            .await;
+                    }
+                }
                 // Spectators should never send a message again after joining
                 Ok(Some(m)) => {
                     tracing::error!("Invalid message received: {m:?}");
                     let _ = self
                         .local_tx
-                        .send(Message::Error {
+                        .send(ServerMessage::Error {
                             error: ProtocolError::InvalidMessage,
                         })
                         .await;
@@ -300,12 +789,568 @@ impl SpectatingClient {
                 Ok(None) => {
                     break;
                 }
+                // The oversized frame was skipped, so the connection is still usable
+                Err(e @ FrameError::FrameLength { .. }) => {
+                    tracing::error!("{e}");
+                    let _ = self
+                        .local_tx
+                        .send(ServerMessage::Error { error: e.into() })
+                        .await;
+                    continue;
+                }
                 Err(e) => {
                     tracing::error!("Error reading message, Closing connection\n{e:?}",);
                     break;
                 }
             };
         }
+        flush(self.local_tx, self.send_task).await;
         tracing::info!("Player disconnected");
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use clash_lib::lobby::{LobbyOptions, Password};
+    use clash_lib::net::{
+        connection::{self, ConnectionRx, ConnectionTx},
+        Capabilities, ClientMessage, LobbyDelta, LobbyError, LobbyMessage, ProtocolError,
+        ServerMessage, PROTOCOL_VERSION,
+    };
+    use clash_lib::LobbyId;
+    use serde::{Deserialize, Serialize};
+    use tokio::io::duplex;
+    use tokio::net::{TcpListener, TcpStream};
+
+    use crate::config::Config;
+    use crate::state::ServerState;
+    use crate::testing;
+
+    use super::{accept_connection, handle_new_connection};
+
+    /// Serve an in-process connection and return the client's end of it
+    fn connect(state: &ServerState) -> (ConnectionTx<ClientMessage>, ConnectionRx<ServerMessage>) {
+        let (client, server) = duplex(1024);
+        tokio::spawn(handle_new_connection(
+            state.clone(),
+            connection::from_stream(server),
+            None,
+        ));
+        connection::from_stream(client)
+    }
+
+    fn hello(protocol_version: u16) -> ClientMessage {
+        ClientMessage::Hello {
+            protocol_version,
+            client_version: "test".to_owned(),
+            capabilities: Capabilities::ALL,
+        }
+    }
+
+    #[tokio::test]
+    async fn host_lobby() {
+        let state = ServerState::default();
+        let (mut tx, mut rx) = connect(&state);
+        tx.write_frame(hello(PROTOCOL_VERSION)).await.unwrap();
+        tx.write_frame(ClientMessage::GameHost).await.unwrap();
+
+        let Some(ServerMessage::ConnectionAccept { player_id, .. }) =
+            rx.read_frame().await.unwrap()
+        else {
+            panic!("Handshake should be accepted");
+        };
+        // Frames after the handshake are sent with compression flags
+        rx.enable_compression();
+        let Some(ServerMessage::GameLobbyInfo { lobby }) = rx.read_frame().await.unwrap() else {
+            panic!("Client should be sent their lobby after joining");
+        };
+        assert_eq!(lobby.host_id, Some(player_id));
+    }
+
+    #[tokio::test]
+    async fn request_response() {
+        let state = ServerState::default();
+        let (mut host_tx, mut host_rx) = connect(&state);
+        host_tx.write_frame(hello(PROTOCOL_VERSION)).await.unwrap();
+        host_tx.write_frame(ClientMessage::GameHost).await.unwrap();
+        host_rx.read_frame().await.unwrap();
+        host_rx.enable_compression();
+        let Some(ServerMessage::GameLobbyInfo { lobby }) = host_rx.read_frame().await.unwrap()
+        else {
+            panic!("Client should be sent their lobby after joining");
+        };
+
+        let (mut tx, mut rx) = connect(&state);
+        tx.write_frame(hello(PROTOCOL_VERSION)).await.unwrap();
+        tx.write_frame(ClientMessage::GameJoin {
+            lobby_id: lobby.lobby_id,
+            spectate: false,
+            password: None,
+        })
+        .await
+        .unwrap();
+        rx.read_frame().await.unwrap();
+        rx.enable_compression();
+
+        // Only the host can start the game, and the error tells us exactly that
+        tx.write_frame(ClientMessage::Request {
+            id: 3,
+            message: LobbyMessage::GameBegin,
+        })
+        .await
+        .unwrap();
+        loop {
+            match rx.read_frame().await.unwrap().unwrap() {
+                ServerMessage::Response { id, result } => {
+                    assert_eq!(id, 3);
+                    assert_eq!(result, Err(LobbyError::NeedsHost));
+                    break;
+                }
+                ServerMessage::Error { error } => panic!("Unexpected error {error}"),
+                _ => continue,
+            }
+        }
+
+        // Ending a game that hasn't started is rejected instead of taking down the connection
+        host_tx
+            .write_frame(ClientMessage::Request {
+                id: 4,
+                message: LobbyMessage::GameEnd,
+            })
+            .await
+            .unwrap();
+        loop {
+            match host_rx.read_frame().await.unwrap().unwrap() {
+                ServerMessage::Response { id, result } => {
+                    assert_eq!(id, 4);
+                    assert!(matches!(result, Err(LobbyError::InvalidAction(_))));
+                    break;
+                }
+                ServerMessage::Error { error } => panic!("Unexpected error {error}"),
+                _ => continue,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn list_lobbies() {
+        let state = ServerState::default();
+        let mut hosts = Vec::new();
+        for public in [true, false] {
+            let (mut tx, mut rx) = connect(&state);
+            tx.write_frame(hello(PROTOCOL_VERSION)).await.unwrap();
+            tx.write_frame(ClientMessage::GameHost).await.unwrap();
+            rx.read_frame().await.unwrap();
+            rx.enable_compression();
+            tx.write_frame(ClientMessage::Request {
+                id: 0,
+                message: LobbyMessage::GameOptions {
+                    options: LobbyOptions {
+                        name: "Pickup".to_owned(),
+                        public,
+                        ..Default::default()
+                    },
+                },
+            })
+            .await
+            .unwrap();
+            while !matches!(
+                rx.read_frame().await.unwrap(),
+                Some(ServerMessage::Response { .. })
+            ) {}
+            hosts.push((tx, rx));
+        }
+
+        // Only the public lobby is listed, and the client is free to hang up afterwards
+        let (mut tx, mut rx) = connect(&state);
+        tx.write_frame(hello(PROTOCOL_VERSION)).await.unwrap();
+        tx.write_frame(ClientMessage::ListLobbies).await.unwrap();
+        let Some(ServerMessage::LobbyList { lobbies }) = rx.read_frame().await.unwrap() else {
+            panic!("Client should be sent the lobby list");
+        };
+        assert_eq!(lobbies.len(), 1);
+        assert_eq!(lobbies[0].name, "Pickup");
+        assert_eq!(lobbies[0].player_count, 1);
+    }
+
+    #[tokio::test]
+    async fn lagging_client() {
+        let state = ServerState::default();
+        let (mut tx, mut rx) = connect(&state);
+        tx.write_frame(hello(PROTOCOL_VERSION)).await.unwrap();
+        tx.write_frame(ClientMessage::GameHost).await.unwrap();
+        let Some(ServerMessage::ConnectionAccept { player_id, .. }) =
+            rx.read_frame().await.unwrap()
+        else {
+            panic!("Handshake should be accepted");
+        };
+        rx.enable_compression();
+        let Some(ServerMessage::GameLobbyInfo { lobby }) = rx.read_frame().await.unwrap() else {
+            panic!("Client should be sent their lobby after joining");
+        };
+
+        // Change the lobby far more often than the client is reading
+        let handle = state
+            .get_lobby_handle_provider(lobby.lobby_id)
+            .unwrap()
+            .into_handle(player_id)
+            .unwrap();
+        for i in 0..500 {
+            handle.set_player_can_start(i % 2 == 0).await.unwrap();
+        }
+
+        // Once the client catches up it is sent a new snapshot in place of the updates it missed
+        loop {
+            match rx.read_frame().await.unwrap().unwrap() {
+                ServerMessage::GameLobbyInfo { lobby } => {
+                    assert!(lobby.sequence > 100);
+                    break;
+                }
+                ServerMessage::LobbyDelta { .. } | ServerMessage::Ping { .. } => continue,
+                m => panic!("Unexpected message {m:?}"),
+            }
+        }
+        assert_eq!(state.metrics().lagged(), 1);
+    }
+
+    #[tokio::test]
+    async fn flood() {
+        let state = ServerState::default();
+        let (mut tx, mut rx) = connect(&state);
+        tx.write_frame(hello(PROTOCOL_VERSION)).await.unwrap();
+        tx.write_frame(ClientMessage::GameHost).await.unwrap();
+        rx.read_frame().await.unwrap();
+        rx.enable_compression();
+
+        tokio::spawn(async move {
+            for i in 0.. {
+                let m = ClientMessage::Lobby(LobbyMessage::PlayerCanStart(i % 2 == 0));
+                if tx.write_frame(m).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        // The client is warned before being disconnected
+        let mut warned = false;
+        while let Ok(Some(m)) = rx.read_frame().await {
+            if let ServerMessage::Error {
+                error: ProtocolError::RateLimited,
+            } = m
+            {
+                warned = true;
+            }
+        }
+        assert!(warned);
+    }
+
+    #[tokio::test]
+    async fn flood_requests() {
+        let state = ServerState::default();
+        let (mut tx, mut rx) = connect(&state);
+        tx.write_frame(hello(PROTOCOL_VERSION)).await.unwrap();
+        tx.write_frame(ClientMessage::GameHost).await.unwrap();
+        rx.read_frame().await.unwrap();
+        rx.enable_compression();
+
+        tokio::spawn(async move {
+            for id in 0.. {
+                let m = ClientMessage::Request {
+                    id,
+                    message: LobbyMessage::PlayerCanStart(id % 2 == 0),
+                };
+                if tx.write_frame(m).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        // Dropped requests are still answered
+        let mut rejected = false;
+        while let Ok(Some(m)) = rx.read_frame().await {
+            if let ServerMessage::Response {
+                result: Err(LobbyError::RateLimited),
+                ..
+            } = m
+            {
+                rejected = true;
+            }
+        }
+        assert!(rejected);
+    }
+
+    #[tokio::test]
+    async fn password() {
+        let state = ServerState::default();
+        let (mut host_tx, mut host_rx) = connect(&state);
+        host_tx.write_frame(hello(PROTOCOL_VERSION)).await.unwrap();
+        host_tx.write_frame(ClientMessage::GameHost).await.unwrap();
+        host_rx.read_frame().await.unwrap();
+        host_rx.enable_compression();
+        let Some(ServerMessage::GameLobbyInfo { lobby }) = host_rx.read_frame().await.unwrap()
+        else {
+            panic!("Client should be sent their lobby after joining");
+        };
+        host_tx
+            .write_frame(ClientMessage::Request {
+                id: 0,
+                message: LobbyMessage::GameOptions {
+                    options: LobbyOptions {
+                        password: Password::New("hunter2".to_owned()),
+                        allow_spectators: false,
+                        ..Default::default()
+                    },
+                },
+            })
+            .await
+            .unwrap();
+        // The password itself is never sent back out
+        loop {
+            match host_rx.read_frame().await.unwrap().unwrap() {
+                ServerMessage::LobbyDelta {
+                    delta: LobbyDelta::Action { action, .. },
+                    ..
+                } => {
+                    let LobbyMessage::GameOptions { options } = action else {
+                        panic!("Unexpected action {action:?}");
+                    };
+                    assert_eq!(options.password, Password::Protected);
+                    break;
+                }
+                _ => continue,
+            }
+        }
+
+        let join = |password: Option<&str>, spectate| ClientMessage::GameJoin {
+            lobby_id: lobby.lobby_id,
+            spectate,
+            password: password.map(str::to_owned),
+        };
+        for (msg, expected) in [
+            (join(None, false), ProtocolError::IncorrectPassword),
+            (
+                join(Some("hunter3"), false),
+                ProtocolError::IncorrectPassword,
+            ),
+            (
+                join(Some("hunter2"), true),
+                ProtocolError::SpectatingDisabled,
+            ),
+        ] {
+            let (mut tx, mut rx) = connect(&state);
+            tx.write_frame(hello(PROTOCOL_VERSION)).await.unwrap();
+            tx.write_frame(msg).await.unwrap();
+            let Some(ServerMessage::Error { error }) = rx.read_frame().await.unwrap() else {
+                panic!("Client should be rejected");
+            };
+            assert_eq!(
+                std::mem::discriminant(&error),
+                std::mem::discriminant(&expected)
+            );
+        }
+
+        let (mut tx, mut rx) = connect(&state);
+        tx.write_frame(hello(PROTOCOL_VERSION)).await.unwrap();
+        tx.write_frame(join(Some("hunter2"), false)).await.unwrap();
+        assert!(matches!(
+            rx.read_frame().await.unwrap(),
+            Some(ServerMessage::ConnectionAccept { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn kicked_player_cant_resume() {
+        let state = ServerState::default();
+        let (mut host_tx, mut host_rx) = connect(&state);
+        host_tx.write_frame(hello(PROTOCOL_VERSION)).await.unwrap();
+        host_tx.write_frame(ClientMessage::GameHost).await.unwrap();
+        host_rx.read_frame().await.unwrap();
+        host_rx.enable_compression();
+        let Some(ServerMessage::GameLobbyInfo { lobby }) = host_rx.read_frame().await.unwrap()
+        else {
+            panic!("Client should be sent their lobby after joining");
+        };
+
+        let (mut tx, mut rx) = connect(&state);
+        tx.write_frame(hello(PROTOCOL_VERSION)).await.unwrap();
+        tx.write_frame(ClientMessage::GameJoin {
+            lobby_id: lobby.lobby_id,
+            spectate: false,
+            password: None,
+        })
+        .await
+        .unwrap();
+        let Some(ServerMessage::ConnectionAccept {
+            player_id,
+            resume_token: Some(token),
+            ..
+        }) = rx.read_frame().await.unwrap()
+        else {
+            panic!("Player should be accepted with a resume token");
+        };
+        rx.enable_compression();
+
+        host_tx
+            .write_frame(ClientMessage::Lobby(LobbyMessage::KickPlayer { player_id }))
+            .await
+            .unwrap();
+        loop {
+            match rx.read_frame().await.unwrap() {
+                Some(ServerMessage::Error { error }) => {
+                    assert!(matches!(error, ProtocolError::Kicked));
+                    break;
+                }
+                Some(_) => continue,
+                None => panic!("Player should be told they were kicked"),
+            }
+        }
+
+        // The kicked player's id is released instead of being held for them to resume with
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while state.player_ids().contains(&player_id) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("Kicked player's session shouldn't be held");
+        let (mut tx, mut rx) = connect(&state);
+        tx.write_frame(hello(PROTOCOL_VERSION)).await.unwrap();
+        tx.write_frame(ClientMessage::Resume { token })
+            .await
+            .unwrap();
+        assert!(matches!(
+            rx.read_frame().await.unwrap(),
+            Some(ServerMessage::Error {
+                error: ProtocolError::InvalidResumeToken
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn tls_handshake_is_pending() {
+        let state = ServerState::new(Config {
+            max_pending_connections: 1,
+            handshake_timeout: Duration::from_millis(200),
+            ..Default::default()
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stalled = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
+        let accept = tokio::spawn(accept_connection(
+            state.clone(),
+            Some(testing::tls_acceptor()),
+            socket,
+        ));
+        tokio::task::yield_now().await;
+
+        // A client that never finishes its TLS handshake holds a handshake slot
+        let (mut tx, mut rx) = connect(&state);
+        let _ = tx.write_frame(hello(PROTOCOL_VERSION)).await;
+        assert!(matches!(
+            rx.read_frame().await.unwrap(),
+            Some(ServerMessage::Error {
+                error: ProtocolError::ServerBusy
+            })
+        ));
+
+        // Until the handshake deadline runs out
+        tokio::time::timeout(Duration::from_secs(2), accept)
+            .await
+            .expect("TLS handshake should time out")
+            .unwrap();
+        drop(stalled);
+    }
+
+    #[tokio::test]
+    async fn handshake_deadline() {
+        let state = ServerState::new(Config {
+            handshake_timeout: Duration::from_millis(200),
+            ..Default::default()
+        });
+        let (mut tx, mut rx) = connect(&state);
+        tx.write_frame(hello(PROTOCOL_VERSION)).await.unwrap();
+
+        // Browsing lobbies doesn't buy the client more time to finish its handshake
+        let browse = async {
+            loop {
+                // Writing fails once the server has given up, but its reason can still be read
+                let _ = tx.write_frame(ClientMessage::ListLobbies).await;
+                match rx.read_frame().await.unwrap() {
+                    Some(ServerMessage::LobbyList { .. }) => {
+                        tokio::time::sleep(Duration::from_millis(20)).await
+                    }
+                    m => return m,
+                }
+            }
+        };
+        assert!(matches!(
+            tokio::time::timeout(Duration::from_secs(2), browse).await,
+            Ok(Some(ServerMessage::Error {
+                error: ProtocolError::Timeout
+            }))
+        ));
+    }
+
+    #[tokio::test]
+    async fn version_mismatch() {
+        let state = ServerState::default();
+        let (mut tx, mut rx) = connect(&state);
+        tx.write_frame(hello(u16::MAX)).await.unwrap();
+
+        let Some(ServerMessage::Error {
+            error: ProtocolError::VersionMismatch(client, server),
+        }) = rx.read_frame().await.unwrap()
+        else {
+            panic!("Client should be told about the version mismatch");
+        };
+        assert_eq!(client, u16::MAX.to_string());
+        assert_eq!(server, PROTOCOL_VERSION.to_string());
+        assert!(rx.read_frame().await.unwrap().is_none());
+    }
+
+    /// Messages as they were before protocol versions existed, trimmed to what is exchanged during
+    /// the handshake.
+    #[derive(Debug, Serialize, Deserialize)]
+    enum LegacyMessage {
+        Error { error: LegacyError },
+        Version { version: String },
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
+    enum LegacyError {
+        InvalidLobbyId(LobbyId),
+        InvalidMessage,
+        Disconnected,
+        VersionMismatch(String, String),
+        Message(String),
+    }
+
+    #[tokio::test]
+    async fn legacy_client() {
+        let state = ServerState::default();
+        let (client, server) = duplex(1024);
+        tokio::spawn(handle_new_connection(
+            state.clone(),
+            connection::from_stream(server),
+            None,
+        ));
+        let (mut tx, mut rx) = connection::from_stream::<LegacyMessage, LegacyMessage>(client);
+        tx.write_frame(LegacyMessage::Version {
+            version: "0.1.0".to_owned(),
+        })
+        .await
+        .unwrap();
+
+        let Some(LegacyMessage::Error {
+            error: LegacyError::VersionMismatch(_, server),
+        }) = rx.read_frame().await.unwrap()
+        else {
+            panic!("Legacy client should be told about the version mismatch");
+        };
+        assert_eq!(server, PROTOCOL_VERSION.to_string());
+    }
+}
@@ -0,0 +1,433 @@
+use std::fmt::Display;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+use clap::{Parser, ValueEnum};
+use clash_lib::lobby::LobbyOptions;
+use clash_lib::net::connection::DEFAULT_COMPRESSION_THRESHOLD;
+use clash_lib::net::tls::{self, TlsAcceptor};
+use serde::{de, Deserialize, Deserializer};
+use tracing::metadata::LevelFilter;
+
+use crate::rate_limit::RateLimit;
+
+const DEFAULT_PORT: u16 = 42932;
+const DEFAULT_MAX_LOBBIES: usize = 1024;
+const DEFAULT_MAX_CONNECTIONS: usize = 4096;
+const DEFAULT_RESUME_GRACE: Duration = Duration::from_secs(60);
+const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+const DEFAULT_HEARTBEAT_MISSES: u32 = 3;
+const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_MAX_PENDING_CONNECTIONS: usize = 64;
+const DEFAULT_MAX_FRAME_LEN: u32 = 1024 * 1024;
+const DEFAULT_LAG_LIMIT: u32 = 3;
+const DEFAULT_LAG_WINDOW: Duration = Duration::from_secs(60);
+const DEFAULT_RATE_LIMIT: RateLimit = RateLimit {
+    per_sec: 20.0,
+    burst: 40,
+};
+const DEFAULT_MESSAGE_RATE_LIMIT: RateLimit = RateLimit {
+    per_sec: 5.0,
+    burst: 10,
+};
+const DEFAULT_RATE_LIMIT_STRIKES: u32 = 5;
+
+/// Server settings, read from a TOML file and the command line at startup.
+///
+/// Field names in the file match the fields here, except durations which are given in seconds
+/// and end in `_secs`. Default lobby options go in a `[lobby]` table.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Addresses to listen on. IPv6 addresses are supported, `::` will usually accept both IPv4
+    /// and IPv6 connections.
+    pub bind: Vec<IpAddr>,
+    pub port: u16,
+    /// Port to accept WebSocket spectators on. The WebSocket listener is disabled without one.
+    pub websocket_port: Option<u16>,
+    /// Port to serve the admin console on, only reachable from this machine. The console is
+    /// disabled without one.
+    pub admin_port: Option<u16>,
+    #[serde(deserialize_with = "from_str")]
+    pub log_level: LevelFilter,
+    pub log_format: LogFormat,
+    /// How many lobbies may be open at once.
+    pub max_lobbies: usize,
+    /// How many clients may be connected at once, including WebSocket spectators.
+    pub max_connections: usize,
+    /// Options new lobbies start with.
+    pub lobby: LobbyOptions,
+    /// Shown to players when they connect.
+    pub motd: Option<String>,
+    /// How long a player who lost connection keeps their place in a lobby before being removed.
+    #[serde(rename = "resume_grace_secs", deserialize_with = "secs")]
+    pub resume_grace: Duration,
+    /// How often clients are pinged.
+    #[serde(rename = "heartbeat_interval_secs", deserialize_with = "secs")]
+    pub heartbeat_interval: Duration,
+    /// How many pings in a row a client can fail to answer before they are disconnected.
+    pub heartbeat_misses: u32,
+    /// How long a new connection has to complete its handshake.
+    #[serde(rename = "handshake_timeout_secs", deserialize_with = "secs")]
+    pub handshake_timeout: Duration,
+    /// How many connections may be in the middle of their handshake at once. Connections beyond
+    /// this are turned away immediately.
+    pub max_pending_connections: usize,
+    /// Largest message in bytes that clients may send. Larger messages are rejected with an error.
+    pub max_frame_len: u32,
+    /// Messages to clients that are at least this many bytes are compressed, if the client
+    /// supports it.
+    pub compression_threshold: usize,
+    /// How many times a client can fall behind on lobby updates within `lag_window` before it is
+    /// disconnected. Each time it is sent a new snapshot of the lobby instead.
+    pub lag_limit: u32,
+    #[serde(rename = "lag_window_secs", deserialize_with = "secs")]
+    pub lag_window: Duration,
+    /// How quickly each client may send messages of any kind.
+    pub rate_limit: RateLimit,
+    /// How quickly each client may send each kind of lobby message.
+    pub message_rate_limit: RateLimit,
+    /// How many messages a client may have dropped for exceeding its rate limits each minute
+    /// before it is disconnected.
+    pub rate_limit_strikes: u32,
+    /// PEM encoded certificate chain to serve TLS with. Connections are unencrypted without one.
+    pub tls_cert: Option<PathBuf>,
+    /// PEM encoded private key for `tls_cert`.
+    pub tls_key: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Full,
+    Compact,
+    /// One JSON object per line, for log collectors.
+    Json,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bind: vec![IpAddr::V4(Ipv4Addr::UNSPECIFIED)],
+            port: DEFAULT_PORT,
+            websocket_port: None,
+            admin_port: None,
+            log_level: LevelFilter::INFO,
+            log_format: LogFormat::default(),
+            max_lobbies: DEFAULT_MAX_LOBBIES,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            lobby: LobbyOptions::default(),
+            motd: None,
+            resume_grace: DEFAULT_RESUME_GRACE,
+            heartbeat_interval: DEFAULT_HEARTBEAT_INTERVAL,
+            heartbeat_misses: DEFAULT_HEARTBEAT_MISSES,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            max_pending_connections: DEFAULT_MAX_PENDING_CONNECTIONS,
+            max_frame_len: DEFAULT_MAX_FRAME_LEN,
+            compression_threshold: DEFAULT_COMPRESSION_THRESHOLD,
+            lag_limit: DEFAULT_LAG_LIMIT,
+            lag_window: DEFAULT_LAG_WINDOW,
+            rate_limit: DEFAULT_RATE_LIMIT,
+            message_rate_limit: DEFAULT_MESSAGE_RATE_LIMIT,
+            rate_limit_strikes: DEFAULT_RATE_LIMIT_STRIKES,
+            tls_cert: None,
+            tls_key: None,
+        }
+    }
+}
+
+/// Command line flags, each of which can also be set with the environment variable listed in
+/// `--help`. These take priority over the config file.
+#[derive(Debug, Parser)]
+#[command(version = crate::VERSION, about = "Server for BfBB Clash")]
+pub struct Args {
+    /// TOML file to read settings from.
+    #[arg(short, long, env = "CLASH_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// Address to listen on, may be given more than once.
+    #[arg(long, env = "BIND", value_delimiter = ',')]
+    pub bind: Vec<IpAddr>,
+    #[arg(short, long, env = "PORT")]
+    pub port: Option<u16>,
+    #[arg(long, env = "WEBSOCKET_PORT")]
+    pub websocket_port: Option<u16>,
+    #[arg(long, env = "ADMIN_PORT")]
+    pub admin_port: Option<u16>,
+    /// One of off, error, warn, info, debug or trace.
+    #[arg(long, env = "LOG_LEVEL")]
+    pub log_level: Option<LevelFilter>,
+    #[arg(long, env = "LOG_FORMAT")]
+    pub log_format: Option<LogFormat>,
+    #[arg(long, env = "MAX_LOBBIES")]
+    pub max_lobbies: Option<usize>,
+    #[arg(long, env = "MAX_CONNECTIONS")]
+    pub max_connections: Option<usize>,
+    /// Message of the day, shown to players when they connect.
+    #[arg(long, env = "MOTD")]
+    pub motd: Option<String>,
+    #[arg(long, env = "RESUME_GRACE_SECS")]
+    pub resume_grace_secs: Option<u64>,
+    #[arg(long, env = "HEARTBEAT_INTERVAL_SECS")]
+    pub heartbeat_interval_secs: Option<u64>,
+    #[arg(long, env = "HEARTBEAT_MISSES")]
+    pub heartbeat_misses: Option<u32>,
+    #[arg(long, env = "HANDSHAKE_TIMEOUT_SECS")]
+    pub handshake_timeout_secs: Option<u64>,
+    #[arg(long, env = "MAX_PENDING_CONNECTIONS")]
+    pub max_pending_connections: Option<usize>,
+    #[arg(long, env = "MAX_FRAME_LEN")]
+    pub max_frame_len: Option<u32>,
+    #[arg(long, env = "COMPRESSION_THRESHOLD")]
+    pub compression_threshold: Option<usize>,
+    #[arg(long, env = "LAG_LIMIT")]
+    pub lag_limit: Option<u32>,
+    #[arg(long, env = "LAG_WINDOW_SECS")]
+    pub lag_window_secs: Option<u64>,
+    #[arg(long, env = "RATE_LIMIT_PER_SEC")]
+    pub rate_limit_per_sec: Option<f64>,
+    #[arg(long, env = "RATE_LIMIT_BURST")]
+    pub rate_limit_burst: Option<u32>,
+    #[arg(long, env = "MESSAGE_RATE_LIMIT_PER_SEC")]
+    pub message_rate_limit_per_sec: Option<f64>,
+    #[arg(long, env = "MESSAGE_RATE_LIMIT_BURST")]
+    pub message_rate_limit_burst: Option<u32>,
+    #[arg(long, env = "RATE_LIMIT_STRIKES")]
+    pub rate_limit_strikes: Option<u32>,
+    #[arg(long, env = "TLS_CERT")]
+    pub tls_cert: Option<PathBuf>,
+    #[arg(long, env = "TLS_KEY")]
+    pub tls_key: Option<PathBuf>,
+}
+
+impl Config {
+    /// Reads the config file given on the command line, if any, then applies the rest of the
+    /// command line on top of it.
+    pub fn load() -> anyhow::Result<Self> {
+        Self::from_args(Args::parse())
+    }
+
+    pub fn from_args(args: Args) -> anyhow::Result<Self> {
+        let mut config = match &args.config {
+            Some(path) => Self::from_file(path)?,
+            None => Self::default(),
+        };
+        config.apply(args);
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Rejects settings that the server can't run with.
+    fn validate(&self) -> anyhow::Result<()> {
+        for (name, value) in [
+            ("HEARTBEAT_INTERVAL_SECS", self.heartbeat_interval),
+            ("HANDSHAKE_TIMEOUT_SECS", self.handshake_timeout),
+            ("LAG_WINDOW_SECS", self.lag_window),
+        ] {
+            if value.is_zero() {
+                bail!("{name} must be at least 1");
+            }
+        }
+        if self.heartbeat_misses == 0 {
+            bail!("HEARTBEAT_MISSES must be at least 1");
+        }
+        for (name, limit) in [
+            ("RATE_LIMIT", self.rate_limit),
+            ("MESSAGE_RATE_LIMIT", self.message_rate_limit),
+        ] {
+            // Also catches NaN
+            if !(limit.per_sec > 0.0 && limit.per_sec.is_finite()) {
+                bail!("{name}_PER_SEC must be a positive number");
+            }
+            if limit.burst == 0 {
+                bail!("{name}_BURST must be at least 1");
+            }
+        }
+        Ok(())
+    }
+
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        toml::from_str(&text).with_context(|| format!("Failed to parse {}", path.display()))
+    }
+
+    /// Loads `tls_cert` and `tls_key`, or returns `None` when TLS isn't configured.
+    pub fn tls_acceptor(&self) -> anyhow::Result<Option<TlsAcceptor>> {
+        match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => tls::acceptor(cert, key)
+                .map(Some)
+                .with_context(|| format!("Failed to load TLS certificate {}", cert.display())),
+            (None, None) => Ok(None),
+            _ => bail!("Both TLS_CERT and TLS_KEY must be set to enable TLS"),
+        }
+    }
+
+    fn apply(&mut self, args: Args) {
+        if !args.bind.is_empty() {
+            self.bind = args.bind;
+        }
+        set(&mut self.port, args.port);
+        self.websocket_port = args.websocket_port.or(self.websocket_port);
+        self.admin_port = args.admin_port.or(self.admin_port);
+        set(&mut self.log_level, args.log_level);
+        set(&mut self.log_format, args.log_format);
+        set(&mut self.max_lobbies, args.max_lobbies);
+        set(&mut self.max_connections, args.max_connections);
+        self.motd = args.motd.or(self.motd.take());
+        set(
+            &mut self.resume_grace,
+            args.resume_grace_secs.map(Duration::from_secs),
+        );
+        set(
+            &mut self.heartbeat_interval,
+            args.heartbeat_interval_secs.map(Duration::from_secs),
+        );
+        set(&mut self.heartbeat_misses, args.heartbeat_misses);
+        set(
+            &mut self.handshake_timeout,
+            args.handshake_timeout_secs.map(Duration::from_secs),
+        );
+        set(
+            &mut self.max_pending_connections,
+            args.max_pending_connections,
+        );
+        set(&mut self.max_frame_len, args.max_frame_len);
+        set(&mut self.compression_threshold, args.compression_threshold);
+        set(&mut self.lag_limit, args.lag_limit);
+        set(
+            &mut self.lag_window,
+            args.lag_window_secs.map(Duration::from_secs),
+        );
+        set(&mut self.rate_limit.per_sec, args.rate_limit_per_sec);
+        set(&mut self.rate_limit.burst, args.rate_limit_burst);
+        set(
+            &mut self.message_rate_limit.per_sec,
+            args.message_rate_limit_per_sec,
+        );
+        set(
+            &mut self.message_rate_limit.burst,
+            args.message_rate_limit_burst,
+        );
+        set(&mut self.rate_limit_strikes, args.rate_limit_strikes);
+        self.tls_cert = args.tls_cert.or(self.tls_cert.take());
+        self.tls_key = args.tls_key.or(self.tls_key.take());
+    }
+}
+
+fn set<T>(field: &mut T, value: Option<T>) {
+    if let Some(value) = value {
+        *field = value;
+    }
+}
+
+fn secs<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    u64::deserialize(deserializer).map(Duration::from_secs)
+}
+
+fn from_str<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    String::deserialize(deserializer)?
+        .parse()
+        .map_err(de::Error::custom)
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{IpAddr, Ipv6Addr};
+    use std::time::Duration;
+
+    use clap::Parser;
+    use tracing::metadata::LevelFilter;
+
+    use super::{Args, Config};
+
+    #[test]
+    fn config_file() {
+        let config: Config = toml::from_str(
+            r#"
+            bind = ["::"]
+            log_level = "warn"
+            heartbeat_interval_secs = 2
+            motd = "Welcome!"
+
+            [rate_limit]
+            per_sec = 1.5
+            burst = 3
+
+            [lobby]
+            tier_count = 2
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.bind, [IpAddr::V6(Ipv6Addr::UNSPECIFIED)]);
+        assert_eq!(config.log_level, LevelFilter::WARN);
+        assert_eq!(config.heartbeat_interval, Duration::from_secs(2));
+        assert_eq!(config.motd.as_deref(), Some("Welcome!"));
+        assert_eq!(config.rate_limit.burst, 3);
+        assert_eq!(config.lobby.tier_count, 2);
+        // Anything left out keeps its default
+        assert_eq!(config.port, Config::default().port);
+        assert_eq!(
+            config.lobby.lab_door_cost,
+            Config::default().lobby.lab_door_cost
+        );
+
+        assert!(toml::from_str::<Config>("prot = 1").is_err());
+    }
+
+    #[test]
+    fn flags_override_file() {
+        let mut config: Config = toml::from_str("port = 1\nheartbeat_misses = 7").unwrap();
+        config.apply(Args::parse_from([
+            "clash-server",
+            "--port",
+            "2",
+            "--bind",
+            "127.0.0.1,::1",
+        ]));
+
+        assert_eq!(config.port, 2);
+        assert_eq!(config.heartbeat_misses, 7);
+        assert_eq!(config.bind.len(), 2);
+    }
+
+    #[test]
+    fn invalid_values() {
+        for arg in [
+            "--heartbeat-interval-secs=0",
+            "--handshake-timeout-secs=0",
+            "--heartbeat-misses=0",
+            "--rate-limit-per-sec=0",
+            "--rate-limit-per-sec=-1",
+            "--message-rate-limit-per-sec=NaN",
+            "--message-rate-limit-burst=0",
+        ] {
+            let args = Args::parse_from(["clash-server", arg]);
+            assert!(Config::from_args(args).is_err(), "{arg} should be rejected");
+        }
+        assert!(Config::from_args(Args::parse_from(["clash-server"])).is_ok());
+    }
+
+    #[test]
+    fn tls_misconfigured() {
+        let mut config = Config::default();
+        assert!(config.tls_acceptor().unwrap().is_none());
+
+        config.tls_cert = Some("cert.pem".into());
+        let e = config.tls_acceptor().err().unwrap();
+        assert!(e.to_string().contains("TLS_KEY"));
+
+        config.tls_key = Some("does-not-exist.pem".into());
+        config.tls_cert = Some("does-not-exist.pem".into());
+        assert!(config.tls_acceptor().is_err());
+    }
+}
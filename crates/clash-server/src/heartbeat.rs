@@ -0,0 +1,102 @@
+use std::time::Duration;
+
+use clash_lib::net::ServerMessage;
+use tokio::time::{self, Instant, Interval, MissedTickBehavior};
+
+/// Pings a client periodically to measure round-trip time and to detect connections that have
+/// silently died.
+pub struct Heartbeat {
+    interval: Interval,
+    max_missed: u32,
+    missed: u32,
+    next_nonce: u32,
+    /// The latest ping that hasn't been answered yet, and when it was sent.
+    outstanding: Option<(u32, Instant)>,
+}
+
+impl Heartbeat {
+    pub fn new(period: Duration, max_missed: u32) -> Self {
+        let mut interval = time::interval(period);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        Self {
+            interval,
+            max_missed,
+            missed: 0,
+            next_nonce: 0,
+            outstanding: None,
+        }
+    }
+
+    /// Waits until the next ping should be sent and returns it.
+    ///
+    /// Returns `None` instead if the client has missed too many pings in a row and should be
+    /// disconnected.
+    ///
+    /// # Cancel safety
+    ///
+    /// This method is cancel safe.
+    pub async fn tick(&mut self) -> Option<ServerMessage> {
+        self.interval.tick().await;
+        if self.outstanding.is_some() {
+            self.missed += 1;
+        }
+        if self.missed >= self.max_missed {
+            return None;
+        }
+
+        let nonce = self.next_nonce;
+        self.next_nonce = self.next_nonce.wrapping_add(1);
+        self.outstanding = Some((nonce, Instant::now()));
+        Some(ServerMessage::Ping { nonce })
+    }
+
+    /// Records a response from the client, returning the round-trip time if it answers the
+    /// latest ping.
+    pub fn pong(&mut self, nonce: u32) -> Option<Duration> {
+        // Even a late response shows the connection is still alive
+        self.missed = 0;
+        match self.outstanding {
+            Some((expected, sent)) if expected == nonce => {
+                self.outstanding = None;
+                Some(sent.elapsed())
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use clash_lib::net::ServerMessage;
+
+    use super::Heartbeat;
+
+    #[tokio::test]
+    async fn measures_rtt() {
+        let mut heartbeat = Heartbeat::new(Duration::from_millis(1), 2);
+        let Some(ServerMessage::Ping { nonce }) = heartbeat.tick().await else {
+            panic!("First tick should produce a ping");
+        };
+
+        // Only the latest ping can be used to measure rtt
+        assert!(heartbeat.pong(nonce + 1).is_none());
+        assert!(heartbeat.pong(nonce).is_some());
+        assert!(heartbeat.pong(nonce).is_none());
+    }
+
+    #[tokio::test]
+    async fn missed_pings() {
+        let mut heartbeat = Heartbeat::new(Duration::from_millis(1), 2);
+        let Some(ServerMessage::Ping { nonce }) = heartbeat.tick().await else {
+            panic!("First tick should produce a ping");
+        };
+        assert!(heartbeat.tick().await.is_some());
+
+        // A late response still resets the missed count
+        heartbeat.pong(nonce);
+        assert!(heartbeat.tick().await.is_some());
+        assert!(heartbeat.tick().await.is_none());
+    }
+}
@@ -0,0 +1,92 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::state::ServerState;
+
+/// Keeps track of how often a client falls behind on lobby updates, so that a client that
+/// can't keep up is disconnected instead of being sent a new snapshot over and over.
+pub struct LagTracker {
+    state: ServerState,
+    limit: u32,
+    window: Duration,
+    /// When each lag within the current window happened, oldest first.
+    recent: VecDeque<Instant>,
+}
+
+impl LagTracker {
+    pub fn new(state: ServerState) -> Self {
+        let config = state.config();
+        let (limit, window) = (config.lag_limit, config.lag_window);
+        Self {
+            state,
+            limit,
+            window,
+            recent: VecDeque::new(),
+        }
+    }
+
+    /// Records that the client missed `skipped` lobby updates.
+    ///
+    /// Returns `false` if the client has now lagged more than the configured limit within the
+    /// configured window and should be disconnected.
+    pub fn lagged(&mut self, skipped: u64) -> bool {
+        let now = Instant::now();
+        while let Some(&oldest) = self.recent.front() {
+            if now.duration_since(oldest) < self.window {
+                break;
+            }
+            self.recent.pop_front();
+        }
+        self.recent.push_back(now);
+
+        let metrics = self.state.metrics();
+        metrics.record_lag();
+        if self.recent.len() > self.limit as usize {
+            metrics.record_lag_disconnect();
+            tracing::warn!(
+                total = metrics.lag_disconnects(),
+                "Client missed {skipped} lobby updates and has lagged {} times recently, closing connection",
+                self.recent.len()
+            );
+            return false;
+        }
+        tracing::warn!(
+            total = metrics.lagged(),
+            "Client missed {skipped} lobby updates, sending a new snapshot"
+        );
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::config::Config;
+    use crate::state::ServerState;
+
+    use super::LagTracker;
+
+    #[tokio::test]
+    async fn repeated_lag() {
+        let state = ServerState::new(Config {
+            lag_limit: 2,
+            lag_window: Duration::from_millis(50),
+            ..Default::default()
+        });
+        let mut lag = LagTracker::new(state.clone());
+        assert!(lag.lagged(1));
+        assert!(lag.lagged(1));
+
+        // Lagging is forgiven once the window has passed
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert!(lag.lagged(1));
+        assert!(lag.lagged(1));
+        assert!(!lag.lagged(1));
+
+        assert_eq!(state.metrics().lagged(), 5);
+        assert_eq!(state.metrics().lag_disconnects(), 1);
+    }
+}
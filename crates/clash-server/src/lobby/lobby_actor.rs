@@ -1,21 +1,38 @@
 use bfbb::{Level, Spatula};
-use clash_lib::lobby::{GamePhase, LobbyOptions, NetworkedLobby};
-use clash_lib::net::{Item, LobbyMessage, Message};
+use clash_lib::lobby::{GamePhase, LobbyOptions, LobbySummary, NetworkedLobby, MAX_LOBBY_NAME_LEN};
+use clash_lib::net::{Item, LobbyDelta, LobbyMessage, ProtocolError, ServerMessage};
 use clash_lib::player::{NetworkedPlayer, PlayerOptions};
 use clash_lib::{LobbyId, PlayerId, MAX_PLAYERS};
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::time::Duration;
 use tokio::sync::{broadcast, mpsc, oneshot};
 use tracing::instrument;
 
 use crate::state::OwnedId;
 
-use super::{LobbyError, LobbyResult};
+use super::password::PasswordHash;
+use super::standings;
+use super::{LobbyError, LobbyEvent, LobbyResult, LobbySubscription};
 
 pub struct LobbyActor {
     id: OwnedId<LobbyId>,
     receiver: mpsc::Receiver<LobbyAction>,
     shared: NetworkedLobby,
-    sender: broadcast::Sender<Message>,
+    sender: broadcast::Sender<LobbyEvent>,
     next_menu_order: u8,
+    /// When each player joined, used to pick the next host. `menu_order` is too narrow for this
+    /// since it wraps around in long-running lobbies.
+    join_order: HashMap<PlayerId, u64>,
+    next_join: u64,
+    password: Option<PasswordHash>,
+    spectate_password: Option<PasswordHash>,
+    /// Where each player connected from, so they can be banned by IP address.
+    addresses: HashMap<PlayerId, IpAddr>,
+    banned_players: HashSet<PlayerId>,
+    banned_addresses: HashSet<IpAddr>,
+    /// The lobby sequence at which each player last collected a spatula worth points this game.
+    last_collection: HashMap<PlayerId, u64>,
 }
 
 #[derive(Debug)]
@@ -28,16 +45,44 @@ pub enum LobbyAction {
         respond_to: oneshot::Sender<LobbyResult<()>>,
         id: PlayerId,
     },
+    EndGame {
+        respond_to: oneshot::Sender<LobbyResult<()>>,
+        id: PlayerId,
+    },
     AddPlayer {
-        respond_to: oneshot::Sender<LobbyResult<broadcast::Receiver<Message>>>,
+        respond_to: oneshot::Sender<LobbyResult<LobbySubscription>>,
         id: PlayerId,
+        addr: Option<IpAddr>,
     },
     AddSpectator {
-        respond_to: oneshot::Sender<broadcast::Receiver<Message>>,
+        respond_to: oneshot::Sender<LobbySubscription>,
+    },
+    Authorize {
+        respond_to: oneshot::Sender<Result<(), ProtocolError>>,
+        password: Option<String>,
+        spectate: bool,
+        addr: Option<IpAddr>,
+    },
+    GetSnapshot {
+        respond_to: oneshot::Sender<NetworkedLobby>,
+    },
+    GetSummary {
+        respond_to: oneshot::Sender<Option<LobbySummary>>,
     },
     RemovePlayer {
         id: PlayerId,
     },
+    DisconnectPlayer {
+        id: PlayerId,
+    },
+    ReconnectPlayer {
+        respond_to: oneshot::Sender<LobbyResult<LobbySubscription>>,
+        id: PlayerId,
+    },
+    SetPlayerLatency {
+        id: PlayerId,
+        latency: Duration,
+    },
     SetPlayerOptions {
         respond_to: oneshot::Sender<LobbyResult<()>>,
         id: PlayerId,
@@ -63,19 +108,53 @@ pub enum LobbyAction {
         id: PlayerId,
         options: LobbyOptions,
     },
+    /// Kick `target`, on behalf of the host `id` or the server itself when it's `None`.
+    KickPlayer {
+        respond_to: oneshot::Sender<LobbyResult<()>>,
+        id: Option<PlayerId>,
+        target: PlayerId,
+    },
+    BanPlayer {
+        respond_to: oneshot::Sender<LobbyResult<()>>,
+        id: PlayerId,
+        target: PlayerId,
+        ban_ip: bool,
+    },
+    TransferHost {
+        respond_to: oneshot::Sender<LobbyResult<()>>,
+        id: PlayerId,
+        target: PlayerId,
+    },
+    Announce {
+        message: String,
+    },
+    Close,
 }
 
 impl LobbyActor {
-    pub fn new(receiver: mpsc::Receiver<LobbyAction>, lobby_id: OwnedId<LobbyId>) -> Self {
+    pub fn new(
+        receiver: mpsc::Receiver<LobbyAction>,
+        lobby_id: OwnedId<LobbyId>,
+        options: LobbyOptions,
+    ) -> Self {
         let (sender, _) = broadcast::channel(100);
-
-        Self {
+        let mut actor = Self {
             receiver,
             shared: NetworkedLobby::new(*lobby_id),
             id: lobby_id,
             sender,
             next_menu_order: 0,
-        }
+            join_order: HashMap::new(),
+            next_join: 0,
+            password: None,
+            spectate_password: None,
+            addresses: HashMap::new(),
+            banned_players: HashSet::new(),
+            banned_addresses: HashSet::new(),
+            last_collection: HashMap::new(),
+        };
+        actor.shared.options = actor.store_passwords(options);
+        actor
     }
 
     #[instrument(skip_all, fields(lobby_id = %self.id))]
@@ -89,13 +168,47 @@ impl LobbyActor {
                 LobbyAction::StartGame { respond_to, id } => {
                     let _ = respond_to.send(self.start_game(id));
                 }
-                LobbyAction::AddPlayer { respond_to, id } => {
-                    let _ = respond_to.send(self.add_player(id));
+                LobbyAction::EndGame { respond_to, id } => {
+                    let _ = respond_to.send(self.end_game(id));
+                }
+                LobbyAction::AddPlayer {
+                    respond_to,
+                    id,
+                    addr,
+                } => {
+                    let result = self.add_player(id);
+                    if let (Ok(_), Some(addr)) = (&result, addr) {
+                        self.addresses.insert(id, addr);
+                    }
+                    let _ = respond_to.send(result);
                 }
                 LobbyAction::AddSpectator { respond_to } => {
                     let _ = respond_to.send(self.add_spectator());
                 }
+                LobbyAction::Authorize {
+                    respond_to,
+                    password,
+                    spectate,
+                    addr,
+                } => {
+                    let result = self.authorize(password.as_deref(), spectate, addr);
+                    let _ = respond_to.send(result);
+                }
+                LobbyAction::GetSnapshot { respond_to } => {
+                    let _ = respond_to.send(self.shared.clone());
+                }
+                LobbyAction::GetSummary { respond_to } => {
+                    let summary = self.shared.options.public.then(|| self.shared.summary());
+                    let _ = respond_to.send(summary);
+                }
                 LobbyAction::RemovePlayer { id } => self.rem_player(id),
+                LobbyAction::DisconnectPlayer { id } => self.disconnect_player(id),
+                LobbyAction::ReconnectPlayer { respond_to, id } => {
+                    let _ = respond_to.send(self.reconnect_player(id));
+                }
+                LobbyAction::SetPlayerLatency { id, latency } => {
+                    self.set_player_latency(id, latency)
+                }
                 LobbyAction::SetPlayerOptions {
                     respond_to,
                     id,
@@ -131,14 +244,77 @@ impl LobbyActor {
                 } => {
                     let _ = respond_to.send(self.set_game_options(id, options));
                 }
+                LobbyAction::KickPlayer {
+                    respond_to,
+                    id,
+                    target,
+                } => {
+                    let result = match id {
+                        Some(id) => self.host_kick_player(id, target),
+                        None => self.kick_player(target, ProtocolError::Kicked),
+                    };
+                    let _ = respond_to.send(result);
+                }
+                LobbyAction::BanPlayer {
+                    respond_to,
+                    id,
+                    target,
+                    ban_ip,
+                } => {
+                    let _ = respond_to.send(self.ban_player(id, target, ban_ip));
+                }
+                LobbyAction::TransferHost {
+                    respond_to,
+                    id,
+                    target,
+                } => {
+                    let _ = respond_to.send(self.transfer_host(id, target));
+                }
+                LobbyAction::Announce { message } => self.announce(message),
+                LobbyAction::Close => {
+                    self.close();
+                    break;
+                }
             }
         }
     }
 
-    fn send_lobby(&mut self) {
-        let _ = self.sender.send(Message::GameLobbyInfo {
-            lobby: self.shared.clone(),
-        });
+    /// Applies `delta` to the lobby and broadcasts it to all subscribers.
+    ///
+    /// Returns `false` if there was nobody to broadcast to.
+    fn update(&mut self, delta: LobbyDelta) -> bool {
+        self.shared.apply(&delta);
+        let msg = ServerMessage::LobbyDelta {
+            delta,
+            sequence: self.shared.sequence,
+            checksum: self.shared.game_state.checksum(),
+        };
+        self.send(msg)
+    }
+
+    /// Broadcasts `msg` to all subscribers, returning `false` if there was nobody to send it to.
+    fn send(&self, msg: ServerMessage) -> bool {
+        self.sender.send(LobbyEvent::Message(msg)).is_ok()
+    }
+
+    fn update_action(&mut self, player_id: PlayerId, action: LobbyMessage) -> bool {
+        self.update(LobbyDelta::Action { player_id, action })
+    }
+
+    /// Hash any new passwords in `options`, so that only [`Password::Protected`] is ever shared.
+    ///
+    /// [`Password::Protected`]: clash_lib::lobby::Password::Protected
+    fn store_passwords(&mut self, mut options: LobbyOptions) -> LobbyOptions {
+        PasswordHash::store(&mut self.password, &mut options.password);
+        PasswordHash::store(&mut self.spectate_password, &mut options.spectate_password);
+        options
+    }
+
+    fn subscribe(&self) -> LobbySubscription {
+        LobbySubscription {
+            snapshot: self.shared.clone(),
+            events: self.sender.subscribe(),
+        }
     }
 }
 
@@ -152,8 +328,8 @@ impl LobbyActor {
             return Err(LobbyError::NeedsHost);
         }
 
-        self.shared.reset();
-        self.send_lobby();
+        self.update_action(player_id, LobbyMessage::ResetLobby);
+        self.last_collection.clear();
         tracing::info!("Reset lobby");
         Ok(())
     }
@@ -170,45 +346,53 @@ impl LobbyActor {
             return Ok(());
         }
 
-        self.shared.reset();
-        self.shared.game_phase = GamePhase::Playing;
-        self.send_lobby();
-        if self
-            .sender
-            .send(Message::Lobby(LobbyMessage::GameBegin))
-            .is_err()
-        {
+        if !self.update_action(player_id, LobbyMessage::GameBegin) {
             tracing::warn!("Lobby started with no players in lobby.")
         }
+        self.last_collection.clear();
 
         tracing::info!("Started lobby");
         Ok(())
     }
 
+    /// Lets the host finish the current game early. Standings are decided by the scores at the
+    /// time the game ends.
     #[instrument(skip(self))]
-    fn stop_game(&mut self) {
-        self.shared.game_phase = GamePhase::Setup;
-        let _ = self.sender.send(Message::GameLobbyInfo {
-            lobby: self.shared.clone(),
-        });
-        if self
-            .sender
-            .send(Message::Lobby(LobbyMessage::GameEnd))
-            .is_err()
-        {
+    fn end_game(&mut self, player_id: PlayerId) -> LobbyResult<()> {
+        if self.shared.host_id != Some(player_id) {
+            return Err(LobbyError::NeedsHost);
+        }
+        if self.shared.game_phase != GamePhase::Playing {
+            return Err(LobbyError::InvalidAction(player_id));
+        }
+
+        self.stop_game(player_id);
+        tracing::info!("Host ended game");
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    fn stop_game(&mut self, player_id: PlayerId) {
+        // Standings go out first so they're already known once the game is seen as finished
+        let standings = standings::rank(&self.shared, &self.last_collection);
+        self.update(LobbyDelta::Standings { standings });
+        if !self.update_action(player_id, LobbyMessage::GameEnd) {
             tracing::warn!("Lobby finished with no players in lobby.")
         }
     }
 
     /// Adds a new player to this lobby. If there is currently no host, they will become it.
-    /// A `[broadcast::Receiver]` is returned that will be sent all future events that happen
+    /// A [`LobbySubscription`] is returned that will be sent all future events that happen
     /// to this lobby.
     ///
     /// # Errors
     ///
-    /// This function will return an error if the lobby is already full
+    /// This function will return an error if the lobby is already full or the player was banned
     #[instrument(skip_all)]
-    fn add_player(&mut self, player_id: PlayerId) -> LobbyResult<broadcast::Receiver<Message>> {
+    fn add_player(&mut self, player_id: PlayerId) -> LobbyResult<LobbySubscription> {
+        if self.banned_players.contains(&player_id) {
+            return Err(LobbyError::Banned);
+        }
         if self.shared.players.len() >= MAX_PLAYERS {
             return Err(LobbyError::LobbyFull);
         }
@@ -216,9 +400,10 @@ impl LobbyActor {
         // TODO: Unhardcode player color
         let mut player = NetworkedPlayer::new(PlayerOptions::default(), self.next_menu_order);
         player.options.color = clash_lib::player::COLORS[self.shared.players.len()];
-        self.next_menu_order += 1;
+        self.next_menu_order = self.next_menu_order.wrapping_add(1);
+        self.join_order.insert(player_id, self.next_join);
+        self.next_join += 1;
 
-        self.shared.players.insert(player_id, player);
         // TODO: When the last player in a lobby leaves, it is closed, therefore this should just be
         //  done once when the lobby is first created. (This will also allow us to get rid of the Option
         //  for the lobby's host_id)
@@ -227,12 +412,10 @@ impl LobbyActor {
         }
 
         tracing::info!("Player joined lobby");
-        // Subscribe early so that this player will receive the lobby update that adds them
-        let recv = self.sender.subscribe();
-
-        self.send_lobby();
+        self.update(LobbyDelta::PlayerJoined { player_id, player });
 
-        Ok(recv)
+        // Subscribe late, the new player will already be present in their snapshot
+        Ok(self.subscribe())
     }
 
     /// Adds a spectator to a lobby.
@@ -242,30 +425,175 @@ impl LobbyActor {
     /// In the future lobbies may need to become aware of spectators. (Allow/Deny spectating, show
     /// spectator counts/names, etc.)
     #[instrument(skip(self))]
-    fn add_spectator(&mut self) -> broadcast::Receiver<Message> {
-        let recv = self.sender.subscribe();
+    fn add_spectator(&mut self) -> LobbySubscription {
         tracing::info!("Player is now spectating");
-        self.send_lobby();
-        recv
+        self.subscribe()
     }
 
-    /// Removes a player from the lobby. If the host is removed, a new host is assigned randomly.
+    /// Checks that `password` allows joining this lobby, or spectating it when `spectate` is set.
+    ///
+    /// This must be done before [`add_player`](Self::add_player) or
+    /// [`add_spectator`](Self::add_spectator) for anyone that isn't the host or resuming a session.
+    #[instrument(skip(self, password))]
+    fn authorize(
+        &self,
+        password: Option<&str>,
+        spectate: bool,
+        addr: Option<IpAddr>,
+    ) -> Result<(), ProtocolError> {
+        if addr.is_some_and(|addr| self.banned_addresses.contains(&addr)) {
+            tracing::info!("Rejected banned address");
+            return Err(LobbyError::Banned.into());
+        }
+        if spectate && !self.shared.options.allow_spectators {
+            return Err(ProtocolError::SpectatingDisabled);
+        }
+        let hash = match spectate {
+            true => &self.spectate_password,
+            false => &self.password,
+        };
+        match hash {
+            Some(hash) if !password.is_some_and(|p| hash.verify(p)) => {
+                tracing::info!("Incorrect password");
+                Err(ProtocolError::IncorrectPassword)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Removes a player from the lobby. If the host is removed, the connected player who joined
+    /// the lobby earliest becomes the new host.
     #[instrument(skip(self))]
     fn rem_player(&mut self, player_id: PlayerId) {
-        if self.shared.players.remove(&player_id).is_none() {
+        if !self.shared.players.contains_key(&player_id) {
             tracing::warn!("Attempted to remove player from lobby who isn't in it");
             return;
         }
         tracing::info!("Player left lobby");
+        self.addresses.remove(&player_id);
+        self.join_order.remove(&player_id);
+        self.update(LobbyDelta::PlayerLeft { player_id });
+
         if self.shared.host_id == Some(player_id) {
-            // Pass host to first remaining player in list (effectively random with a HashMap)
-            // NOTE: We could consider passing host based on join order
-            self.shared.host_id = self.shared.players.iter().next().map(|(&id, _)| id);
-            tracing::info!("Player {:?} is now the host", self.shared.host_id);
+            // Pass host to whoever has been in the lobby the longest, preferring players that are
+            // still connected.
+            let host_id = self
+                .shared
+                .players
+                .iter()
+                .min_by_key(|&(id, p)| (!p.connected, self.join_order.get(id)))
+                .map(|(&id, _)| id);
+            tracing::info!("Player {host_id:?} is now the host");
+            self.update(LobbyDelta::HostChanged { host_id });
+        }
+    }
+
+    /// Marks a player as having lost connection. They keep their place in the lobby until they
+    /// reconnect or are removed.
+    #[instrument(skip(self))]
+    fn disconnect_player(&mut self, player_id: PlayerId) {
+        if !self.shared.players.contains_key(&player_id) {
+            tracing::warn!("Attempted to disconnect player from lobby who isn't in it");
+            return;
+        }
+        tracing::info!("Player lost connection");
+        self.update(LobbyDelta::PlayerConnection {
+            player_id,
+            connected: false,
+        });
+    }
+
+    /// Removes a player from the lobby and disconnects them with `error`.
+    #[instrument(skip(self))]
+    fn kick_player(&mut self, player_id: PlayerId, error: ProtocolError) -> LobbyResult<()> {
+        if !self.shared.players.contains_key(&player_id) {
+            return Err(LobbyError::PlayerInvalid(player_id));
+        }
+        self.rem_player(player_id);
+        let _ = self.sender.send(LobbyEvent::Disconnect {
+            player_id: Some(player_id),
+            error,
+        });
+        tracing::info!("Kicked player");
+        Ok(())
+    }
+
+    /// Checks that `player_id` is the host and `target` is another player in the lobby.
+    fn check_host_target(&self, player_id: PlayerId, target: PlayerId) -> LobbyResult<()> {
+        if self.shared.host_id != Some(player_id) {
+            return Err(LobbyError::NeedsHost);
+        }
+        if target == player_id || !self.shared.players.contains_key(&target) {
+            return Err(LobbyError::InvalidAction(player_id));
+        }
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    fn host_kick_player(&mut self, player_id: PlayerId, target: PlayerId) -> LobbyResult<()> {
+        self.check_host_target(player_id, target)?;
+        self.kick_player(target, ProtocolError::Kicked)
+    }
+
+    /// Kicks a player and keeps them from joining again for as long as the lobby is open. When
+    /// `ban_ip` is set, nobody else can join or spectate from the same IP address either.
+    ///
+    /// # Errors
+    ///
+    /// Nothing is done if `ban_ip` is set but the player's IP address isn't known.
+    #[instrument(skip(self))]
+    fn ban_player(
+        &mut self,
+        player_id: PlayerId,
+        target: PlayerId,
+        ban_ip: bool,
+    ) -> LobbyResult<()> {
+        self.check_host_target(player_id, target)?;
+        if ban_ip {
+            let addr = *self
+                .addresses
+                .get(&target)
+                .ok_or(LobbyError::AddressUnknown)?;
+            self.banned_addresses.insert(addr);
         }
+        self.banned_players.insert(target);
+        tracing::info!("Banned player");
+        self.kick_player(target, LobbyError::Banned.into())
+    }
 
-        // Update remaining clients of the change
-        self.send_lobby();
+    #[instrument(skip(self))]
+    fn transfer_host(&mut self, player_id: PlayerId, target: PlayerId) -> LobbyResult<()> {
+        self.check_host_target(player_id, target)?;
+        self.update_action(player_id, LobbyMessage::TransferHost { player_id: target });
+        tracing::info!("Transferred host");
+        Ok(())
+    }
+
+    /// Marks a previously disconnected player as connected again, returning a new
+    /// [`LobbySubscription`] for their new connection.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the player is no longer in the lobby
+    #[instrument(skip(self))]
+    fn reconnect_player(&mut self, player_id: PlayerId) -> LobbyResult<LobbySubscription> {
+        if !self.shared.players.contains_key(&player_id) {
+            return Err(LobbyError::PlayerInvalid(player_id));
+        }
+        tracing::info!("Player reconnected");
+        self.update(LobbyDelta::PlayerConnection {
+            player_id,
+            connected: true,
+        });
+        Ok(self.subscribe())
+    }
+
+    fn set_player_latency(&mut self, player_id: PlayerId, latency: Duration) {
+        // Latency is measured by the server itself, so this can only fail if the player was
+        // removed while the measurement was in flight.
+        if self.shared.players.contains_key(&player_id) {
+            self.update(LobbyDelta::PlayerLatency { player_id, latency });
+        }
     }
 
     #[instrument(skip(self, options))]
@@ -282,52 +610,42 @@ impl LobbyActor {
 
         // TODO: Unhardcode player color
         options.color = player.options.color;
-        player.options = options;
-        tracing::info!("Updated player options to {:#?}", player.options);
+        tracing::info!("Updated player options to {options:#?}");
 
-        self.send_lobby();
+        self.update_action(player_id, LobbyMessage::PlayerOptions { options });
         Ok(())
     }
 
     #[instrument(skip(self, can_start))]
     fn set_player_can_start(&mut self, player_id: PlayerId, can_start: bool) -> LobbyResult<()> {
-        let player = self
-            .shared
-            .players
-            .get_mut(&player_id)
-            .ok_or(LobbyError::PlayerInvalid(player_id))?;
+        if !self.shared.players.contains_key(&player_id) {
+            return Err(LobbyError::PlayerInvalid(player_id));
+        }
 
-        player.ready_to_start = can_start;
         tracing::info!(
             "Player is {}ready to start",
             if can_start { "" } else { "not " }
         );
-        self.send_lobby();
+        self.update_action(player_id, LobbyMessage::PlayerCanStart(can_start));
         Ok(())
     }
 
     #[instrument(skip(self, level))]
     fn set_player_level(&mut self, player_id: PlayerId, level: Option<Level>) -> LobbyResult<()> {
-        let player = self
-            .shared
-            .players
-            .get_mut(&player_id)
-            .ok_or(LobbyError::PlayerInvalid(player_id))?;
+        if !self.shared.players.contains_key(&player_id) {
+            return Err(LobbyError::PlayerInvalid(player_id));
+        }
 
-        player.current_level = level;
         tracing::info!("Player entered level {level:?}");
-
-        self.send_lobby();
+        self.update_action(player_id, LobbyMessage::GameCurrentLevel { level });
         Ok(())
     }
 
     #[instrument(skip(self, item))]
     fn player_collected_item(&mut self, player_id: PlayerId, item: Item) -> LobbyResult<()> {
-        let player = self
-            .shared
-            .players
-            .get_mut(&player_id)
-            .ok_or(LobbyError::PlayerInvalid(player_id))?;
+        if !self.shared.players.contains_key(&player_id) {
+            return Err(LobbyError::PlayerInvalid(player_id));
+        }
 
         match item {
             Item::Spatula(spat) => {
@@ -335,7 +653,7 @@ impl LobbyActor {
 
                 // This can happen in rare situations where the player colllected an exhausted spatula
                 // before receiving the lobby update that exhausted it. We should just ignore this case
-                if state.collection_vec.len() == self.shared.options.tier_count.into() {
+                if state.collection_vec.len() == usize::from(self.shared.options.tier_count) {
                     tracing::info!("Player tried to collect exhausted spatula {spat:?}.",);
                     return Ok(());
                 }
@@ -344,65 +662,77 @@ impl LobbyActor {
                     return Err(LobbyError::InvalidAction(player_id));
                 }
 
-                state.collection_vec.push(player_id);
                 tracing::info!(
                     "Player collected {spat:?} with tier {:?}",
-                    state.collection_vec.len()
+                    state.collection_vec.len() + 1
                 );
+                self.update_action(player_id, LobbyMessage::GameItemCollected { item });
+                // Otherwise whoever ends the game would always lose an earliest finish tie-break
+                if spat != Spatula::KahRahTae && spat != Spatula::TheSmallShallRuleOrNot {
+                    self.last_collection.insert(player_id, self.shared.sequence);
+                }
 
                 if spat == Spatula::TheSmallShallRuleOrNot {
-                    self.shared.game_phase = GamePhase::Finished;
-                    if self
-                        .sender
-                        .send(Message::Lobby(LobbyMessage::GameEnd))
-                        .is_err()
-                    {
-                        tracing::warn!("Game finished with no players in lobby.")
-                    }
-                } else if spat != Spatula::KahRahTae {
-                    player.score += self
-                        .shared
-                        .options
-                        .spat_scores
-                        .get(state.collection_vec.len() - 1)
-                        .unwrap_or(&0);
+                    self.stop_game(player_id);
                 }
-
-                self.send_lobby();
             }
         }
         Ok(())
     }
 
+    #[instrument(skip(self))]
+    fn announce(&mut self, message: String) {
+        tracing::info!("Sent announcement");
+        self.send(ServerMessage::Announcement { message });
+    }
+
+    /// Disconnects everyone in the lobby. The lobby stops once this returns, even if there are
+    /// still handles to it.
+    #[instrument(skip(self))]
+    fn close(&mut self) {
+        tracing::info!("Force closing lobby");
+        let _ = self.sender.send(LobbyEvent::Disconnect {
+            player_id: None,
+            error: ProtocolError::LobbyClosed,
+        });
+    }
+
     #[instrument(skip(self, options))]
     fn set_game_options(&mut self, player_id: PlayerId, options: LobbyOptions) -> LobbyResult<()> {
         if self.shared.host_id != Some(player_id) {
             return Err(LobbyError::NeedsHost);
         }
-        self.shared.options = options;
-        tracing::info!("Set lobby options to {:#?}", self.shared.options);
-
-        self.send_lobby();
+        if options.name.chars().count() > MAX_LOBBY_NAME_LEN {
+            return Err(LobbyError::InvalidAction(player_id));
+        }
+        let options = self.store_passwords(options);
+        tracing::info!("Set lobby options to {options:#?}");
+        self.update_action(player_id, LobbyMessage::GameOptions { options });
         Ok(())
     }
 }
 
-// TODO: Test that correct messages are broadcast once protocol is updated to send incremental events
 #[cfg(test)]
 mod test {
+    use std::net::{IpAddr, Ipv4Addr};
     use std::time::Duration;
 
     use bfbb::{Level, Spatula};
-    use clash_lib::{lobby::GamePhase, net::Item, player::PlayerOptions, LobbyId};
+    use clash_lib::{
+        lobby::{DeltaOutcome, GamePhase, LobbyOptions, Standing, TieBreaker},
+        net::{Item, LobbyDelta, LobbyMessage, ProtocolError, ServerMessage},
+        player::PlayerOptions,
+        LobbyId,
+    };
     use tokio::{sync::mpsc, time::timeout};
 
-    use crate::lobby::{lobby_handle::LobbyHandleProvider, LobbyError};
+    use crate::lobby::{lobby_handle::LobbyHandleProvider, LobbyError, LobbyEvent};
 
     use super::LobbyActor;
 
     fn setup() -> LobbyActor {
         let (_, rx) = mpsc::channel(2);
-        LobbyActor::new(rx, LobbyId(0).into())
+        LobbyActor::new(rx, LobbyId(0).into(), LobbyOptions::default())
     }
 
     #[test]
@@ -440,6 +770,90 @@ mod test {
         assert_eq!(lobby.shared.game_phase, GamePhase::Playing);
     }
 
+    #[test]
+    fn end_game() {
+        let mut lobby = setup();
+        lobby.add_player(0.into()).unwrap();
+        lobby.add_player(1.into()).unwrap();
+
+        // There's no game to end yet
+        assert_eq!(
+            lobby.end_game(0.into()),
+            Err(LobbyError::InvalidAction(0.into()))
+        );
+
+        lobby.set_player_can_start(0.into(), true).unwrap();
+        lobby.set_player_can_start(1.into(), true).unwrap();
+        lobby.start_game(0.into()).unwrap();
+        lobby
+            .player_collected_item(1.into(), Item::Spatula(Spatula::SpongebobsCloset))
+            .unwrap();
+
+        // Only the host can end a game
+        assert_eq!(lobby.end_game(1.into()), Err(LobbyError::NeedsHost));
+        assert_eq!(lobby.shared.game_phase, GamePhase::Playing);
+
+        let mut sub = lobby.subscribe();
+        assert!(lobby.end_game(0.into()).is_ok());
+        assert_eq!(lobby.shared.game_phase, GamePhase::Finished);
+        // Final standings are sent just before the game ends, going by the scores so far
+        assert!(matches!(
+            sub.events.try_recv(),
+            Ok(LobbyEvent::Message(ServerMessage::LobbyDelta {
+                delta: LobbyDelta::Standings { standings },
+                ..
+            })) if standings.first().map(|s| s.player_id) == Some(1.into())
+        ));
+        assert!(matches!(
+            sub.events.try_recv(),
+            Ok(LobbyEvent::Message(ServerMessage::LobbyDelta {
+                delta: LobbyDelta::Action {
+                    action: LobbyMessage::GameEnd,
+                    ..
+                },
+                ..
+            }))
+        ));
+        assert_eq!(lobby.shared.standings.len(), 2);
+    }
+
+    #[test]
+    fn earliest_finish_standings() {
+        let mut lobby = setup();
+        lobby.shared.options.tie_breaker = TieBreaker::EarliestFinish;
+        lobby.add_player(0.into()).unwrap();
+        lobby.add_player(1.into()).unwrap();
+        lobby.set_player_can_start(0.into(), true).unwrap();
+        lobby.set_player_can_start(1.into(), true).unwrap();
+        lobby.start_game(0.into()).unwrap();
+
+        // Both players end up with the same score, and player 0 collected their last spatula first.
+        // Ending the game with The Small Shall Rule isn't worth points so it doesn't count.
+        for (player, spat) in [
+            (0, Spatula::SpongebobsCloset),
+            (1, Spatula::OnTopOfThePineapple),
+            (0, Spatula::TheSmallShallRuleOrNot),
+        ] {
+            lobby
+                .player_collected_item(player.into(), Item::Spatula(spat))
+                .unwrap();
+        }
+        assert_eq!(lobby.shared.game_phase, GamePhase::Finished);
+        assert_eq!(
+            lobby.shared.standings,
+            [
+                Standing {
+                    player_id: 0.into(),
+                    place: 1
+                },
+                Standing {
+                    player_id: 1.into(),
+                    place: 2
+                }
+            ]
+        );
+    }
+
     #[test]
     fn add_player() {
         let mut lobby = setup();
@@ -457,6 +871,97 @@ mod test {
         ));
     }
 
+    #[test]
+    fn add_player_broadcasts() {
+        let mut lobby = setup();
+        let mut sub = lobby.add_player(0.into()).unwrap();
+        assert!(sub.snapshot.players.contains_key(&0));
+
+        // Existing players are told about the new player, who is already in their own snapshot
+        let new_sub = lobby.add_player(1.into()).unwrap();
+        assert!(new_sub.snapshot.players.contains_key(&1));
+        assert!(matches!(
+            sub.events.try_recv(),
+            Ok(LobbyEvent::Message(ServerMessage::LobbyDelta {
+                delta: LobbyDelta::PlayerJoined {
+                    player_id: clash_lib::PlayerId(1),
+                    ..
+                },
+                ..
+            }))
+        ));
+        assert!(sub.events.try_recv().is_err());
+    }
+
+    #[test]
+    fn actions_broadcast_deltas() {
+        let mut lobby = setup();
+        let mut sub = lobby.add_player(0.into()).unwrap();
+
+        lobby
+            .set_player_level(0.into(), Some(Level::JellyfishRock))
+            .unwrap();
+        let Ok(LobbyEvent::Message(ServerMessage::LobbyDelta {
+            delta,
+            sequence,
+            checksum,
+        })) = sub.events.try_recv()
+        else {
+            panic!("Changing levels should broadcast a delta");
+        };
+        assert!(matches!(
+            delta,
+            LobbyDelta::Action {
+                action: LobbyMessage::GameCurrentLevel {
+                    level: Some(Level::JellyfishRock)
+                },
+                ..
+            }
+        ));
+
+        // Applying the broadcast deltas to the initial snapshot results in the same lobby
+        assert_eq!(
+            sub.snapshot.apply_stamped(&delta, sequence, checksum),
+            DeltaOutcome::Applied
+        );
+        lobby
+            .player_collected_item(0.into(), Spatula::TheSmallShallRuleOrNot.into())
+            .unwrap();
+        while let Ok(LobbyEvent::Message(ServerMessage::LobbyDelta {
+            delta,
+            sequence,
+            checksum,
+        })) = sub.events.try_recv()
+        {
+            assert_eq!(
+                sub.snapshot.apply_stamped(&delta, sequence, checksum),
+                DeltaOutcome::Applied
+            );
+        }
+        assert_eq!(sub.snapshot.sequence, lobby.shared.sequence);
+        assert_eq!(sub.snapshot.game_phase, GamePhase::Finished);
+        assert_eq!(
+            sub.snapshot.players.get(&0).unwrap().current_level,
+            Some(Level::JellyfishRock)
+        );
+        assert_eq!(
+            sub.snapshot.game_state.spatulas.len(),
+            lobby.shared.game_state.spatulas.len()
+        );
+    }
+
+    #[test]
+    fn invalid_actions_are_not_broadcast() {
+        let mut lobby = setup();
+        let mut sub = lobby.add_player(0.into()).unwrap();
+        lobby.add_player(1.into()).unwrap();
+        sub.events.try_recv().unwrap();
+
+        assert!(lobby.reset_lobby(1.into()).is_err());
+        assert!(lobby.set_player_level(1337.into(), None).is_err());
+        assert!(sub.events.try_recv().is_err());
+    }
+
     #[test]
     fn add_spectator() {
         let mut lobby = setup();
@@ -482,6 +987,164 @@ mod test {
         assert!(lobby.shared.players.is_empty());
     }
 
+    #[test]
+    fn host_migration() {
+        let mut lobby = setup();
+        // Join order differs from id order
+        for id in [0, 3, 2, 1] {
+            lobby.add_player(id.into()).unwrap();
+        }
+        let mut sub = lobby.subscribe();
+
+        // Host passes in join order, skipping players who have lost connection
+        lobby.disconnect_player(3.into());
+        lobby.rem_player(0.into());
+        assert_eq!(lobby.shared.host_id, Some(2.into()));
+        let mut host_changes = Vec::new();
+        while let Ok(LobbyEvent::Message(ServerMessage::LobbyDelta {
+            delta,
+            sequence,
+            checksum,
+        })) = sub.events.try_recv()
+        {
+            if let LobbyDelta::HostChanged { host_id } = delta {
+                host_changes.push(host_id);
+            }
+            sub.snapshot.apply_stamped(&delta, sequence, checksum);
+        }
+        assert_eq!(host_changes, [Some(2.into())]);
+        assert_eq!(sub.snapshot.host_id, Some(2.into()));
+
+        // Offline players are only chosen when nobody else is left
+        lobby.rem_player(2.into());
+        assert_eq!(lobby.shared.host_id, Some(1.into()));
+        lobby.rem_player(1.into());
+        assert_eq!(lobby.shared.host_id, Some(3.into()));
+
+        // Join order keeps counting after the menu order wraps around
+        lobby.next_menu_order = u8::MAX;
+        lobby.add_player(4.into()).unwrap();
+        lobby.add_player(5.into()).unwrap();
+        lobby.rem_player(3.into());
+        assert_eq!(lobby.shared.host_id, Some(4.into()));
+    }
+
+    #[test]
+    fn host_moderation() {
+        let mut lobby = setup();
+        lobby.add_player(0.into()).unwrap();
+        lobby.add_player(1.into()).unwrap();
+        lobby.add_player(2.into()).unwrap();
+        let mut sub = lobby.subscribe();
+
+        assert_eq!(
+            lobby.host_kick_player(1.into(), 2.into()),
+            Err(LobbyError::NeedsHost)
+        );
+        assert_eq!(
+            lobby.host_kick_player(0.into(), 0.into()),
+            Err(LobbyError::InvalidAction(0.into()))
+        );
+        assert_eq!(
+            lobby.transfer_host(0.into(), 1337.into()),
+            Err(LobbyError::InvalidAction(0.into()))
+        );
+
+        lobby.host_kick_player(0.into(), 2.into()).unwrap();
+        assert!(!lobby.shared.players.contains_key(&2));
+        // The kicked player is told to leave after everyone sees them removed
+        while let Ok(event) = sub.events.try_recv() {
+            if let LobbyEvent::Disconnect { player_id, error } = event {
+                assert_eq!(player_id, Some(2.into()));
+                assert!(matches!(error, ProtocolError::Kicked));
+            }
+        }
+
+        lobby.transfer_host(0.into(), 1.into()).unwrap();
+        assert_eq!(lobby.shared.host_id, Some(1.into()));
+        assert!(matches!(
+            sub.events.try_recv(),
+            Ok(LobbyEvent::Message(ServerMessage::LobbyDelta {
+                delta: LobbyDelta::Action {
+                    action: LobbyMessage::TransferHost { .. },
+                    ..
+                },
+                ..
+            }))
+        ));
+        assert_eq!(
+            lobby.transfer_host(0.into(), 1.into()),
+            Err(LobbyError::NeedsHost)
+        );
+    }
+
+    #[test]
+    fn ban_player() {
+        let mut lobby = setup();
+        let addr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let other_addr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+        lobby.add_player(0.into()).unwrap();
+        lobby.add_player(1.into()).unwrap();
+        lobby.add_player(2.into()).unwrap();
+        lobby.addresses.insert(1.into(), addr);
+
+        // An IP ban can't be applied without knowing the player's address
+        assert_eq!(
+            lobby.ban_player(0.into(), 2.into(), true),
+            Err(LobbyError::AddressUnknown)
+        );
+        assert!(lobby.shared.players.contains_key(&2));
+
+        lobby.ban_player(0.into(), 2.into(), false).unwrap();
+        assert!(!lobby.shared.players.contains_key(&2));
+        assert_eq!(lobby.add_player(2.into()).err(), Some(LobbyError::Banned));
+        assert!(lobby.authorize(None, false, Some(addr)).is_ok());
+
+        // Banning by IP also keeps out anyone else at that address, players and spectators alike
+        lobby.ban_player(0.into(), 1.into(), true).unwrap();
+        for spectate in [false, true] {
+            assert!(matches!(
+                lobby.authorize(None, spectate, Some(addr)),
+                Err(ProtocolError::Lobby(LobbyError::Banned))
+            ));
+        }
+        assert!(lobby.authorize(None, false, Some(other_addr)).is_ok());
+        assert!(lobby.authorize(None, false, None).is_ok());
+    }
+
+    #[test]
+    fn disconnect_and_reconnect_player() {
+        let mut lobby = setup();
+        lobby.add_player(0.into()).unwrap();
+        lobby.add_player(1.into()).unwrap();
+        lobby
+            .player_collected_item(1.into(), Spatula::SpongebobsCloset.into())
+            .unwrap();
+        let score = lobby.shared.players.get(&1).unwrap().score;
+
+        // Disconnected players keep their place in the lobby
+        lobby.disconnect_player(1.into());
+        let player = lobby.shared.players.get(&1).unwrap();
+        assert!(!player.connected);
+        assert_eq!(player.score, score);
+
+        // Their new subscription starts from a snapshot that has them online again
+        let sub = lobby.reconnect_player(1.into()).unwrap();
+        let player = sub.snapshot.players.get(&1).unwrap();
+        assert!(player.connected);
+        assert_eq!(player.score, score);
+        assert!(sub.snapshot.game_state.spatulas[&Spatula::SpongebobsCloset]
+            .collection_vec
+            .contains(&1.into()));
+
+        // Can't reconnect somebody who was removed
+        lobby.rem_player(1.into());
+        assert!(matches!(
+            lobby.reconnect_player(1.into()),
+            Err(LobbyError::PlayerInvalid(_))
+        ));
+    }
+
     #[test]
     fn set_player_options() {
         let mut lobby = setup();
@@ -709,7 +1372,7 @@ mod test {
     async fn lobby_dies() {
         let get_lobby = || {
             let (tx, rx) = mpsc::channel(2);
-            let mut actor = LobbyActor::new(rx, LobbyId(0).into());
+            let mut actor = LobbyActor::new(rx, LobbyId(0).into(), LobbyOptions::default());
             let handle = LobbyHandleProvider {
                 sender: tx.downgrade(),
             }
@@ -1,14 +1,16 @@
 use bfbb::Level;
 use clash_lib::{
-    lobby::LobbyOptions,
-    net::{Item, Message},
+    lobby::{LobbyOptions, LobbySummary, NetworkedLobby},
+    net::{Item, ProtocolError},
     player::PlayerOptions,
     PlayerId,
 };
-use tokio::sync::{broadcast, mpsc, oneshot};
+use std::net::IpAddr;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
 
-use super::LobbyError;
 use super::{lobby_actor::LobbyAction, LobbyResult};
+use super::{LobbyError, LobbySubscription};
 
 #[derive(Clone, Debug)]
 pub struct LobbyHandleProvider {
@@ -23,7 +25,7 @@ impl LobbyHandleProvider {
         })
     }
 
-    pub async fn spectate(&self) -> LobbyResult<broadcast::Receiver<Message>> {
+    pub async fn spectate(&self) -> LobbyResult<LobbySubscription> {
         let (tx, rx) = oneshot::channel();
         let sender = self.sender.upgrade().ok_or(LobbyError::HandleInvalid)?;
         let _ = sender
@@ -31,6 +33,83 @@ impl LobbyHandleProvider {
             .await;
         rx.await.map_err(|_| LobbyError::HandleInvalid)
     }
+
+    /// Check `password` against the lobby's password for players, or for spectators when
+    /// `spectate` is set, and that `addr` hasn't been banned.
+    pub async fn authorize(
+        &self,
+        password: Option<String>,
+        spectate: bool,
+        addr: Option<IpAddr>,
+    ) -> Result<(), ProtocolError> {
+        let (tx, rx) = oneshot::channel();
+        let sender = self.sender.upgrade().ok_or(LobbyError::HandleInvalid)?;
+        let _ = sender
+            .send(LobbyAction::Authorize {
+                respond_to: tx,
+                password,
+                spectate,
+                addr,
+            })
+            .await;
+        rx.await.map_err(|_| LobbyError::HandleInvalid)?
+    }
+
+    /// Remove `player_id` from the lobby and disconnect them.
+    pub async fn kick(&self, player_id: PlayerId) -> LobbyResult<()> {
+        let (tx, rx) = oneshot::channel();
+        let sender = self.sender.upgrade().ok_or(LobbyError::HandleInvalid)?;
+        let _ = sender
+            .send(LobbyAction::KickPlayer {
+                respond_to: tx,
+                id: None,
+                target: player_id,
+            })
+            .await;
+        rx.await.map_err(|_| LobbyError::HandleInvalid)?
+    }
+
+    /// Show `message` to everyone in the lobby.
+    pub async fn announce(&self, message: String) -> LobbyResult<()> {
+        let sender = self.sender.upgrade().ok_or(LobbyError::HandleInvalid)?;
+        sender
+            .send(LobbyAction::Announce { message })
+            .await
+            .map_err(|_| LobbyError::HandleInvalid)
+    }
+
+    /// Disconnect everyone in the lobby and close it.
+    pub async fn close(&self) -> LobbyResult<()> {
+        let sender = self.sender.upgrade().ok_or(LobbyError::HandleInvalid)?;
+        sender
+            .send(LobbyAction::Close)
+            .await
+            .map_err(|_| LobbyError::HandleInvalid)
+    }
+
+    /// Get the current state of the lobby.
+    pub async fn snapshot(&self) -> LobbyResult<NetworkedLobby> {
+        let sender = self.sender.upgrade().ok_or(LobbyError::HandleInvalid)?;
+        get_snapshot(&sender).await
+    }
+
+    /// Describe the lobby for the lobby browser, or `None` if it isn't public.
+    pub async fn summary(&self) -> LobbyResult<Option<LobbySummary>> {
+        let (tx, rx) = oneshot::channel();
+        let sender = self.sender.upgrade().ok_or(LobbyError::HandleInvalid)?;
+        let _ = sender
+            .send(LobbyAction::GetSummary { respond_to: tx })
+            .await;
+        rx.await.map_err(|_| LobbyError::HandleInvalid)
+    }
+}
+
+async fn get_snapshot(sender: &mpsc::Sender<LobbyAction>) -> LobbyResult<NetworkedLobby> {
+    let (tx, rx) = oneshot::channel();
+    let _ = sender
+        .send(LobbyAction::GetSnapshot { respond_to: tx })
+        .await;
+    rx.await.map_err(|_| LobbyError::HandleInvalid)
 }
 
 #[derive(Debug)]
@@ -40,6 +119,13 @@ pub struct LobbyHandle {
 }
 
 impl LobbyHandle {
+    /// Get a [`LobbyHandleProvider`] for the same lobby, which won't keep the lobby open.
+    pub fn provider(&self) -> LobbyHandleProvider {
+        LobbyHandleProvider {
+            sender: self.sender.downgrade(),
+        }
+    }
+
     async fn execute<T>(
         &self,
         msg: LobbyAction,
@@ -69,20 +155,63 @@ impl LobbyHandle {
         self.execute(msg, rx).await
     }
 
+    pub async fn end_game(&self) -> Result<(), LobbyError> {
+        let (tx, rx) = oneshot::channel();
+        let msg = LobbyAction::EndGame {
+            respond_to: tx,
+            id: self.player_id,
+        };
+        self.execute(msg, rx).await
+    }
+
     /// Adds a new player to this lobby. If there is currently no host, they will become it.
     ///
     /// TODO: Would be nice to not have to manually call this. Since it's async we can't
     /// currently do this in the object constructor without holding a reference to the LobbyHandleProvider
     /// across an await boundary.
-    pub async fn join_lobby(&self) -> Result<broadcast::Receiver<Message>, LobbyError> {
+    pub async fn join_lobby(&self, addr: Option<IpAddr>) -> Result<LobbySubscription, LobbyError> {
         let (tx, rx) = oneshot::channel();
         let msg = LobbyAction::AddPlayer {
             respond_to: tx,
             id: self.player_id,
+            addr,
         };
         self.execute(msg, rx).await
     }
 
+    /// Get the current state of the lobby.
+    pub async fn snapshot(&self) -> LobbyResult<NetworkedLobby> {
+        get_snapshot(&self.sender).await
+    }
+
+    /// Marks this player as having lost connection without removing them from the lobby.
+    pub async fn disconnect(&self) {
+        let _ = self
+            .sender
+            .send(LobbyAction::DisconnectPlayer { id: self.player_id })
+            .await;
+    }
+
+    /// Marks this player as connected again after a call to [`LobbyHandle::disconnect`].
+    pub async fn reconnect(&self) -> Result<LobbySubscription, LobbyError> {
+        let (tx, rx) = oneshot::channel();
+        let msg = LobbyAction::ReconnectPlayer {
+            respond_to: tx,
+            id: self.player_id,
+        };
+        self.execute(msg, rx).await
+    }
+
+    pub async fn set_player_latency(&self, latency: Duration) {
+        let _ = self
+            .sender
+            .send(LobbyAction::SetPlayerLatency {
+                id: self.player_id,
+                latency,
+            })
+            .await;
+    }
+
     pub async fn set_player_options(&self, options: PlayerOptions) -> Result<(), LobbyError> {
         let (tx, rx) = oneshot::channel();
         let msg = LobbyAction::SetPlayerOptions {
@@ -132,6 +261,37 @@ impl LobbyHandle {
         };
         self.execute(msg, rx).await
     }
+
+    pub async fn kick_player(&self, target: PlayerId) -> Result<(), LobbyError> {
+        let (tx, rx) = oneshot::channel();
+        let msg = LobbyAction::KickPlayer {
+            respond_to: tx,
+            id: Some(self.player_id),
+            target,
+        };
+        self.execute(msg, rx).await
+    }
+
+    pub async fn ban_player(&self, target: PlayerId, ban_ip: bool) -> Result<(), LobbyError> {
+        let (tx, rx) = oneshot::channel();
+        let msg = LobbyAction::BanPlayer {
+            respond_to: tx,
+            id: self.player_id,
+            target,
+            ban_ip,
+        };
+        self.execute(msg, rx).await
+    }
+
+    pub async fn transfer_host(&self, target: PlayerId) -> Result<(), LobbyError> {
+        let (tx, rx) = oneshot::channel();
+        let msg = LobbyAction::TransferHost {
+            respond_to: tx,
+            id: self.player_id,
+            target,
+        };
+        self.execute(msg, rx).await
+    }
 }
 
 impl Drop for LobbyHandle {
@@ -207,6 +367,23 @@ mod test {
         actor.await.unwrap();
     }
 
+    #[tokio::test]
+    async fn end_game() {
+        let (mut rx, handle) = setup();
+        let actor = tokio::spawn(async move {
+            let m = rx.recv().await.unwrap();
+            assert!(matches!(
+                m,
+                LobbyAction::EndGame {
+                    respond_to: _,
+                    id: PlayerId(123),
+                }
+            ));
+        });
+        let _ = handle.end_game().await;
+        actor.await.unwrap();
+    }
+
     #[tokio::test]
     async fn add_player() {
         let (mut rx, handle) = setup();
@@ -216,11 +393,12 @@ mod test {
                 m,
                 LobbyAction::AddPlayer {
                     respond_to: _,
-                    id: PlayerId(123)
+                    id: PlayerId(123),
+                    addr: None,
                 }
             ));
         });
-        let _ = handle.join_lobby().await;
+        let _ = handle.join_lobby(None).await;
         actor.await.unwrap();
     }
 
@@ -235,6 +413,29 @@ mod test {
         actor.await.unwrap();
     }
 
+    #[tokio::test]
+    async fn disconnect_and_reconnect() {
+        let (mut rx, handle) = setup();
+        let actor = tokio::spawn(async move {
+            let m = rx.recv().await.unwrap();
+            assert!(matches!(
+                m,
+                LobbyAction::DisconnectPlayer { id: PlayerId(123) }
+            ));
+            let m = rx.recv().await.unwrap();
+            assert!(matches!(
+                m,
+                LobbyAction::ReconnectPlayer {
+                    respond_to: _,
+                    id: PlayerId(123),
+                }
+            ));
+        });
+        handle.disconnect().await;
+        let _ = handle.reconnect().await;
+        actor.await.unwrap();
+    }
+
     #[tokio::test]
     async fn set_player_options() {
         let (mut rx, handle) = setup();
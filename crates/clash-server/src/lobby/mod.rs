@@ -1,6 +1,9 @@
-use clash_lib::{net::ProtocolError, LobbyId, PlayerId};
-use thiserror::Error;
-use tokio::sync::mpsc;
+use clash_lib::{
+    lobby::{LobbyOptions, NetworkedLobby},
+    net::{ProtocolError, ServerMessage},
+    LobbyId, PlayerId,
+};
+use tokio::sync::{broadcast, mpsc};
 
 use crate::state::OwnedId;
 
@@ -11,36 +14,46 @@ use self::{
 
 mod lobby_actor;
 pub mod lobby_handle;
+mod password;
+mod standings;
 
-#[derive(Copy, Clone, Debug, Error, PartialEq, Eq)]
-pub enum LobbyError {
-    #[error("Attempted to add a player to a full lobby")]
-    LobbyFull,
-    #[error("Action attempted by Player {0:#} who is not in this lobby")]
-    PlayerInvalid(PlayerId),
-    #[error("Action attempted by Player {0:#} was invalid.")]
-    InvalidAction(PlayerId),
-    #[error("Non-host attempted a host-only action")]
-    NeedsHost,
-    #[error("The Lobby Handle is no longer connected to a lobby.")]
-    HandleInvalid,
-}
+pub use clash_lib::net::LobbyError;
+
+pub type LobbyResult<T> = Result<T, LobbyError>;
 
-impl From<LobbyError> for ProtocolError {
-    fn from(v: LobbyError) -> Self {
-        Self::Message(v.to_string())
-    }
+/// A subscription to the events of a lobby.
+///
+/// `events` will receive every change made to the lobby after `snapshot` was taken,
+/// so applying them in order to `snapshot` will keep a client in sync with the lobby.
+#[derive(Debug)]
+pub struct LobbySubscription {
+    pub snapshot: NetworkedLobby,
+    pub events: broadcast::Receiver<LobbyEvent>,
 }
 
-pub type LobbyResult<T> = Result<T, LobbyError>;
+/// Something that happened in a lobby, sent to everyone subscribed to it.
+// Nearly every event is a message, boxing them would only add an allocation per broadcast.
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone, Debug)]
+pub enum LobbyEvent {
+    /// A message to pass on to every client.
+    Message(ServerMessage),
+    /// The client of `player_id`, or every client when it's `None`, should be sent `error` and
+    /// disconnected.
+    Disconnect {
+        player_id: Option<PlayerId>,
+        error: ProtocolError,
+    },
+}
 
 pub fn start_new_lobby(
     id: OwnedId<LobbyId>,
     host_id: PlayerId,
+    options: LobbyOptions,
 ) -> (LobbyHandleProvider, LobbyHandle) {
     let (sender, receiver) = mpsc::channel(64);
     let weak_sender = sender.downgrade();
-    let actor = LobbyActor::new(receiver, id);
+    let actor = LobbyActor::new(receiver, id, options);
     let handle = LobbyHandle {
         sender,
         player_id: host_id,
@@ -0,0 +1,86 @@
+use clash_lib::lobby::Password;
+use rand::Rng;
+use sha2::{Digest, Sha256};
+
+/// A salted hash of a lobby password. The password itself is never kept.
+#[derive(Debug)]
+pub struct PasswordHash {
+    salt: [u8; 16],
+    digest: [u8; 32],
+}
+
+impl PasswordHash {
+    pub fn new(password: &str) -> Self {
+        let salt = rand::thread_rng().gen();
+        Self {
+            salt,
+            digest: digest(&salt, password),
+        }
+    }
+
+    pub fn verify(&self, password: &str) -> bool {
+        digest(&self.salt, password) == self.digest
+    }
+
+    /// Update `hash` from a [`Password`] sent by the host, replacing it with what should be shared
+    /// with everyone else.
+    ///
+    /// [`Password::Protected`] keeps the current password, and an empty [`Password::New`] removes it.
+    pub fn store(hash: &mut Option<Self>, password: &mut Password) {
+        match std::mem::take(password) {
+            Password::None => *hash = None,
+            Password::New(p) if p.is_empty() => *hash = None,
+            Password::New(p) => *hash = Some(Self::new(&p)),
+            Password::Protected => {}
+        }
+        if hash.is_some() {
+            *password = Password::Protected;
+        }
+    }
+}
+
+fn digest(salt: &[u8], password: &str) -> [u8; 32] {
+    Sha256::new()
+        .chain_update(salt)
+        .chain_update(password)
+        .finalize()
+        .into()
+}
+
+#[cfg(test)]
+mod test {
+    use clash_lib::lobby::Password;
+
+    use super::PasswordHash;
+
+    #[test]
+    fn verify() {
+        let hash = PasswordHash::new("hunter2");
+        assert!(hash.verify("hunter2"));
+        assert!(!hash.verify("hunter3"));
+        assert!(!hash.verify(""));
+    }
+
+    #[test]
+    fn store() {
+        let mut hash = None;
+        let mut password = Password::New("hunter2".to_owned());
+        PasswordHash::store(&mut hash, &mut password);
+        assert_eq!(password, Password::Protected);
+        assert!(hash.as_ref().unwrap().verify("hunter2"));
+
+        // The host sends back what they were given to keep the password
+        PasswordHash::store(&mut hash, &mut password);
+        assert_eq!(password, Password::Protected);
+        assert!(hash.as_ref().unwrap().verify("hunter2"));
+
+        let mut password = Password::Protected;
+        PasswordHash::store(&mut None, &mut password);
+        assert_eq!(password, Password::None);
+
+        let mut password = Password::New(String::new());
+        PasswordHash::store(&mut hash, &mut password);
+        assert_eq!(password, Password::None);
+        assert!(hash.is_none());
+    }
+}
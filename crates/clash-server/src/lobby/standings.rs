@@ -0,0 +1,161 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+
+use bfbb::Spatula;
+use clash_lib::lobby::{NetworkedLobby, Standing, TieBreaker};
+use clash_lib::PlayerId;
+
+/// Rank the players of `lobby` by score, breaking ties with the lobby's [`TieBreaker`].
+///
+/// `last_collection` holds the lobby sequence at which each player last collected a spatula worth
+/// points, which is what [`TieBreaker::EarliestFinish`] goes by.
+pub fn rank(lobby: &NetworkedLobby, last_collection: &HashMap<PlayerId, u64>) -> Vec<Standing> {
+    // Lower is better, so that it can be sorted on directly
+    let tie_break = |player_id: PlayerId| -> u64 {
+        match lobby.options.tie_breaker {
+            TieBreaker::None => 0,
+            TieBreaker::MostGold => u64::MAX - gold_count(lobby, player_id),
+            TieBreaker::EarliestFinish => {
+                last_collection.get(&player_id).copied().unwrap_or(u64::MAX)
+            }
+            TieBreaker::SmallShallRule => {
+                let winner = lobby
+                    .game_state
+                    .spatulas
+                    .get(&Spatula::TheSmallShallRuleOrNot)
+                    .and_then(|s| s.collection_vec.first());
+                u64::from(winner != Some(&player_id))
+            }
+        }
+    };
+
+    let mut ranked = lobby
+        .players
+        .iter()
+        .map(|(&id, p)| ((Reverse(p.score), tie_break(id)), p.menu_order, id))
+        .collect::<Vec<_>>();
+    // Players who are still tied are listed in join order
+    ranked.sort_by_key(|&(key, menu_order, _)| (key, menu_order));
+
+    let mut standings = Vec::<Standing>::with_capacity(ranked.len());
+    for (i, &(key, _, player_id)) in ranked.iter().enumerate() {
+        let place = match i.checked_sub(1) {
+            Some(prev) if ranked[prev].0 == key => standings[prev].place,
+            _ => i + 1,
+        };
+        standings.push(Standing { player_id, place });
+    }
+    standings
+}
+
+/// Number of spatulas that `player_id` collected before anyone else. Spatulas that aren't worth any
+/// points don't count.
+fn gold_count(lobby: &NetworkedLobby, player_id: PlayerId) -> u64 {
+    lobby
+        .game_state
+        .spatulas
+        .iter()
+        .filter(|(&spat, _)| spat != Spatula::KahRahTae && spat != Spatula::TheSmallShallRuleOrNot)
+        .filter(|(_, state)| state.collection_vec.first() == Some(&player_id))
+        .count() as u64
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use bfbb::Spatula;
+    use clash_lib::{
+        lobby::{NetworkedLobby, Standing, TieBreaker},
+        player::{NetworkedPlayer, PlayerOptions},
+        PlayerId,
+    };
+
+    use super::rank;
+
+    fn setup(scores: &[u32]) -> NetworkedLobby {
+        let mut lobby = NetworkedLobby::new(0);
+        for (i, &score) in scores.iter().enumerate() {
+            let mut player = NetworkedPlayer::new(PlayerOptions::default(), i as u8);
+            player.score = score;
+            lobby.players.insert((i as u32).into(), player);
+        }
+        lobby
+    }
+
+    fn places(standings: &[Standing]) -> Vec<(PlayerId, usize)> {
+        standings.iter().map(|s| (s.player_id, s.place)).collect()
+    }
+
+    #[test]
+    fn by_score() {
+        let mut lobby = setup(&[50, 200, 100]);
+        lobby.options.tie_breaker = TieBreaker::None;
+        assert_eq!(
+            places(&rank(&lobby, &HashMap::new())),
+            [(1.into(), 1), (2.into(), 2), (0.into(), 3)]
+        );
+    }
+
+    #[test]
+    fn shared_places() {
+        let mut lobby = setup(&[100, 200, 100, 50]);
+        lobby.options.tie_breaker = TieBreaker::None;
+        assert_eq!(
+            places(&rank(&lobby, &HashMap::new())),
+            [(1.into(), 1), (0.into(), 2), (2.into(), 2), (3.into(), 4)]
+        );
+    }
+
+    #[test]
+    fn most_gold() {
+        let mut lobby = setup(&[150, 150]);
+        lobby.options.tie_breaker = TieBreaker::MostGold;
+        for (spat, collectors) in [
+            (Spatula::SpongebobsCloset, [1, 0]),
+            (Spatula::OnTopOfThePineapple, [0, 1]),
+            (Spatula::CowaBungee, [1, 0]),
+            // Worth no points, so it doesn't count as gold either
+            (Spatula::KahRahTae, [0, 1]),
+            (Spatula::TheSmallShallRuleOrNot, [0, 1]),
+        ] {
+            lobby
+                .game_state
+                .spatulas
+                .entry(spat)
+                .or_default()
+                .collection_vec = collectors.map(PlayerId::from).to_vec();
+        }
+        assert_eq!(
+            places(&rank(&lobby, &HashMap::new())),
+            [(1.into(), 1), (0.into(), 2)]
+        );
+    }
+
+    #[test]
+    fn earliest_finish() {
+        let mut lobby = setup(&[100, 100, 100]);
+        lobby.options.tie_breaker = TieBreaker::EarliestFinish;
+        let last_collection = HashMap::from([(0.into(), 12), (1.into(), 7)]);
+        assert_eq!(
+            places(&rank(&lobby, &last_collection)),
+            [(1.into(), 1), (0.into(), 2), (2.into(), 3)]
+        );
+    }
+
+    #[test]
+    fn small_shall_rule() {
+        let mut lobby = setup(&[100, 100, 100]);
+        lobby.options.tie_breaker = TieBreaker::SmallShallRule;
+        lobby
+            .game_state
+            .spatulas
+            .entry(Spatula::TheSmallShallRuleOrNot)
+            .or_default()
+            .collection_vec = vec![2.into()];
+        assert_eq!(
+            places(&rank(&lobby, &HashMap::new())),
+            [(2.into(), 1), (0.into(), 2), (1.into(), 2)]
+        );
+    }
+}
@@ -1,33 +1,132 @@
+mod admin;
 mod client;
+mod config;
+mod heartbeat;
+mod lag;
 mod lobby;
+mod metrics;
+mod rate_limit;
 mod state;
+#[cfg(test)]
+mod testing;
+mod websocket;
 
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr};
+
+use clash_lib::net::tls::TlsAcceptor;
+use config::{Config, LogFormat};
 use state::ServerState;
-use tokio::net::TcpListener;
-use tracing::metadata::LevelFilter;
+use tokio::net::{TcpListener, TcpStream};
 
 const VERSION: &str = env!("CLASH_VERSION");
-const DEFAULT_PORT: u16 = 42932;
 
 #[tokio::main]
 async fn main() {
-    tracing_subscriber::fmt()
-        .with_max_level(LevelFilter::DEBUG)
-        .init();
+    // TLS is set up before anything else so that a bad certificate is reported like any other
+    // config error
+    let (config, tls) = match Config::load().and_then(|config| {
+        let tls = config.tls_acceptor()?;
+        Ok((config, tls))
+    }) {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            eprintln!("{e:#}");
+            std::process::exit(1);
+        }
+    };
+
+    let subscriber = tracing_subscriber::fmt().with_max_level(config.log_level);
+    match config.log_format {
+        LogFormat::Full => subscriber.init(),
+        LogFormat::Compact => subscriber.compact().init(),
+        LogFormat::Json => subscriber.json().init(),
+    }
+
+    tracing::info!(
+        "Server Version: {} (protocol version {})",
+        crate::VERSION,
+        clash_lib::net::PROTOCOL_VERSION
+    );
+
+    let listeners = bind(&config.bind, config.port).await;
+    tracing::info!("Listening on port {}", config.port);
+
+    match tls {
+        Some(_) => tracing::info!("TLS enabled"),
+        None => tracing::warn!("TLS is not configured, connections will not be encrypted"),
+    }
+
+    let websocket_listeners = match config.websocket_port {
+        Some(port) => {
+            let listeners = bind(&config.bind, port).await;
+            tracing::info!("Accepting WebSocket spectators on port {port}");
+            listeners
+        }
+        None => Vec::new(),
+    };
 
-    tracing::info!("Server Version: {}", crate::VERSION);
+    // The admin console can only be reached from this machine
+    let admin_listener = match config.admin_port {
+        Some(port) => {
+            let mut listeners = bind(&[IpAddr::V4(Ipv4Addr::LOCALHOST)], port).await;
+            tracing::info!("Admin console listening on localhost port {port}");
+            listeners.pop()
+        }
+        None => None,
+    };
 
-    let port = std::env::var("PORT")
-        .ok()
-        .and_then(|p| p.parse().ok())
-        .unwrap_or(DEFAULT_PORT);
-    let listener = TcpListener::bind(("0.0.0.0", port)).await.unwrap();
-    tracing::info!("Listening on port {port}");
+    let state = ServerState::new(config);
+    if let Some(listener) = admin_listener {
+        tokio::spawn(admin::serve(state.clone(), listener));
+    }
+    for listener in websocket_listeners {
+        tokio::spawn(serve(
+            listener,
+            state.clone(),
+            tls.clone(),
+            websocket::accept_connection,
+        ));
+    }
+    let clients = listeners
+        .into_iter()
+        .map(|listener| {
+            tokio::spawn(serve(
+                listener,
+                state.clone(),
+                tls.clone(),
+                client::accept_connection,
+            ))
+        })
+        .collect::<Vec<_>>();
+    for task in clients {
+        task.await.unwrap();
+    }
+}
 
-    let state = ServerState::default();
+/// Listen on `port` at each of `addrs`.
+async fn bind(addrs: &[IpAddr], port: u16) -> Vec<TcpListener> {
+    let mut listeners = Vec::with_capacity(addrs.len());
+    for &addr in addrs {
+        let listener = TcpListener::bind((addr, port))
+            .await
+            .unwrap_or_else(|e| panic!("Failed to bind to {addr} port {port}: {e}"));
+        listeners.push(listener);
+    }
+    listeners
+}
+
+/// Accept connections from `listener` forever, handing each one to `accept`.
+async fn serve<F>(
+    listener: TcpListener,
+    state: ServerState,
+    tls: Option<TlsAcceptor>,
+    accept: fn(ServerState, Option<TlsAcceptor>, TcpStream) -> F,
+) where
+    F: Future<Output = ()> + Send + 'static,
+{
     loop {
         let (socket, _) = listener.accept().await.unwrap();
-
-        tokio::spawn(client::handle_new_connection(state.clone(), socket));
+        tokio::spawn(accept(state.clone(), tls.clone(), socket));
     }
 }
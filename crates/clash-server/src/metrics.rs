@@ -0,0 +1,28 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Counters describing how the server has been doing since it started.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// Times a client fell so far behind on lobby updates that it had to be sent a new snapshot.
+    lagged: AtomicU64,
+    /// Clients that were disconnected for lagging behind too often.
+    lag_disconnects: AtomicU64,
+}
+
+impl Metrics {
+    pub fn record_lag(&self) {
+        self.lagged.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_lag_disconnect(&self) {
+        self.lag_disconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn lagged(&self) -> u64 {
+        self.lagged.load(Ordering::Relaxed)
+    }
+
+    pub fn lag_disconnects(&self) -> u64 {
+        self.lag_disconnects.load(Ordering::Relaxed)
+    }
+}
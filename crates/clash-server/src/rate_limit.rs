@@ -0,0 +1,160 @@
+use std::collections::HashMap;
+use std::mem::{self, Discriminant};
+
+use clash_lib::net::{ClientMessage, LobbyMessage};
+use serde::Deserialize;
+use tokio::time::Instant;
+
+use crate::config::Config;
+
+/// How often something is allowed to happen: `per_sec` on average, with bursts of up to `burst`
+/// at once.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+pub struct RateLimit {
+    pub per_sec: f64,
+    pub burst: u32,
+}
+
+/// What should be done with a message from a client, see [`RateLimiter::check`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Allow,
+    /// The message exceeds a rate limit and should be dropped with a warning.
+    Drop,
+    /// The client has exceeded its rate limits too often and should be disconnected.
+    Disconnect,
+}
+
+/// Limits how quickly a single connection can send messages, both overall and for each kind of
+/// [`LobbyMessage`], so that one client can't flood its lobby with broadcasts.
+pub struct RateLimiter {
+    connection: TokenBucket,
+    message_limit: RateLimit,
+    messages: HashMap<Discriminant<LobbyMessage>, TokenBucket>,
+    /// Every dropped message costs a strike, and a client out of strikes is disconnected.
+    strikes: TokenBucket,
+}
+
+impl RateLimiter {
+    pub fn new(config: &Config) -> Self {
+        let strikes = config.rate_limit_strikes;
+        Self {
+            connection: TokenBucket::new(config.rate_limit),
+            message_limit: config.message_rate_limit,
+            messages: HashMap::new(),
+            strikes: TokenBucket::new(RateLimit {
+                per_sec: f64::from(strikes) / 60.0,
+                burst: strikes,
+            }),
+        }
+    }
+
+    /// Decide whether `message`, which was just received from the client, is within its limits.
+    pub fn check(&mut self, message: &ClientMessage) -> Verdict {
+        let allowed = match message {
+            ClientMessage::Lobby(m) | ClientMessage::Request { message: m, .. } => {
+                let limit = self.message_limit;
+                self.messages
+                    .entry(mem::discriminant(m))
+                    .or_insert_with(|| TokenBucket::new(limit))
+                    .take()
+            }
+            _ => true,
+        } && self.connection.take();
+
+        if allowed {
+            Verdict::Allow
+        } else if self.strikes.take() {
+            Verdict::Drop
+        } else {
+            Verdict::Disconnect
+        }
+    }
+}
+
+struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            tokens: f64::from(limit.burst),
+            refilled_at: Instant::now(),
+        }
+    }
+
+    /// Takes a token from the bucket, returning `false` if it's empty.
+    fn take(&mut self) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled_at);
+        self.refilled_at = now;
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.limit.per_sec)
+            .min(f64::from(self.limit.burst));
+
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use clash_lib::net::{ClientMessage, LobbyMessage};
+
+    use crate::config::Config;
+
+    use super::{RateLimit, RateLimiter, TokenBucket, Verdict};
+
+    #[tokio::test]
+    async fn token_bucket() {
+        let mut bucket = TokenBucket::new(RateLimit {
+            per_sec: 20.0,
+            burst: 2,
+        });
+        assert!(bucket.take());
+        assert!(bucket.take());
+        assert!(!bucket.take());
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert!(bucket.take());
+    }
+
+    #[test]
+    fn limits() {
+        let mut limiter = RateLimiter::new(&Config {
+            rate_limit: RateLimit {
+                per_sec: 0.0,
+                burst: 4,
+            },
+            message_rate_limit: RateLimit {
+                per_sec: 0.0,
+                burst: 2,
+            },
+            rate_limit_strikes: 1,
+            ..Default::default()
+        });
+        let level = ClientMessage::Lobby(LobbyMessage::GameCurrentLevel { level: None });
+        let can_start = ClientMessage::Request {
+            id: 0,
+            message: LobbyMessage::PlayerCanStart(true),
+        };
+
+        // Each kind of message has its own limit
+        assert_eq!(limiter.check(&level), Verdict::Allow);
+        assert_eq!(limiter.check(&level), Verdict::Allow);
+        assert_eq!(limiter.check(&level), Verdict::Drop);
+        assert_eq!(limiter.check(&can_start), Verdict::Allow);
+
+        // While everything counts towards the connection's limit
+        assert_eq!(limiter.check(&ClientMessage::Resync), Verdict::Allow);
+        assert_eq!(limiter.check(&ClientMessage::Resync), Verdict::Disconnect);
+    }
+}
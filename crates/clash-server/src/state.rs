@@ -1,38 +1,121 @@
-use clash_lib::net::ProtocolError;
+use clash_lib::lobby::LobbySummary;
+use clash_lib::net::{ProtocolError, ResumeToken};
 use clash_lib::{LobbyId, PlayerId};
+use futures::future;
 use rand::{thread_rng, Rng};
 use std::collections::{HashMap, HashSet};
 use std::fmt::Display;
 use std::ops::Deref;
 use std::sync::{Arc, Mutex, MutexGuard};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
 
+use crate::config::Config;
 use crate::lobby;
 use crate::lobby::lobby_handle::{LobbyHandle, LobbyHandleProvider};
+use crate::metrics::Metrics;
 
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, Debug)]
 pub struct ServerState {
+    config: Arc<Config>,
     players: Arc<Mutex<HashSet<PlayerId>>>,
     lobbies: Arc<Mutex<HashMap<LobbyId, LobbyHandleProvider>>>,
+    sessions: Arc<Mutex<HashMap<ResumeToken, HeldSession>>>,
+    /// Limits how many connections can be open at once.
+    connections: Arc<Semaphore>,
+    /// Limits how many connections can be in the middle of their handshake at once.
+    handshakes: Arc<Semaphore>,
+    metrics: Arc<Metrics>,
+}
+
+impl Default for ServerState {
+    fn default() -> Self {
+        Self::new(Config::default())
+    }
+}
+
+/// A player who lost connection, kept alive so that they can resume their session.
+#[derive(Debug)]
+pub struct HeldSession {
+    pub player_id: OwnedId<PlayerId>,
+    pub lobby_handle: LobbyHandle,
 }
 
 impl ServerState {
+    pub fn new(config: Config) -> Self {
+        Self {
+            connections: Arc::new(Semaphore::new(config.max_connections)),
+            handshakes: Arc::new(Semaphore::new(config.max_pending_connections)),
+            config: Arc::new(config),
+            players: Default::default(),
+            lobbies: Default::default(),
+            sessions: Default::default(),
+            metrics: Default::default(),
+        }
+    }
+
+    /// Reserve a slot for a new connection, to be held for as long as it stays open.
+    ///
+    /// # Errors
+    ///
+    /// Will return a [`ProtocolError::ServerBusy`] if too many connections are already open.
+    pub fn begin_connection(&self) -> Result<OwnedSemaphorePermit, ProtocolError> {
+        self.connections
+            .clone()
+            .try_acquire_owned()
+            .map_err(|_| ProtocolError::ServerBusy)
+    }
+
+    /// Reserve a slot for a new connection to perform its handshake in.
+    ///
+    /// # Errors
+    ///
+    /// Will return a [`ProtocolError::ServerBusy`] if too many connections are already
+    /// performing their handshake.
+    pub fn begin_handshake(&self) -> Result<OwnedSemaphorePermit, ProtocolError> {
+        self.handshakes
+            .clone()
+            .try_acquire_owned()
+            .map_err(|_| ProtocolError::ServerBusy)
+    }
+
     pub fn add_player(&self) -> OwnedId<PlayerId> {
         let player_id = self.gen_player_id();
         self.players().insert(player_id);
         OwnedId::<PlayerId>::new(self.clone(), player_id)
     }
 
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
     /// Open a new lobby with the player represented by `host_id` as the only player.
     ///
     /// This will add a [`LobbyHandleProvider`] to [`ServerState`]'s lobby list and return a
     /// concrete `LobbyHandle` for the player who opened the lobby.
-    pub fn open_lobby(&self, host_id: PlayerId) -> LobbyHandle {
-        let lobby_id = self.gen_lobby_id();
-        let (handle_provider, handle) =
-            lobby::start_new_lobby(OwnedId::<LobbyId>::new(self.clone(), lobby_id), host_id);
+    ///
+    /// # Errors
+    ///
+    /// Will return a [`ProtocolError::LobbyLimit`] if the server already has as many lobbies open
+    /// as it is configured to allow.
+    pub fn open_lobby(&self, host_id: PlayerId) -> Result<LobbyHandle, ProtocolError> {
+        // Hold the lock until the new lobby is added so no other lobby can be given the same id
+        let mut lobbies = self.lobbies();
+        if lobbies.len() >= self.config.max_lobbies {
+            return Err(ProtocolError::LobbyLimit);
+        }
+        let lobby_id = gen_lobby_id(&lobbies);
+        let (handle_provider, handle) = lobby::start_new_lobby(
+            OwnedId::<LobbyId>::new(self.clone(), lobby_id),
+            host_id,
+            self.config.lobby.clone(),
+        );
         tracing::info!("Lobby {lobby_id} opened");
-        self.lobbies().insert(lobby_id, handle_provider);
-        handle
+        lobbies.insert(lobby_id, handle_provider);
+        Ok(handle)
     }
 
     /// Get a [`LobbyHandleProvider`] instance for the specified `lobby_id`
@@ -53,14 +136,83 @@ impl ServerState {
         Ok(provider)
     }
 
-    fn players(&self) -> MutexGuard<HashSet<PlayerId>> {
+    /// Every open lobby, ordered by id.
+    pub fn lobby_providers(&self) -> Vec<(LobbyId, LobbyHandleProvider)> {
+        let mut lobbies = self
+            .lobbies()
+            .iter()
+            .map(|(&id, provider)| (id, provider.clone()))
+            .collect::<Vec<_>>();
+        lobbies.sort_by_key(|&(id, _)| id.0);
+        lobbies
+    }
+
+    /// Every player id in use, including players whose session is being held.
+    pub fn player_ids(&self) -> Vec<PlayerId> {
+        self.players().iter().copied().collect()
+    }
+
+    /// Number of connections currently open.
+    pub fn connection_count(&self) -> usize {
+        self.config.max_connections - self.connections.available_permits()
+    }
+
+    /// Describe every public lobby for the lobby browser.
+    pub async fn list_lobbies(&self) -> Vec<LobbySummary> {
+        let providers = self.lobbies().values().cloned().collect::<Vec<_>>();
+        let summaries = future::join_all(providers.iter().map(LobbyHandleProvider::summary)).await;
+        let mut lobbies = summaries
+            .into_iter()
+            .filter_map(|s| s.ok().flatten())
+            .collect::<Vec<_>>();
+        lobbies.sort_by(|a, b| a.name.cmp(&b.name));
+        lobbies
+    }
+
+    /// Hold `session` until it is resumed with `token` or the resume grace period ends, at which
+    /// point the session is dropped and the player is removed from their lobby.
+    pub fn hold_session(&self, token: ResumeToken, session: HeldSession) {
+        self.sessions().insert(token, session);
+
+        let state = self.clone();
+        let grace = self.config.resume_grace;
+        tokio::spawn(async move {
+            tokio::time::sleep(grace).await;
+            let expired = state.sessions().remove(&token);
+            if let Some(session) = expired {
+                tracing::info!("Session for player {} expired", session.player_id);
+            }
+        });
+    }
+
+    /// Take back a session previously held with [`ServerState::hold_session`].
+    ///
+    /// # Errors
+    ///
+    /// Will return a [`ProtocolError::InvalidResumeToken`] if there is no session held for `token`,
+    /// either because it was never issued or because the session has expired.
+    pub fn resume_session(&self, token: ResumeToken) -> Result<HeldSession, ProtocolError> {
+        self.sessions()
+            .remove(&token)
+            .ok_or(ProtocolError::InvalidResumeToken)
+    }
+
+    pub fn gen_resume_token(&self) -> ResumeToken {
+        ResumeToken(thread_rng().gen())
+    }
+
+    fn players(&self) -> MutexGuard<'_, HashSet<PlayerId>> {
         self.players.lock().unwrap()
     }
 
-    fn lobbies(&self) -> MutexGuard<HashMap<LobbyId, LobbyHandleProvider>> {
+    fn lobbies(&self) -> MutexGuard<'_, HashMap<LobbyId, LobbyHandleProvider>> {
         self.lobbies.lock().unwrap()
     }
 
+    fn sessions(&self) -> MutexGuard<'_, HashMap<ResumeToken, HeldSession>> {
+        self.sessions.lock().unwrap()
+    }
+
     // TODO: dedupe this.
     fn gen_player_id(&self) -> PlayerId {
         let mut player_id;
@@ -72,16 +224,15 @@ impl ServerState {
         }
         player_id
     }
+}
 
-    fn gen_lobby_id(&self) -> LobbyId {
-        let mut lobby_id;
-        loop {
-            lobby_id = thread_rng().gen::<u32>().into();
-            if !self.lobbies().contains_key(&lobby_id) {
-                break;
-            };
+/// Pick an unused lobby id. Ids are kept below [`LobbyId::MAX`] so they can be shared as a code.
+fn gen_lobby_id(lobbies: &HashMap<LobbyId, LobbyHandleProvider>) -> LobbyId {
+    loop {
+        let lobby_id = thread_rng().gen_range(0..LobbyId::MAX).into();
+        if !lobbies.contains_key(&lobby_id) {
+            return lobby_id;
         }
-        lobby_id
     }
 }
 
@@ -153,3 +304,29 @@ impl<Id: Copy> Drop for OwnedId<Id> {
         (self.cleanup)(self.state.clone(), self.id);
     }
 }
+
+#[cfg(test)]
+mod test {
+    use clash_lib::net::ProtocolError;
+
+    use super::ServerState;
+    use crate::config::Config;
+
+    #[test]
+    fn handshake_limit() {
+        let state = ServerState::new(Config {
+            max_pending_connections: 1,
+            ..Default::default()
+        });
+
+        let permit = state.begin_handshake().unwrap();
+        assert!(matches!(
+            state.begin_handshake(),
+            Err(ProtocolError::ServerBusy)
+        ));
+
+        // Finishing a handshake makes room for another
+        drop(permit);
+        assert!(state.begin_handshake().is_ok());
+    }
+}
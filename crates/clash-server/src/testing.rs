@@ -0,0 +1,14 @@
+//! Helpers shared between tests.
+
+use clash_lib::net::tls::{self, TlsAcceptor};
+
+/// An acceptor for a freshly generated self-signed certificate.
+pub fn tls_acceptor() -> TlsAcceptor {
+    let generated = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+    let dir = std::env::temp_dir();
+    let cert = dir.join(format!("clash-server-test-{}.pem", std::process::id()));
+    let key = dir.join(format!("clash-server-test-{}.key", std::process::id()));
+    std::fs::write(&cert, generated.cert.pem()).unwrap();
+    std::fs::write(&key, generated.signing_key.serialize_pem()).unwrap();
+    tls::acceptor(&cert, &key).unwrap()
+}
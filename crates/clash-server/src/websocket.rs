@@ -0,0 +1,416 @@
+//! Read-only access to lobbies over WebSockets, for browser based spectators and stream overlays.
+//!
+//! A connection is opened to `/lobby/<LOBBY_ID>`, where the lobby id is written as the same code
+//! the client copies. The server then sends the same messages a spectator would receive,
+//! encoded as JSON text frames: a `GameLobbyInfo` with the full lobby followed by a `LobbyDelta`
+//! for every change made to it. If the lobby doesn't exist an `Error` is sent and the connection
+//! is closed. Anything sent by the browser is ignored.
+//!
+//! Lobbies with a spectator password expect it in the query string, as in
+//! `/lobby/<LOBBY_ID>?password=<PASSWORD>`.
+
+use clash_lib::net::{ProtocolError, ServerMessage};
+use std::net::IpAddr;
+
+use clash_lib::LobbyId;
+use futures::{SinkExt, StreamExt};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::select;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::OwnedSemaphorePermit;
+use tokio::time::Instant;
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::StatusCode;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+use tracing::instrument;
+
+use clash_lib::net::tls::TlsAcceptor;
+
+use crate::lag::LagTracker;
+use crate::lobby::lobby_handle::LobbyHandleProvider;
+use crate::lobby::{LobbyEvent, LobbySubscription};
+use crate::state::ServerState;
+
+/// Set up the transport for a newly accepted WebSocket connection, then begin serving it.
+pub async fn accept_connection(state: ServerState, tls: Option<TlsAcceptor>, socket: TcpStream) {
+    let addr = socket.peer_addr().ok().map(|addr| addr.ip());
+    let Some(acceptor) = tls else {
+        return handle_new_connection(state, socket, addr).await;
+    };
+
+    // The TLS handshake counts towards the connection limits and the handshake deadline like the
+    // WebSocket handshake does
+    let deadline = Instant::now() + state.config().handshake_timeout;
+    let Some(permits) = begin(&state) else {
+        return;
+    };
+    match tokio::time::timeout_at(deadline, acceptor.accept(socket)).await {
+        Ok(Ok(stream)) => serve(state, stream, addr, permits, deadline).await,
+        Ok(Err(e)) => tracing::warn!("TLS handshake failed: {e}"),
+        Err(_) => tracing::warn!("TLS handshake timed out"),
+    }
+}
+
+/// Perform the WebSocket handshake on `stream` from `addr` and stream the requested lobby to it.
+pub async fn handle_new_connection(
+    state: ServerState,
+    stream: impl AsyncRead + AsyncWrite + Unpin,
+    addr: Option<IpAddr>,
+) {
+    let deadline = Instant::now() + state.config().handshake_timeout;
+    if let Some(permits) = begin(&state) {
+        serve(state, stream, addr, permits, deadline).await;
+    }
+}
+
+/// Reserve a connection slot and a handshake slot for a new connection.
+fn begin(state: &ServerState) -> Option<(OwnedSemaphorePermit, OwnedSemaphorePermit)> {
+    // WebSocket connections share the connection and handshake limits of regular connections
+    let Ok(connection) = state.begin_connection() else {
+        tracing::warn!("Too many connections, dropping WebSocket connection");
+        return None;
+    };
+    let Ok(handshake) = state.begin_handshake() else {
+        tracing::warn!("Too many pending connections, dropping WebSocket connection");
+        return None;
+    };
+    Some((connection, handshake))
+}
+
+#[instrument(skip_all)]
+async fn serve(
+    state: ServerState,
+    stream: impl AsyncRead + AsyncWrite + Unpin,
+    addr: Option<IpAddr>,
+    (_connection, permit): (OwnedSemaphorePermit, OwnedSemaphorePermit),
+    deadline: Instant,
+) {
+    let mut lobby_id = None;
+    let mut password = None;
+    // The response type is dictated by tungstenite
+    #[allow(clippy::result_large_err)]
+    let callback = |request: &Request, response: Response| match parse_path(request.uri().path()) {
+        Some(id) => {
+            lobby_id = Some(id);
+            password = request.uri().query().and_then(parse_password);
+            Ok(response)
+        }
+        None => Err(not_found()),
+    };
+    let mut ws = match tokio::time::timeout_at(
+        deadline,
+        tokio_tungstenite::accept_hdr_async(stream, callback),
+    )
+    .await
+    {
+        Ok(Ok(ws)) => ws,
+        Ok(Err(e)) => {
+            tracing::warn!("WebSocket handshake failed: {e}");
+            return;
+        }
+        Err(_) => {
+            tracing::warn!("WebSocket handshake timed out");
+            return;
+        }
+    };
+    // The callback only accepts requests with a lobby id
+    let lobby_id = lobby_id.expect("WebSocket accepted without a lobby id");
+
+    let (lobby, subscription) = match spectate(&state, lobby_id, password, addr).await {
+        Ok(spectating) => spectating,
+        Err(error) => {
+            tracing::info!(%error, "Rejecting WebSocket spectator");
+            let _ = send(&mut ws, &ServerMessage::Error { error }).await;
+            let _ = ws.close(None).await;
+            return;
+        }
+    };
+    drop(permit);
+
+    tracing::info!("WebSocket spectator joined lobby {lobby_id}");
+    run(&state, ws, lobby, subscription).await;
+    tracing::info!("WebSocket spectator disconnected");
+}
+
+async fn spectate(
+    state: &ServerState,
+    lobby_id: LobbyId,
+    password: Option<String>,
+    addr: Option<IpAddr>,
+) -> Result<(LobbyHandleProvider, LobbySubscription), ProtocolError> {
+    let provider = state.get_lobby_handle_provider(lobby_id)?;
+    provider.authorize(password, true, addr).await?;
+    let subscription = provider.spectate().await?;
+    Ok((provider, subscription))
+}
+
+async fn run(
+    state: &ServerState,
+    mut ws: WebSocketStream<impl AsyncRead + AsyncWrite + Unpin>,
+    lobby: LobbyHandleProvider,
+    subscription: LobbySubscription,
+) {
+    let LobbySubscription {
+        snapshot,
+        events: mut lobby_rx,
+    } = subscription;
+    if send(&mut ws, &ServerMessage::GameLobbyInfo { lobby: snapshot })
+        .await
+        .is_err()
+    {
+        return;
+    }
+
+    // Browsers answer pings on their own, this just lets us notice connections that have died
+    let mut ping = tokio::time::interval(state.config().heartbeat_interval);
+    let pong_timeout = state.config().heartbeat_interval * state.config().heartbeat_misses;
+    let mut last_pong = Instant::now();
+    let mut lag = LagTracker::new(state.clone());
+    loop {
+        select! {
+            event = lobby_rx.recv() => {
+                let m = match event {
+                    Ok(LobbyEvent::Message(m)) => m,
+                    Ok(LobbyEvent::Disconnect { player_id: None, error }) => {
+                        let _ = send(&mut ws, &ServerMessage::Error { error }).await;
+                        let _ = ws.close(None).await;
+                        return;
+                    }
+                    Ok(LobbyEvent::Disconnect { .. }) => continue,
+                    Err(RecvError::Lagged(skipped)) => {
+                        if !lag.lagged(skipped) {
+                            let _ = ws.close(None).await;
+                            return;
+                        }
+                        lobby_rx = lobby_rx.resubscribe();
+                        let Ok(lobby) = lobby.snapshot().await else {
+                            return;
+                        };
+                        ServerMessage::GameLobbyInfo { lobby }
+                    }
+                    // The lobby has closed, so there is nothing left to spectate
+                    Err(RecvError::Closed) => {
+                        let _ = ws.close(None).await;
+                        return;
+                    }
+                };
+                if send(&mut ws, &m).await.is_err() {
+                    return;
+                }
+            }
+            frame = ws.next() => match frame {
+                Some(Ok(Message::Close(_))) | None => return,
+                Some(Ok(Message::Pong(_))) => last_pong = Instant::now(),
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => {
+                    tracing::error!("Error reading WebSocket message, closing connection\n{e:?}");
+                    return;
+                }
+            },
+            _ = ping.tick() => {
+                if last_pong.elapsed() >= pong_timeout {
+                    tracing::info!("WebSocket spectator stopped answering pings");
+                    let _ = ws.close(None).await;
+                    return;
+                }
+                if ws.send(Message::Ping(Default::default())).await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+async fn send(
+    ws: &mut WebSocketStream<impl AsyncRead + AsyncWrite + Unpin>,
+    msg: &ServerMessage,
+) -> Result<(), tokio_tungstenite::tungstenite::Error> {
+    let json = serde_json::to_string(msg).expect("ServerMessage should always serialize to JSON");
+    ws.send(Message::text(json)).await
+}
+
+/// Get the lobby id out of a request path of the form `/lobby/<LOBBY_ID>`.
+fn parse_path(path: &str) -> Option<LobbyId> {
+    let id = path.strip_prefix("/lobby/")?.trim_end_matches('/');
+    id.parse().ok()
+}
+
+/// Get the spectator password out of a request's query string.
+fn parse_password(query: &str) -> Option<String> {
+    form_urlencoded::parse(query.as_bytes())
+        .find(|(key, _)| key == "password")
+        .map(|(_, password)| password.into_owned())
+}
+
+fn not_found() -> ErrorResponse {
+    let mut response = ErrorResponse::new(Some(
+        "Expected a path of the form /lobby/<LOBBY_ID>".to_owned(),
+    ));
+    *response.status_mut() = StatusCode::NOT_FOUND;
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use clash_lib::LobbyId;
+    use futures::StreamExt;
+    use serde_json::Value;
+    use tokio::io::duplex;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_tungstenite::tungstenite::Message;
+
+    use crate::config::Config;
+    use crate::state::ServerState;
+    use crate::testing;
+
+    use super::{accept_connection, handle_new_connection, parse_password, parse_path};
+
+    #[test]
+    fn lobby_path() {
+        assert_eq!(parse_path("/lobby/00001Z"), Some(LobbyId(63)));
+        assert_eq!(parse_path("/lobby/00abc0/"), Some(LobbyId(0x52D80)));
+        assert_eq!(parse_path("/lobby/1F"), None);
+        assert_eq!(parse_path("/lobby/"), None);
+        assert_eq!(parse_path("/spectate/1F"), None);
+    }
+
+    #[test]
+    fn password_query() {
+        assert_eq!(
+            parse_password("password=hunter2"),
+            Some("hunter2".to_owned())
+        );
+        assert_eq!(
+            parse_password("a=b&password=two%20words"),
+            Some("two words".to_owned())
+        );
+        assert_eq!(parse_password("a=b"), None);
+    }
+
+    async fn next_json(
+        ws: &mut (impl StreamExt<Item = Result<Message, tokio_tungstenite::tungstenite::Error>> + Unpin),
+    ) -> Value {
+        loop {
+            match ws.next().await.unwrap().unwrap() {
+                Message::Text(text) => return serde_json::from_str(&text).unwrap(),
+                Message::Ping(_) => continue,
+                m => panic!("Unexpected message {m:?}"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn spectate_lobby() {
+        let state = ServerState::default();
+        let host = state.add_player();
+        let handle = state.open_lobby(*host).unwrap();
+        let lobby_id = handle.join_lobby(None).await.unwrap().snapshot.lobby_id;
+
+        let (client, server) = duplex(4096);
+        tokio::spawn(handle_new_connection(state.clone(), server, None));
+        let (mut ws, _) =
+            tokio_tungstenite::client_async(format!("ws://localhost/lobby/{lobby_id}"), client)
+                .await
+                .unwrap();
+
+        let info = next_json(&mut ws).await;
+        assert_eq!(info["GameLobbyInfo"]["lobby"]["lobby_id"], lobby_id.0);
+
+        handle.set_player_can_start(true).await.unwrap();
+        let delta = next_json(&mut ws).await;
+        assert_eq!(
+            delta["LobbyDelta"]["delta"]["Action"]["action"]["PlayerCanStart"],
+            true
+        );
+    }
+
+    #[tokio::test]
+    async fn unanswered_pings() {
+        let state = ServerState::new(Config {
+            heartbeat_interval: Duration::from_millis(50),
+            heartbeat_misses: 2,
+            ..Default::default()
+        });
+        let host = state.add_player();
+        let handle = state.open_lobby(*host).unwrap();
+        let lobby_id = handle.join_lobby(None).await.unwrap().snapshot.lobby_id;
+
+        let (client, server) = duplex(4096);
+        tokio::spawn(handle_new_connection(state.clone(), server, None));
+        let (mut ws, _) =
+            tokio_tungstenite::client_async(format!("ws://localhost/lobby/{lobby_id}"), client)
+                .await
+                .unwrap();
+
+        // Pongs are only sent while reading, so a client that stops reading stops answering
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        let closed = async {
+            loop {
+                match ws.next().await {
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                    Some(Ok(_)) => continue,
+                }
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(2), closed)
+            .await
+            .expect("Connection should be closed");
+    }
+
+    #[tokio::test]
+    async fn tls_handshake_is_pending() {
+        let state = ServerState::new(Config {
+            max_pending_connections: 1,
+            handshake_timeout: Duration::from_millis(200),
+            ..Default::default()
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stalled = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
+        let accept = tokio::spawn(accept_connection(
+            state.clone(),
+            Some(testing::tls_acceptor()),
+            socket,
+        ));
+        tokio::task::yield_now().await;
+
+        // A spectator that never finishes its TLS handshake holds a handshake slot
+        let (client, server) = duplex(4096);
+        tokio::spawn(handle_new_connection(state.clone(), server, None));
+        assert!(
+            tokio_tungstenite::client_async("ws://localhost/lobby/000001", client)
+                .await
+                .is_err()
+        );
+
+        // Until the handshake deadline runs out
+        tokio::time::timeout(Duration::from_secs(2), accept)
+            .await
+            .expect("TLS handshake should time out")
+            .unwrap();
+        drop(stalled);
+    }
+
+    #[tokio::test]
+    async fn missing_lobby() {
+        let state = ServerState::default();
+        let (client, server) = duplex(4096);
+        tokio::spawn(handle_new_connection(state, server, None));
+        let (mut ws, _) = tokio_tungstenite::client_async("ws://localhost/lobby/000001", client)
+            .await
+            .unwrap();
+
+        let error = next_json(&mut ws).await;
+        assert!(error["Error"]["error"]["InvalidLobbyId"].is_number());
+        assert!(matches!(
+            ws.next().await,
+            Some(Ok(Message::Close(_))) | None
+        ));
+    }
+}
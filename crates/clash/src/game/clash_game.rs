@@ -4,8 +4,8 @@ use bfbb::game_interface::game_var::{GameVar, GameVarMut};
 use bfbb::game_interface::{InterfaceError, InterfaceProvider, InterfaceResult};
 use bfbb::game_state::{GameMode as BfBBGameMode, GameOstrich};
 use bfbb::{IntoEnumIterator, Level, Spatula};
-use clash_lib::lobby::{GamePhase, NetworkedLobby};
-use clash_lib::net::{Item, LobbyMessage, Message};
+use clash_lib::lobby::{DeltaOutcome, GamePhase, NetworkedLobby};
+use clash_lib::net::{ClientMessage, Item, LobbyDelta, LobbyMessage};
 use clash_lib::PlayerId;
 use tracing::instrument;
 
@@ -66,7 +66,7 @@ impl<I: InterfaceProvider> GameMode for ClashGame<I> {
             if local_player.current_level != level {
                 local_player.current_level = level;
                 network_sender
-                    .try_send(NetCommand::Send(Message::Lobby(
+                    .try_send(NetCommand::Send(ClientMessage::Lobby(
                         LobbyMessage::GameCurrentLevel { level },
                     )))
                     .unwrap();
@@ -79,7 +79,7 @@ impl<I: InterfaceProvider> GameMode for ClashGame<I> {
             if local_player.ready_to_start != can_start {
                 local_player.ready_to_start = can_start;
                 network_sender
-                    .try_send(NetCommand::Send(Message::Lobby(
+                    .try_send(NetCommand::Send(ClientMessage::Lobby(
                         LobbyMessage::PlayerCanStart(can_start),
                     )))
                     .unwrap();
@@ -121,7 +121,7 @@ impl<I: InterfaceProvider> GameMode for ClashGame<I> {
                 if interface.is_task_complete(spat)? {
                     self.local_spat_state.insert(spat);
                     network_sender
-                        .try_send(NetCommand::Send(Message::Lobby(
+                        .try_send(NetCommand::Send(ClientMessage::Lobby(
                             LobbyMessage::GameItemCollected {
                                 item: Item::Spatula(spat),
                             },
@@ -133,7 +133,7 @@ impl<I: InterfaceProvider> GameMode for ClashGame<I> {
                 else if interface.is_spatula_being_collected(spat, local_player.current_level)? {
                     self.local_spat_state.insert(spat);
                     network_sender
-                        .try_send(NetCommand::Send(Message::Lobby(
+                        .try_send(NetCommand::Send(ClientMessage::Lobby(
                             LobbyMessage::GameItemCollected {
                                 item: Item::Spatula(spat),
                             },
@@ -147,47 +147,66 @@ impl<I: InterfaceProvider> GameMode for ClashGame<I> {
         })
     }
 
-    fn message(&mut self, message: LobbyMessage, gui_handle: &mut GuiHandle) {
-        match message {
-            LobbyMessage::GameBegin => {
-                self.local_spat_state.clear();
-                let lobby = &self.lobby;
-
-                let _ = self.provider.do_with_interface(|i| {
-                    i.powers.start_with_powers(lobby.options.ng_plus)?;
-                    i.start_new_game()
-                });
-                gui_handle.send(lobby.clone());
+    fn apply_delta(
+        &mut self,
+        delta: LobbyDelta,
+        sequence: u64,
+        checksum: u64,
+        gui_handle: &mut GuiHandle,
+    ) -> DeltaOutcome {
+        let outcome = self.lobby.apply_stamped(&delta, sequence, checksum);
+        // We'll be sent a new lobby to replace ours if we're out of sync, so don't act on this one
+        if outcome != DeltaOutcome::Applied {
+            return outcome;
+        }
+        if let LobbyDelta::Action { action, .. } = &delta {
+            match action {
+                LobbyMessage::GameBegin => {
+                    self.local_spat_state.clear();
+                    let lobby = &self.lobby;
+
+                    let _ = self.provider.do_with_interface(|i| {
+                        i.powers.start_with_powers(lobby.options.ng_plus)?;
+                        i.start_new_game()
+                    });
+                }
+                LobbyMessage::ResetLobby | LobbyMessage::GameItemCollected { .. } => {
+                    self.sync_spatula_count()
+                }
+                // The phase change is enough to stop reporting collections, the GUI shows the
+                // standings the server sent along with it.
+                LobbyMessage::GameEnd => tracing::info!("Game finished"),
+                _ => (),
             }
-            // We're not yet doing partial updates
-            LobbyMessage::ResetLobby => todo!(),
-            LobbyMessage::PlayerOptions { options: _ } => todo!(),
-            LobbyMessage::GameOptions { options: _ } => todo!(),
-            LobbyMessage::GameEnd => todo!(),
-            LobbyMessage::PlayerCanStart(_) => todo!(),
-            LobbyMessage::GameCurrentLevel { level: _ } => todo!(),
-            LobbyMessage::GameItemCollected { item: _ } => todo!(),
         }
+        gui_handle.send(delta);
+        outcome
     }
 
     fn update_lobby(&mut self, new_lobby: NetworkedLobby, gui_sender: &mut GuiHandle) {
-        // This could fail if the user is restarting dolphin, but that will desync a lot of other things as well
-        // so it's fine to just wait for a future lobby update to correct the issue
-        let _ = self.provider.do_with_interface(|i| {
-            i.spatula_count
-                .set(new_lobby.game_state.spatulas.len() as u32)
-        });
         self.lobby = new_lobby.clone();
+        self.sync_spatula_count();
         gui_sender.send(new_lobby);
     }
 }
 
+impl<I: InterfaceProvider> ClashGame<I> {
+    fn sync_spatula_count(&mut self) {
+        let count = self.lobby.game_state.spatulas.len() as u32;
+        // This could fail if the user is restarting dolphin, but that will desync a lot of other things as well
+        // so it's fine to just wait for a future lobby update to correct the issue
+        let _ = self
+            .provider
+            .do_with_interface(|i| i.spatula_count.set(count));
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use clash_lib::{
         game_state::SpatulaState,
         lobby::{GamePhase, NetworkedLobby},
-        net::{LobbyMessage, Message},
+        net::{ClientMessage, LobbyDelta, LobbyMessage},
         player::{NetworkedPlayer, PlayerOptions},
     };
 
@@ -232,7 +251,7 @@ mod tests {
         game.update(&sender).unwrap();
         for e in expected.into_iter() {
             match receiver.try_recv() {
-                Ok(NetCommand::Send(Message::Lobby(m))) => assert_eq!(e, m),
+                Ok(NetCommand::Send(ClientMessage::Lobby(m))) => assert_eq!(e, m),
                 Ok(m) => panic!("Incorrect Message. Got: {m:#?}\nExpected: {e:#?}"),
                 Err(_) => panic!("No message available. Expected message {e:#?}"),
             }
@@ -330,14 +349,40 @@ mod tests {
         });
         game.lobby.options.ng_plus = true;
 
+        let begin = LobbyDelta::Action {
+            player_id: 0.into(),
+            action: LobbyMessage::GameBegin,
+        };
         let mut handle = GuiHandle::dummy();
-        game.message(LobbyMessage::GameBegin, &mut handle);
+        game.apply_delta(begin.clone(), 1, 0, &mut handle);
         assert!(game.provider.powers.initial_bubble_bowl.value);
         assert!(game.provider.powers.initial_cruise_bubble.value);
 
         game.lobby.options.ng_plus = false;
-        game.message(LobbyMessage::GameBegin, &mut handle);
+        game.apply_delta(begin, 2, 0, &mut handle);
         assert!(!game.provider.powers.initial_bubble_bowl.value);
         assert!(!game.provider.powers.initial_cruise_bubble.value);
     }
+
+    #[test]
+    fn no_collecting_after_game_end() {
+        let mut game = setup_game(|interface| {
+            let task = &mut interface.tasks[Spatula::SpongebobsCloset];
+            task.state.as_mut().unwrap().value |= 4;
+            Ok(())
+        });
+        let end = LobbyDelta::Action {
+            player_id: 0.into(),
+            action: LobbyMessage::GameEnd,
+        };
+        let checksum = {
+            let mut lobby = game.lobby.clone();
+            lobby.apply(&end);
+            lobby.game_state.checksum()
+        };
+        let mut handle = GuiHandle::dummy();
+        game.apply_delta(end, 1, checksum, &mut handle);
+        assert_eq!(game.lobby.game_phase, GamePhase::Finished);
+        update_and_check(&mut game, None);
+    }
 }
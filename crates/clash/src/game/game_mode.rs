@@ -1,5 +1,8 @@
 use bfbb::game_interface::InterfaceResult;
-use clash_lib::{lobby::NetworkedLobby, net::LobbyMessage};
+use clash_lib::{
+    lobby::{DeltaOutcome, NetworkedLobby},
+    net::LobbyDelta,
+};
 
 use crate::{gui::handle::GuiHandle, net::NetCommandSender};
 
@@ -9,7 +12,15 @@ use crate::{gui::handle::GuiHandle, net::NetCommandSender};
 pub trait GameMode {
     fn update(&mut self, network_sender: &NetCommandSender) -> InterfaceResult<()>;
 
-    fn message(&mut self, message: LobbyMessage, gui_sender: &mut GuiHandle);
+    /// Apply a delta stamped with the server's sequence number and checksum, see
+    /// [`NetworkedLobby::apply_stamped`].
+    fn apply_delta(
+        &mut self,
+        delta: LobbyDelta,
+        sequence: u64,
+        checksum: u64,
+        gui_sender: &mut GuiHandle,
+    ) -> DeltaOutcome;
 
     fn update_lobby(&mut self, new_lobby: NetworkedLobby, gui_sender: &mut GuiHandle);
 }
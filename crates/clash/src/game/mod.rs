@@ -3,8 +3,9 @@ mod game_mode;
 
 use bfbb::game_interface::dolphin::DolphinInterface;
 use bfbb::game_interface::{InterfaceError, InterfaceProvider};
+use clash_lib::lobby::{DeltaOutcome, NetworkedLobby};
 use clash_lib::net::LobbyMessage;
-use clash_lib::net::Message;
+use clash_lib::net::{ClientMessage, ServerMessage};
 use spin_sleep::LoopHelper;
 use std::sync::mpsc::Receiver;
 use tokio::sync::oneshot::error::TryRecvError;
@@ -22,24 +23,38 @@ pub type ShutdownReceiver = tokio::sync::oneshot::Receiver<()>;
 ///
 /// This is a temporary hack that is necessary because the GUI relies on the logic thread
 /// to update it with new lobby information. Having a dedicated thread that only forwards messages
-/// is certainly a bit unecessary but it doesn't really make sense to refactor the GUI to receive
-/// network messages directly. The reasoning being that when we implement partial updates the GUI won't
-/// know how to actually update the lobby in place from those messages.
+/// is certainly a bit unecessary but it keeps the GUI unaware of the network protocol.
 pub fn start_spectator(
     mut gui_handle: GuiHandle,
-    _network_sender: NetCommandSender,
-    logic_receiver: Receiver<Message>,
+    network_sender: NetCommandSender,
+    logic_receiver: Receiver<ServerMessage>,
     mut shutdown_receiver: ShutdownReceiver,
 ) {
     // Spectator client doesn't need to care about doubling BfBB's framerate
     let mut loop_helper = LoopHelper::builder().build_with_target_rate(60);
+    // Only kept to check that we're still in sync with the server
+    let mut lobby = NetworkedLobby::new(0);
+    let mut resync = Resync::default();
 
     while let Err(TryRecvError::Empty) = shutdown_receiver.try_recv() {
         loop_helper.loop_start();
         while let Ok(msg) = logic_receiver.recv() {
             match msg {
-                Message::ConnectionAccept { player_id } => gui_handle.send(player_id),
-                Message::GameLobbyInfo { lobby } => gui_handle.send(lobby),
+                ServerMessage::ConnectionAccept { player_id, .. } => gui_handle.send(player_id),
+                ServerMessage::GameLobbyInfo { lobby: new_lobby } => {
+                    resync.done();
+                    lobby = new_lobby.clone();
+                    gui_handle.send(new_lobby);
+                }
+                ServerMessage::LobbyDelta {
+                    delta,
+                    sequence,
+                    checksum,
+                } => match lobby.apply_stamped(&delta, sequence, checksum) {
+                    DeltaOutcome::Applied => gui_handle.send(delta),
+                    DeltaOutcome::Stale => (),
+                    DeltaOutcome::Desync => resync.request(&network_sender),
+                },
                 _ => continue,
             }
         }
@@ -51,7 +66,7 @@ pub fn start_spectator(
 pub fn start_game(
     gui_handle: GuiHandle,
     network_sender: NetCommandSender,
-    logic_receiver: Receiver<Message>,
+    logic_receiver: Receiver<ServerMessage>,
     mut shutdown_receiver: ShutdownReceiver,
 ) {
     let mut loop_helper = LoopHelper::builder()
@@ -63,6 +78,7 @@ pub fn start_game(
         network_sender,
         logic_receiver,
         game: None,
+        resync: Resync::default(),
     };
 
     while let Err(TryRecvError::Empty) = shutdown_receiver.try_recv() {
@@ -78,25 +94,52 @@ pub fn start_game(
 struct Logic<I> {
     gui_handle: GuiHandle,
     network_sender: NetCommandSender,
-    logic_receiver: Receiver<Message>,
+    logic_receiver: Receiver<ServerMessage>,
     game: Option<ClashGame<I>>,
+    resync: Resync,
+}
+
+/// Tracks whether we've asked the server for a new lobby, so that we only ask once.
+#[derive(Default)]
+struct Resync {
+    requested: bool,
+}
+
+impl Resync {
+    fn request(&mut self, network_sender: &NetCommandSender) {
+        if self.requested {
+            return;
+        }
+        tracing::warn!("Lobby is out of sync with the server, requesting a new copy");
+        self.requested = true;
+        network_sender
+            .try_send(NetCommand::Send(ClientMessage::Resync))
+            .unwrap();
+    }
+
+    /// Call when a new lobby is received from the server.
+    fn done(&mut self) {
+        self.requested = false;
+    }
 }
 
 impl<I: InterfaceProvider> Logic<I> {
     #[instrument(skip_all, name = "Logic")]
     fn update(&mut self) {
-        let Some(game) = self.game.as_mut() else { return };
+        let Some(game) = self.game.as_mut() else {
+            return;
+        };
         match game.update(&self.network_sender) {
             Err(InterfaceError::Unhooked) => {
                 // We lost dolphin
                 // Our local state will be updated when the client accepts this message and responds.
                 self.network_sender
-                    .try_send(NetCommand::Send(Message::Lobby(
+                    .try_send(NetCommand::Send(ClientMessage::Lobby(
                         LobbyMessage::GameCurrentLevel { level: None },
                     )))
                     .unwrap();
                 self.network_sender
-                    .try_send(NetCommand::Send(Message::Lobby(
+                    .try_send(NetCommand::Send(ClientMessage::Lobby(
                         LobbyMessage::PlayerCanStart(false),
                     )))
                     .unwrap();
@@ -109,26 +152,38 @@ impl<I: InterfaceProvider> Logic<I> {
 
     fn update_from_network(&mut self) -> Result<(), InterfaceError> {
         for msg in self.logic_receiver.try_iter() {
-            let action = match msg {
-                Message::ConnectionAccept { player_id } => {
-                    self.game = Some(ClashGame::new(I::default(), player_id));
+            let (delta, sequence, checksum) = match msg {
+                ServerMessage::ConnectionAccept { player_id, .. } => {
+                    // A resumed session keeps its player id, so hold on to our local game state
+                    if self.game.is_none() {
+                        self.game = Some(ClashGame::new(I::default(), player_id));
+                    }
                     self.gui_handle.send(player_id);
                     continue;
                 }
-                Message::GameLobbyInfo { lobby } => {
+                ServerMessage::GameLobbyInfo { lobby } => {
+                    self.resync.done();
                     if let Some(g) = self.game.as_mut() {
                         g.update_lobby(lobby, &mut self.gui_handle);
                     }
                     continue;
                 }
-                Message::Lobby(m) => m,
+                ServerMessage::LobbyDelta {
+                    delta,
+                    sequence,
+                    checksum,
+                } => (delta, sequence, checksum),
                 _ => continue,
             };
 
-            self.game
+            let outcome = self
+                .game
                 .as_mut()
-                .expect("Tried to process a LobbyAction without having a gamemode setup")
-                .message(action, &mut self.gui_handle);
+                .expect("Tried to process a LobbyDelta without having a gamemode setup")
+                .apply_delta(delta, sequence, checksum, &mut self.gui_handle);
+            if outcome == DeltaOutcome::Desync {
+                self.resync.request(&self.network_sender);
+            }
         }
         Ok(())
     }
@@ -1,4 +1,3 @@
-use std::net::{SocketAddr, ToSocketAddrs};
 use std::rc::Rc;
 
 use eframe::egui::{
@@ -14,7 +13,7 @@ use tracing::instrument;
 use crate::gui::main_menu::MainMenu;
 use crate::gui::state::State;
 use crate::gui::PADDING;
-use crate::net;
+use crate::net::{self, ServerAddress};
 
 use super::val_text::ValText;
 use super::UiExt;
@@ -25,7 +24,7 @@ pub struct Clash {
     curr_app: Box<dyn App>,
     displayed_error: Option<anyhow::Error>,
     update_task: Promise<Option<String>>,
-    address: ValText<SocketAddr>,
+    address: ValText<ServerAddress>,
 }
 
 impl Clash {
@@ -45,9 +44,7 @@ impl Clash {
             state,
             displayed_error: None,
             update_task,
-            address: ValText::with_validator(|s| {
-                s.to_socket_addrs().ok().and_then(|mut x| x.next())
-            }),
+            address: ValText::with_validator(|s| s.parse().ok()),
         }
     }
 
@@ -184,7 +181,8 @@ impl Clash {
         //        Additionally, this will be considered invalid if the host is temporarily unreachable
         ui.add_option("Server Address", &mut self.address, |addr| {
             *net::SERVER_ADDRESS.lock().unwrap() = addr
-        });
+        })
+        .on_hover_text("Prefix with tls:// to use an encrypted connection");
         ui.add_option(
             "Use icons for spatula tracker",
             self.state.use_icons.get(),
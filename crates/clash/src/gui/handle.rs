@@ -3,7 +3,11 @@
 //! This handle holds a copy of the GUI's [`Context`] and will
 //! ensure that [`Context::request_repaint`] is called after any message is sent.
 
-use clash_lib::{lobby::NetworkedLobby, PlayerId};
+use clash_lib::{
+    lobby::NetworkedLobby,
+    net::{LobbyDelta, LobbyError, LobbyMessage},
+    PlayerId,
+};
 use eframe::egui::Context;
 
 pub(super) type GuiReceiver = std::sync::mpsc::Receiver<GuiMessage>;
@@ -12,6 +16,29 @@ pub(super) type GuiSender = std::sync::mpsc::Sender<GuiMessage>;
 pub enum GuiMessage {
     LocalPlayer(PlayerId),
     LobbyUpdate(NetworkedLobby),
+    LobbyDelta(LobbyDelta),
+    Connection(ConnectionState),
+    /// The server's message of the day.
+    Motd(String),
+    /// A message from the server's operator.
+    Announcement(String),
+    /// The server refused to perform an action we sent.
+    ActionRejected {
+        action: LobbyMessage,
+        error: LobbyError,
+    },
+}
+
+/// Status of our connection to the server, as reported by the network task.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+    Connecting,
+    Connected,
+    Reconnecting {
+        attempt: usize,
+    },
+    /// The network task has given up on connecting to the server.
+    Failed,
 }
 
 impl From<PlayerId> for GuiMessage {
@@ -26,6 +53,18 @@ impl From<NetworkedLobby> for GuiMessage {
     }
 }
 
+impl From<LobbyDelta> for GuiMessage {
+    fn from(delta: LobbyDelta) -> Self {
+        Self::LobbyDelta(delta)
+    }
+}
+
+impl From<ConnectionState> for GuiMessage {
+    fn from(state: ConnectionState) -> Self {
+        Self::Connection(state)
+    }
+}
+
 #[derive(Clone)]
 pub struct GuiHandle {
     pub(super) context: Context,
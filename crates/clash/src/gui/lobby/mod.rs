@@ -3,10 +3,10 @@ use std::mem::ManuallyDrop;
 use std::rc::Rc;
 use std::thread::JoinHandle;
 
-use clash_lib::lobby::{GamePhase, NetworkedLobby};
-use clash_lib::net::{LobbyMessage, Message};
+use clash_lib::lobby::{GamePhase, NetworkedLobby, MAX_LOBBY_NAME_LEN};
+use clash_lib::net::{ClientMessage, LobbyDelta, LobbyError, LobbyMessage};
 use clash_lib::PlayerId;
-use eframe::egui::{Align, Button, CentralPanel, Layout, SidePanel, Ui};
+use eframe::egui::{Align, Button, CentralPanel, Layout, SidePanel, TopBottomPanel, Ui};
 use eframe::App;
 use itertools::intersperse;
 use tracing::instrument;
@@ -16,14 +16,16 @@ use crate::gui::state::State;
 use crate::gui::PADDING;
 use crate::net::{NetCommand, NetCommandSender};
 use player_ui::PlayerUi;
+use podium::Podium;
 use tracker::Tracker;
 
-use super::handle::{GuiMessage, GuiReceiver};
+use super::handle::{ConnectionState, GuiMessage, GuiReceiver};
 use super::main_menu::MainMenu;
-use super::option_editor::OptionEditor;
+use super::option_editor::{OptionEditor, PasswordText};
 use super::val_text::ValText;
 
 mod player_ui;
+mod podium;
 mod tracker;
 
 #[derive(Debug)]
@@ -38,9 +40,13 @@ pub struct LobbyData {
 impl Drop for LobbyData {
     fn drop(&mut self) {
         // Shutdown game and network threads and wait for them to complete
-        self.network_sender
+        if self
+            .network_sender
             .blocking_send(NetCommand::Disconnect)
-            .expect("Failed to signal network thread to shutdown.");
+            .is_err()
+        {
+            tracing::warn!("Network thread stopped before being asked to.");
+        }
 
         // SAFETY: We are dropping ourselves now, so these fields will never be accessed again.
         let (game_shutdown, network_thread, game_thread) = unsafe {
@@ -70,7 +76,14 @@ pub struct Game {
     lobby_data: LobbyData,
     lobby: NetworkedLobby,
     local_player_id: PlayerId,
+    connection: ConnectionState,
+    motd: Option<String>,
+    /// Shown above the lobby until dismissed, for announcements and becoming host.
+    notice: Option<String>,
     is_host: bool,
+    lobby_name: ValText<String>,
+    password: PasswordText,
+    spectate_password: PasswordText,
     lab_door_cost: ValText<u8>,
     tier_count: ValText<u8>,
     scores: Vec<ValText<u32>>,
@@ -83,7 +96,15 @@ impl Game {
             lobby_data,
             lobby: NetworkedLobby::new(0),
             local_player_id: 0.into(),
+            connection: ConnectionState::Connecting,
+            motd: None,
+            notice: None,
             is_host: false,
+            lobby_name: ValText::with_validator(|text| {
+                (text.chars().count() <= MAX_LOBBY_NAME_LEN).then(|| text.to_owned())
+            }),
+            password: Default::default(),
+            spectate_password: Default::default(),
             lab_door_cost: ValText::with_validator(|text| {
                 text.parse::<u8>().ok().filter(|&n| n > 0 && n <= 82)
             }),
@@ -108,28 +129,94 @@ impl App for Game {
                 }
                 GuiMessage::LobbyUpdate(new_lobby) => {
                     self.is_host = new_lobby.host_id == Some(self.local_player_id);
-                    self.lab_door_cost.set_val(new_lobby.options.lab_door_cost);
-                    self.tier_count.set_val(new_lobby.options.tier_count);
-                    self.scores
-                        .resize_with(new_lobby.options.tier_count as usize, ValText::default);
-                    for (i, buf) in self.scores.iter_mut().enumerate() {
-                        buf.set_val(new_lobby.options.spat_scores[i]);
-                    }
-
                     self.lobby = new_lobby;
+                    self.sync_options();
+                }
+                GuiMessage::LobbyDelta(delta) => {
+                    let was_host = self.is_host;
+                    self.lobby.apply(&delta);
+                    self.is_host = self.lobby.host_id == Some(self.local_player_id);
+                    if self.is_host && !was_host {
+                        self.notice = Some("You are now the host of this lobby".to_owned());
+                    }
+                    if let LobbyDelta::Action {
+                        action: LobbyMessage::GameOptions { .. },
+                        ..
+                    } = delta
+                    {
+                        self.sync_options();
+                    }
+                }
+                GuiMessage::Connection(ConnectionState::Failed) => {
+                    // The reason we failed is reported as an error separately
+                    self.state.change_app(MainMenu::new(self.state.clone()));
+                }
+                GuiMessage::Connection(state) => self.connection = state,
+                GuiMessage::Motd(motd) => self.motd = Some(motd),
+                GuiMessage::Announcement(message) => self.notice = Some(message),
+                // The error itself is reported to the user separately
+                GuiMessage::ActionRejected { action, error } => {
+                    if error == LobbyError::NeedsHost {
+                        self.is_host = false;
+                    }
+                    // Undo any edits the server didn't accept
+                    if let LobbyMessage::GameOptions { .. } = action {
+                        self.sync_options();
+                    }
                 }
             }
         }
 
+        if let Some(status) = match self.connection {
+            ConnectionState::Connecting => Some("Connecting...".to_owned()),
+            ConnectionState::Reconnecting { attempt } => Some(format!(
+                "Connection lost, reconnecting (attempt {attempt})..."
+            )),
+            ConnectionState::Connected | ConnectionState::Failed => None,
+        } {
+            TopBottomPanel::top("Connection Status").show(ctx, |ui| {
+                ui.vertical_centered(|ui| ui.small(status));
+            });
+        }
+
+        if let Some(motd) = &self.motd {
+            TopBottomPanel::top("Message of the Day").show(ctx, |ui| {
+                ui.vertical_centered(|ui| ui.label(motd));
+            });
+        }
+
+        if let Some(notice) = &self.notice {
+            let mut dismissed = false;
+            TopBottomPanel::top("Notice").show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    dismissed = ui.small_button("✖").on_hover_text("Dismiss").clicked();
+                    ui.strong(notice);
+                });
+            });
+            if dismissed {
+                self.notice = None;
+            }
+        }
+
         SidePanel::left("Player List")
             .resizable(false)
             .show(ctx, |ui| {
                 ui.add_space(PADDING);
                 // TODO: Cache this
-                let mut players = self.lobby.players.values().collect::<Vec<_>>();
-                players.sort_by(|&a, &b| a.menu_order.cmp(&b.menu_order));
-                for player in players {
+                let mut players = self.lobby.players.iter().collect::<Vec<_>>();
+                players.sort_by_key(|(_, p)| p.menu_order);
+                let mut moderation = None;
+                for (&player_id, player) in players {
                     ui.add(PlayerUi::new(player));
+                    if self.is_host && player_id != self.local_player_id {
+                        moderation = moderation.or(moderation_controls(ui, player_id));
+                    }
+                }
+                if let Some(action) = moderation {
+                    self.lobby_data
+                        .network_sender
+                        .blocking_send(NetCommand::Send(ClientMessage::Lobby(action)))
+                        .unwrap();
                 }
             });
         CentralPanel::default().show(ctx, |ui| {
@@ -146,6 +233,17 @@ impl App for Game {
                                 .try_send(LobbyMessage::ResetLobby.into())
                                 .unwrap();
                         }
+                        if ui
+                            .add_enabled(self.is_host, Button::new("End Game"))
+                            .on_hover_text("Ends the game now and shows the final standings.")
+                            .on_disabled_hover_text("Only the host can end the game.")
+                            .clicked()
+                        {
+                            self.lobby_data
+                                .network_sender
+                                .try_send(LobbyMessage::GameEnd.into())
+                                .unwrap();
+                        }
                     });
                 }
                 GamePhase::Finished => self.paint_end(ui),
@@ -157,7 +255,7 @@ impl App for Game {
                         .on_hover_text("Copy Lobby ID to Clipboard")
                         .clicked()
                     {
-                        ctx.output().copied_text = format!("{:X}", self.lobby.lobby_id.0);
+                        ctx.output().copied_text = self.lobby.lobby_id.to_string();
                     }
                     if ui.button("Leave").clicked() {
                         self.state.change_app(MainMenu::new(self.state.clone()));
@@ -168,7 +266,70 @@ impl App for Game {
     }
 }
 
+/// Host controls for another player in the lobby, returning the action the host picked.
+fn moderation_controls(ui: &mut Ui, player_id: PlayerId) -> Option<LobbyMessage> {
+    let mut action = None;
+    ui.horizontal(|ui| {
+        if ui
+            .small_button("Make Host")
+            .on_hover_text("Make this player the host of the lobby")
+            .clicked()
+        {
+            action = Some(LobbyMessage::TransferHost { player_id });
+        }
+        if ui
+            .small_button("Kick")
+            .on_hover_text("Remove this player from the lobby, they can join again")
+            .clicked()
+        {
+            action = Some(LobbyMessage::KickPlayer { player_id });
+        }
+        ui.menu_button("Ban", |ui| {
+            if ui
+                .button("Ban Player")
+                .on_hover_text("Remove this player and keep them from joining again")
+                .clicked()
+            {
+                action = Some(LobbyMessage::BanPlayer {
+                    player_id,
+                    ban_ip: false,
+                });
+                ui.close_menu();
+            }
+            if ui
+                .button("Ban Player and IP")
+                .on_hover_text("Also keep anyone at the same IP address from joining or spectating")
+                .clicked()
+            {
+                action = Some(LobbyMessage::BanPlayer {
+                    player_id,
+                    ban_ip: true,
+                });
+                ui.close_menu();
+            }
+        });
+    });
+    ui.add_space(PADDING);
+    action
+}
+
 impl Game {
+    /// Update the option editors to reflect the lobby's current options
+    fn sync_options(&mut self) {
+        let options = &self.lobby.options;
+        self.lobby_name.set_val(options.name.clone());
+        self.password.set_protected(options.password.is_set());
+        self.spectate_password
+            .set_protected(options.spectate_password.is_set());
+        self.lab_door_cost.set_val(options.lab_door_cost);
+        self.tier_count.set_val(options.tier_count);
+        self.scores
+            .resize_with(options.tier_count as usize, ValText::default);
+        for (i, buf) in self.scores.iter_mut().enumerate() {
+            buf.set_val(options.spat_scores[i]);
+        }
+    }
+
     fn paint_options(&mut self, ui: &mut Ui) {
         ui.heading("Lobby Options");
         ui.separator();
@@ -182,6 +343,50 @@ impl Game {
     fn options_controls(&mut self, ui: &mut Ui) {
         let mut updated_options = Cow::Borrowed(&self.lobby.options);
 
+        ui.add(
+            OptionEditor::new("Lobby Name", &mut self.lobby_name, |name| {
+                updated_options.to_mut().name = name;
+            })
+            .enabled(self.is_host),
+        )
+        .on_hover_text("Shown in the lobby browser, leave empty to use the host's name");
+
+        ui.add(
+            OptionEditor::new("Public", updated_options.public, |x| {
+                updated_options.to_mut().public = x;
+            })
+            .enabled(self.is_host),
+        )
+        .on_hover_text("List this lobby in the lobby browser so anyone can join");
+
+        ui.add(
+            OptionEditor::new("Password", &mut self.password, |password| {
+                updated_options.to_mut().password = password;
+            })
+            .enabled(self.is_host),
+        )
+        .on_hover_text("Required to join this lobby, leave empty to let anyone join");
+
+        ui.add(
+            OptionEditor::new("Allow Spectators", updated_options.allow_spectators, |x| {
+                updated_options.to_mut().allow_spectators = x;
+            })
+            .enabled(self.is_host),
+        );
+
+        let allow_spectators = updated_options.allow_spectators;
+        ui.add(
+            OptionEditor::new(
+                "Spectator Password",
+                &mut self.spectate_password,
+                |password| {
+                    updated_options.to_mut().spectate_password = password;
+                },
+            )
+            .enabled(self.is_host && allow_spectators),
+        )
+        .on_hover_text("Required to spectate this lobby, separate from the lobby password");
+
         ui.add(
             OptionEditor::new("New Game+", updated_options.ng_plus, |x| {
                 updated_options.to_mut().ng_plus = x;
@@ -200,6 +405,14 @@ impl Game {
         )
         .on_hover_text("Spatulas required to enter Chum Bucket Labs");
 
+        ui.add(
+            OptionEditor::new("Tie Breaker", updated_options.tie_breaker, |x| {
+                updated_options.to_mut().tie_breaker = x;
+            })
+            .enabled(self.is_host),
+        )
+        .on_hover_text("How players with the same score are placed when the game ends");
+
         ui.collapsing("Debug Options", |ui| {
             ui.add(
                 OptionEditor::new("Tier Count", &mut self.tier_count, |n| {
@@ -222,7 +435,7 @@ impl Game {
         if let Cow::Owned(options) = updated_options {
             self.lobby_data
                 .network_sender
-                .blocking_send(NetCommand::Send(Message::Lobby(
+                .blocking_send(NetCommand::Send(ClientMessage::Lobby(
                     LobbyMessage::GameOptions { options },
                 )))
                 .unwrap();
@@ -271,7 +484,9 @@ impl Game {
         if start_game_response.clicked() {
             self.lobby_data
                 .network_sender
-                .try_send(NetCommand::Send(Message::Lobby(LobbyMessage::GameBegin)))
+                .try_send(NetCommand::Send(ClientMessage::Lobby(
+                    LobbyMessage::GameBegin,
+                )))
                 .unwrap();
         }
     }
@@ -279,14 +494,8 @@ impl Game {
     fn paint_end(&mut self, ui: &mut Ui) {
         Tracker::new(&self.state, &self.lobby, self.local_player_id).ui(ui);
 
+        ui.add(Podium::new(&self.lobby));
         ui.vertical_centered(|ui| {
-            // Calculate who won the game, eventually this may be determined by the server or logic thread,
-            // as it may differ by gamemode
-            // TODO: Handle tie-breaker
-            if let Some(winner) = self.lobby.players.values().max_by_key(|&p| p.score) {
-                ui.label(format!("{} Wins!", winner.options.name));
-            }
-
             if ui.button("Reset").clicked() {
                 self.lobby_data
                     .network_sender
@@ -25,19 +25,21 @@ impl<'a> Widget for PlayerUi<'a> {
             TextStyle::Body.resolve(ui.style()),
             color,
         );
-        let score_galley = ui.painter().layout_no_wrap(
-            format!("Score: {}", player.score),
-            TextStyle::Body.resolve(ui.style()),
-            color,
-        );
-        let room_galley = ui.painter().layout_no_wrap(
-            player
-                .current_level
-                .map(|r| r.to_string())
-                .unwrap_or_else(|| "? ? ?".to_string()),
-            TextStyle::Small.resolve(ui.style()),
-            color,
-        );
+        let score_text = match player.latency {
+            Some(latency) => format!("Score: {}  ({}ms)", player.score, latency.as_millis()),
+            None => format!("Score: {}", player.score),
+        };
+        let score_galley =
+            ui.painter()
+                .layout_no_wrap(score_text, TextStyle::Body.resolve(ui.style()), color);
+        let room_text = match (player.connected, player.current_level) {
+            (false, _) => "Offline".to_string(),
+            (true, Some(level)) => level.to_string(),
+            (true, None) => "? ? ?".to_string(),
+        };
+        let room_galley =
+            ui.painter()
+                .layout_no_wrap(room_text, TextStyle::Small.resolve(ui.style()), color);
 
         let name_size = name_galley.size();
         let score_size = score_galley.size();
@@ -0,0 +1,60 @@
+use clash_lib::lobby::NetworkedLobby;
+use eframe::egui::{Grid, Response, RichText, Ui, Widget};
+use itertools::intersperse;
+
+/// The final standings of a finished game, as decided by the server.
+pub struct Podium<'a> {
+    lobby: &'a NetworkedLobby,
+}
+
+impl<'a> Podium<'a> {
+    pub fn new(lobby: &'a NetworkedLobby) -> Self {
+        Self { lobby }
+    }
+}
+
+impl<'a> Widget for Podium<'a> {
+    fn ui(self, ui: &mut Ui) -> Response {
+        // Players who left after the game ended are no longer shown
+        let standings = self
+            .lobby
+            .standings
+            .iter()
+            .filter_map(|s| Some((s.place, self.lobby.players.get(&s.player_id)?)))
+            .collect::<Vec<_>>();
+
+        ui.vertical_centered(|ui| {
+            let winners = standings
+                .iter()
+                .filter(|(place, _)| *place == 1)
+                .map(|(_, p)| p.options.name.as_str())
+                .collect::<Vec<_>>();
+            match winners.len() {
+                0 => {}
+                1 => {
+                    ui.heading(format!("{} Wins!", winners[0]));
+                }
+                _ => {
+                    let names = intersperse(winners, " and ").collect::<String>();
+                    ui.heading(format!("{names} Tie!"));
+                }
+            }
+
+            Grid::new("Podium").num_columns(3).show(ui, |ui| {
+                for (place, player) in standings {
+                    let place = match place {
+                        1 => "🥇".to_owned(),
+                        2 => "🥈".to_owned(),
+                        3 => "🥉".to_owned(),
+                        _ => format!("{place}th"),
+                    };
+                    ui.label(RichText::new(place).heading());
+                    ui.label(RichText::new(&player.options.name).color(player.options.color()));
+                    ui.label(player.score.to_string());
+                    ui.end_row();
+                }
+            });
+        })
+        .response
+    }
+}
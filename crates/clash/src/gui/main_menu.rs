@@ -1,14 +1,18 @@
 use std::{mem::ManuallyDrop, rc::Rc};
 
 use clash_lib::{
-    net::{LobbyMessage, Message},
+    lobby::{GamePhase, LobbySummary},
+    net::{ClientMessage, LobbyMessage, ServerMessage},
     player::PlayerOptions,
-    LobbyId,
+    LobbyId, MAX_PLAYERS,
 };
 use eframe::{
-    egui::{Align, Button, CentralPanel, Layout, TextEdit, TopBottomPanel},
+    egui::{
+        Align, Button, CentralPanel, Context, Grid, Layout, ScrollArea, TextEdit, TopBottomPanel,
+    },
     App,
 };
+use poll_promise::Promise;
 use tracing::instrument;
 
 use crate::gui::BORDER;
@@ -29,6 +33,8 @@ pub struct MainMenu {
     submenu: Submenu,
     player_name: String,
     lobby_id: ValText<LobbyId>,
+    password: String,
+    lobbies: Option<Promise<anyhow::Result<Vec<LobbySummary>>>>,
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
@@ -36,6 +42,7 @@ pub enum Submenu {
     Root,
     Host,
     Join,
+    Browse,
 }
 
 impl MainMenu {
@@ -44,14 +51,9 @@ impl MainMenu {
             state,
             submenu: Submenu::Root,
             player_name: Default::default(),
-            // TODO: atm it is not strictly true that the lobby_id must be 8 digits,
-            //  since it's just a random u32. When this is resolved on the server-side,
-            //  we need to validate it here as well.
-            lobby_id: ValText::with_validator(|text| {
-                u32::from_str_radix(text.strip_prefix("0x").unwrap_or(text), 16)
-                    .map(|v| v.into())
-                    .ok()
-            }),
+            lobby_id: ValText::with_validator(|text| text.parse().ok()),
+            password: Default::default(),
+            lobbies: None,
         }
     }
 }
@@ -73,6 +75,10 @@ impl App for MainMenu {
                         if ui.button("Join Game").clicked() {
                             self.submenu = Submenu::Join;
                         }
+                        if ui.button("Browse Lobbies").clicked() {
+                            self.refresh_lobbies();
+                            self.submenu = Submenu::Browse;
+                        }
                         if ui.button("Host Game").clicked() {
                             self.submenu = Submenu::Host;
                         }
@@ -93,11 +99,11 @@ impl App for MainMenu {
                             let lobby_data = self.spawn_net(ctx.clone(), false);
                             lobby_data
                                 .network_sender
-                                .try_send(NetCommand::Send(Message::GameHost))
+                                .try_send(NetCommand::Send(ClientMessage::GameHost))
                                 .unwrap();
                             lobby_data
                                 .network_sender
-                                .try_send(NetCommand::Send(Message::Lobby(
+                                .try_send(NetCommand::Send(ClientMessage::Lobby(
                                     LobbyMessage::PlayerOptions {
                                         options: PlayerOptions {
                                             name: self.player_name.clone(),
@@ -128,6 +134,7 @@ impl App for MainMenu {
                             .hint_text("Lobby ID")
                             .password(true),
                     );
+                    self.password_field(ui);
 
                     ui.horizontal(|ui| {
                         let mut join_button = ui.add_enabled(
@@ -135,54 +142,22 @@ impl App for MainMenu {
                             Button::new("Join Game"),
                         );
                         if !self.lobby_id.is_valid() {
-                            join_button = join_button.on_disabled_hover_text(
-                                "Lobby ID must be an 8 digit hexadecimal number",
-                            );
+                            join_button = join_button
+                                .on_disabled_hover_text("Lobby ID must be a 6 character code");
                         }
                         if self.player_name.is_empty() {
                             join_button =
                                 join_button.on_disabled_hover_text("Player Name is required")
                         }
                         if join_button.clicked() {
-                            let lobby_data = self.spawn_net(ctx.clone(), false);
-                            lobby_data
-                                .network_sender
-                                .try_send(NetCommand::Send(Message::GameJoin {
-                                    lobby_id: self.lobby_id.get_val().unwrap(),
-                                    spectate: false,
-                                }))
-                                .unwrap();
-                            lobby_data
-                                .network_sender
-                                .try_send(NetCommand::Send(Message::Lobby(
-                                    LobbyMessage::PlayerOptions {
-                                        options: PlayerOptions {
-                                            name: self.player_name.clone(),
-                                            color: (0, 0, 0),
-                                        },
-                                    },
-                                )))
-                                .unwrap();
-                            self.state
-                                .change_app(Game::new(self.state.clone(), lobby_data));
+                            self.join(ctx, self.lobby_id.get_val().unwrap());
                         }
 
                         let spectate_button = ui
                             .add_enabled(self.lobby_id.is_valid(), Button::new("Spectate"))
-                            .on_disabled_hover_text(
-                                "Lobby ID must be an 8 digit hexadecimal number",
-                            );
+                            .on_disabled_hover_text("Lobby ID must be a 6 character code");
                         if spectate_button.clicked() {
-                            let lobby_data = self.spawn_net(ctx.clone(), true);
-                            lobby_data
-                                .network_sender
-                                .try_send(NetCommand::Send(Message::GameJoin {
-                                    lobby_id: self.lobby_id.get_val().unwrap(),
-                                    spectate: true,
-                                }))
-                                .unwrap();
-                            self.state
-                                .change_app(Game::new(self.state.clone(), lobby_data));
+                            self.spectate(ctx, self.lobby_id.get_val().unwrap());
                         }
                     });
 
@@ -192,26 +167,164 @@ impl App for MainMenu {
                     ui.add_space(BORDER);
                 });
             }
+            Submenu::Browse => {
+                TopBottomPanel::top("Title").show(ctx, |ui| {
+                    ui.label("Browse Lobbies");
+                });
+                TopBottomPanel::bottom("Browse Panel").show(ctx, |ui| {
+                    ui.add(TextEdit::singleline(&mut self.player_name).hint_text("Name"));
+                    self.password_field(ui);
+                    ui.horizontal(|ui| {
+                        if ui.button("Refresh").clicked() {
+                            self.refresh_lobbies();
+                        }
+                        if ui.button("Back").clicked() {
+                            self.submenu = Submenu::Root;
+                        }
+                    });
+                    ui.add_space(BORDER);
+                });
+                CentralPanel::default().show(ctx, |ui| self.paint_lobby_list(ctx, ui));
+            }
         }
     }
 }
 
 impl MainMenu {
+    fn password_field(&mut self, ui: &mut eframe::egui::Ui) {
+        ui.add(
+            TextEdit::singleline(&mut self.password)
+                .hint_text("Lobby Password (if needed)")
+                .password(true),
+        );
+    }
+
+    fn refresh_lobbies(&mut self) {
+        self.lobbies = Some(net::spawn_promise(net::list_lobbies()));
+    }
+
+    fn paint_lobby_list(&mut self, ctx: &Context, ui: &mut eframe::egui::Ui) {
+        let lobbies = match self.lobbies.as_ref().and_then(Promise::ready) {
+            None => {
+                ui.vertical_centered(|ui| ui.spinner());
+                return;
+            }
+            Some(Err(e)) => {
+                ui.label(format!("Couldn't get the lobby list: {e:#}"));
+                return;
+            }
+            Some(Ok(lobbies)) if lobbies.is_empty() => {
+                ui.label("There are no public lobbies right now, why not host one?");
+                return;
+            }
+            Some(Ok(lobbies)) => lobbies,
+        };
+
+        let mut chosen = None;
+        ScrollArea::vertical().show(ui, |ui| {
+            Grid::new("Lobby List").striped(true).show(ui, |ui| {
+                for lobby in lobbies {
+                    match lobby.options.password.is_set() {
+                        true => ui.label("🔒").on_hover_text("A password is needed to join"),
+                        false => ui.label(""),
+                    };
+                    ui.label(&lobby.name);
+                    ui.label(format!("{}/{MAX_PLAYERS}", lobby.player_count));
+                    ui.label(match lobby.game_phase {
+                        GamePhase::Setup => "Waiting",
+                        GamePhase::Playing => "Playing",
+                        GamePhase::Finished => "Finished",
+                    });
+                    let mut options = format!("Lab Door: {}", lobby.options.lab_door_cost);
+                    if lobby.options.ng_plus {
+                        options.push_str(", New Game+");
+                    }
+                    ui.label(options);
+
+                    let can_join = !self.player_name.is_empty()
+                        && lobby.player_count < MAX_PLAYERS
+                        && lobby.game_phase == GamePhase::Setup;
+                    let join_button = ui
+                        .add_enabled(can_join, Button::new("Join"))
+                        .on_disabled_hover_text(
+                            "A name is required, and the lobby must have room and not have started",
+                        );
+                    if join_button.clicked() {
+                        chosen = Some((lobby.lobby_id, false));
+                    }
+                    let spectate_button = ui
+                        .add_enabled(lobby.options.allow_spectators, Button::new("Spectate"))
+                        .on_disabled_hover_text("This lobby can't be spectated");
+                    if spectate_button.clicked() {
+                        chosen = Some((lobby.lobby_id, true));
+                    }
+                    ui.end_row();
+                }
+            });
+        });
+
+        match chosen {
+            Some((lobby_id, false)) => self.join(ctx, lobby_id),
+            Some((lobby_id, true)) => self.spectate(ctx, lobby_id),
+            None => (),
+        }
+    }
+
+    fn join(&self, ctx: &Context, lobby_id: LobbyId) {
+        let lobby_data = self.spawn_net(ctx.clone(), false);
+        lobby_data
+            .network_sender
+            .try_send(NetCommand::Send(ClientMessage::GameJoin {
+                lobby_id,
+                spectate: false,
+                password: (!self.password.is_empty()).then(|| self.password.clone()),
+            }))
+            .unwrap();
+        lobby_data
+            .network_sender
+            .try_send(NetCommand::Send(ClientMessage::Lobby(
+                LobbyMessage::PlayerOptions {
+                    options: PlayerOptions {
+                        name: self.player_name.clone(),
+                        color: (0, 0, 0),
+                    },
+                },
+            )))
+            .unwrap();
+        self.state
+            .change_app(Game::new(self.state.clone(), lobby_data));
+    }
+
+    fn spectate(&self, ctx: &Context, lobby_id: LobbyId) {
+        let lobby_data = self.spawn_net(ctx.clone(), true);
+        lobby_data
+            .network_sender
+            .try_send(NetCommand::Send(ClientMessage::GameJoin {
+                lobby_id,
+                spectate: true,
+                password: (!self.password.is_empty()).then(|| self.password.clone()),
+            }))
+            .unwrap();
+        self.state
+            .change_app(Game::new(self.state.clone(), lobby_data));
+    }
+
     fn spawn_net(&self, gui_ctx: eframe::egui::Context, spectator: bool) -> LobbyData {
         let (network_sender, network_receiver) = tokio::sync::mpsc::channel::<NetCommand>(32);
-        let (logic_sender, logic_receiver) = std::sync::mpsc::channel::<Message>();
+        let (logic_sender, logic_receiver) = std::sync::mpsc::channel::<ServerMessage>();
+        let (gui_sender, gui_receiver) = std::sync::mpsc::channel();
+        let gui_handle = GuiHandle {
+            context: gui_ctx,
+            sender: gui_sender,
+        };
         let network_thread = net::spawn(
             network_receiver,
             logic_sender,
             self.state.error_sender.clone(),
+            gui_handle.clone(),
         );
 
         // Start Game Thread
-        let (gui_sender, gui_receiver) = std::sync::mpsc::channel();
-        let gui_handle = GuiHandle {
-            context: gui_ctx,
-            sender: gui_sender,
-        };
         let (game_shutdown, shutdown_receiver) = tokio::sync::oneshot::channel();
         let game_thread = {
             let network_sender = network_sender.clone();
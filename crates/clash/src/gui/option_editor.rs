@@ -1,5 +1,6 @@
+use clash_lib::lobby::{Password, TieBreaker};
 use eframe::{
-    egui::{Checkbox, Response, TextEdit, Ui, Widget, WidgetText},
+    egui::{Checkbox, ComboBox, Response, TextEdit, Ui, Widget, WidgetText},
     epaint::Color32,
 };
 
@@ -28,7 +29,7 @@ impl<'a, In, Out> OptionEditor<'a, In, Out> {
     }
 }
 
-impl<'a, T: Copy> Widget for OptionEditor<'a, &'a mut ValText<T>, T> {
+impl<'a, T: Clone> Widget for OptionEditor<'a, &'a mut ValText<T>, T> {
     fn ui(mut self, ui: &mut Ui) -> eframe::egui::Response {
         ui.horizontal(|ui| {
             if !self.input.is_valid() {
@@ -60,7 +61,74 @@ impl<'a> Widget for OptionEditor<'a, bool, bool> {
     }
 }
 
-impl<'a, T: Copy> Widget for OptionEditor<'a, &'a mut [ValText<T>], (usize, T)> {
+impl<'a> Widget for OptionEditor<'a, TieBreaker, TieBreaker> {
+    fn ui(mut self, ui: &mut Ui) -> Response {
+        ui.horizontal(|ui| {
+            ui.label(self.label.clone());
+            ui.add_enabled_ui(self.enabled, |ui| {
+                ComboBox::from_id_source(self.label.text())
+                    .selected_text(self.input.to_string())
+                    .show_ui(ui, |ui| {
+                        for option in TieBreaker::ALL {
+                            if ui
+                                .selectable_value(&mut self.input, option, option.to_string())
+                                .changed()
+                            {
+                                (self.on_changed)(self.input);
+                            }
+                        }
+                    });
+            });
+        })
+        .response
+    }
+}
+
+/// Text entered for a lobby password.
+///
+/// The server never sends passwords back, so this only knows whether one is set. A new password
+/// is reported once the host is done typing it, and clearing the text removes the password.
+#[derive(Default)]
+pub struct PasswordText {
+    text: String,
+    edited: bool,
+    protected: bool,
+}
+
+impl PasswordText {
+    pub fn set_protected(&mut self, protected: bool) {
+        self.protected = protected;
+    }
+}
+
+impl<'a> Widget for OptionEditor<'a, &'a mut PasswordText, Password> {
+    fn ui(mut self, ui: &mut Ui) -> Response {
+        ui.horizontal(|ui| {
+            ui.label(self.label);
+            let hint = match self.input.protected {
+                true => "Set, type to change",
+                false => "None",
+            };
+            let res = ui.add_enabled(
+                self.enabled,
+                TextEdit::singleline(&mut self.input.text)
+                    .password(true)
+                    .hint_text(hint),
+            );
+            self.input.edited |= res.changed();
+            if res.lost_focus() && self.input.edited {
+                self.input.edited = false;
+                (self.on_changed)(match self.input.text.is_empty() {
+                    true => Password::None,
+                    false => Password::New(self.input.text.clone()),
+                });
+            }
+        })
+        .response
+    }
+}
+
+impl<'a, T: Clone> Widget for OptionEditor<'a, &'a mut [ValText<T>], (usize, T)> {
     fn ui(mut self, ui: &mut Ui) -> Response {
         ui.collapsing(self.label, |ui| {
             for (i, input) in self.input.iter_mut().enumerate() {
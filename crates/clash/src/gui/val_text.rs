@@ -13,7 +13,7 @@ pub struct ValText<T> {
     validator: Box<dyn Fn(&str) -> Option<T>>,
 }
 
-impl<T: Copy> ValText<T> {
+impl<T: Clone> ValText<T> {
     pub fn with_validator(validator: impl Fn(&str) -> Option<T> + 'static) -> Self {
         Self {
             text: Default::default(),
@@ -23,7 +23,7 @@ impl<T: Copy> ValText<T> {
     }
 
     pub fn get_val(&self) -> Option<T> {
-        self.val
+        self.val.clone()
     }
 
     pub fn is_valid(&self) -> bool {
@@ -1,26 +1,37 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Display;
 use std::net::ToSocketAddrs;
+use std::path::PathBuf;
+use std::str::FromStr;
 use std::sync::mpsc::Sender;
 use std::sync::Mutex;
+use std::time::Duration;
 use std::{future::Future, net::SocketAddr};
 
+use clash_lib::lobby::LobbySummary;
+use clash_lib::net::tls::{self, ServerName, TlsConnector};
 use clash_lib::net::{
-    connection::{self, ConnectionRx},
-    LobbyMessage, Message,
+    connection::{self, ConnectionRx, ConnectionTx},
+    Capabilities, ClientMessage, FrameError, LobbyError, LobbyMessage, ProtocolError, ResumeToken,
+    ServerMessage, PROTOCOL_VERSION,
 };
 use futures::TryFutureExt;
 use once_cell::sync::Lazy;
 use poll_promise::Promise;
 use semver::Version;
 use serde::Deserialize;
-use tokio::{net::TcpStream, runtime::Runtime};
+use tokio::{net::TcpStream, runtime::Runtime, select};
 use tokio::{sync::mpsc, task::JoinHandle};
 use tracing::instrument;
 
+use crate::gui::handle::{ConnectionState, GuiHandle, GuiMessage};
+
 pub type NetCommandReceiver = mpsc::Receiver<NetCommand>;
 pub type NetCommandSender = mpsc::Sender<NetCommand>;
 
 static RUNTIME: Lazy<Runtime> = Lazy::new(|| Runtime::new().unwrap());
-pub static SERVER_ADDRESS: Lazy<Mutex<SocketAddr>> = Lazy::new(|| Mutex::new(load_ip_address()));
+pub static SERVER_ADDRESS: Lazy<Mutex<ServerAddress>> =
+    Lazy::new(|| Mutex::new(load_server_address()));
 
 #[instrument]
 pub async fn check_for_updates() -> Option<String> {
@@ -67,151 +78,432 @@ pub async fn check_for_updates() -> Option<String> {
 #[derive(Clone, Debug)]
 pub enum NetCommand {
     Disconnect,
-    Send(Message),
+    Send(ClientMessage),
 }
 
 impl<T> From<T> for NetCommand
 where
-    Message: From<T>,
+    ClientMessage: From<T>,
 {
     fn from(msg: T) -> Self {
         Self::Send(msg.into())
     }
 }
 
+/// Delays between attempts to (re)connect to the server. We give up once they've all been used.
+const RECONNECT_BACKOFF: [Duration; 6] = [
+    Duration::from_millis(250),
+    Duration::from_millis(500),
+    Duration::from_secs(1),
+    Duration::from_secs(2),
+    Duration::from_secs(4),
+    Duration::from_secs(8),
+];
+
 /// Entry point to the network. Spawns the network task on the preconfigured [`Runtime`]
 pub fn spawn(
     receiver: NetCommandReceiver,
-    logic_sender: Sender<Message>,
+    logic_sender: Sender<ServerMessage>,
     error_sender: Sender<anyhow::Error>,
+    gui_handle: GuiHandle,
 ) -> JoinHandle<()> {
-    RUNTIME.spawn(net_task(receiver, logic_sender, error_sender))
+    RUNTIME.spawn(net_task(receiver, logic_sender, error_sender, gui_handle))
 }
 
-#[instrument(skip_all, name = "Network")]
-async fn net_task(
-    mut receiver: NetCommandReceiver,
-    logic_sender: Sender<Message>,
-    error_sender: Sender<anyhow::Error>,
-) {
-    let ip = load_ip_address();
-    tracing::info!("Connecting to server at '{ip}'");
+/// What we need to know to get back into our lobby after losing connection.
+#[derive(Debug, Default)]
+struct Session {
+    /// The message we used to enter a lobby, if we've sent one yet.
+    join: Option<ClientMessage>,
+    resume_token: Option<ResumeToken>,
+    /// Messages that couldn't be sent while we were disconnected.
+    pending: VecDeque<ClientMessage>,
+    /// Whether the server has accepted the current connection.
+    accepted: bool,
+    /// Whether the server refused the current connection, retrying won't help.
+    rejected: bool,
+    /// Lobby messages sent on the current connection that the server hasn't answered yet.
+    requests: HashMap<u32, LobbyMessage>,
+    next_request_id: u32,
+}
 
-    let addr = { *SERVER_ADDRESS.lock().unwrap() };
-    let sock = TcpStream::connect(addr).await.unwrap();
-    let (mut conn_tx, conn_rx) = connection::from_socket(sock);
-    conn_tx
-        .write_frame(Message::Version {
-            version: crate::VERSION.to_owned(),
-        })
-        .await
-        .unwrap();
+impl Session {
+    fn record(&mut self, msg: &ClientMessage) {
+        if let ClientMessage::GameHost | ClientMessage::GameJoin { .. } = msg {
+            self.join = Some(msg.clone());
+        }
+    }
 
-    let recv_task = tokio::spawn(recv_task(conn_rx, error_sender.clone(), logic_sender));
-    while let Some(command) = receiver.recv().await {
-        // NetCommand should be a Disconnect or Send command
-        let msg = match command {
-            NetCommand::Disconnect => break,
-            NetCommand::Send(m) => m,
+    /// Give lobby messages a request id so that we know which one the server is answering.
+    fn track_request(&mut self, msg: ClientMessage) -> ClientMessage {
+        let ClientMessage::Lobby(message) = msg else {
+            return msg;
         };
-        tracing::debug!("Sending message {msg:#?}");
-        if let Err(e) = conn_tx.write_frame(msg).await {
-            tracing::error!("Error sending message to server. Disconnecting. {e:#?}");
-            error_sender
-                .send(e.into())
-                .expect("GUI has crashed and so will we.");
-            break;
+        let id = self.next_request_id;
+        self.next_request_id = id.wrapping_add(1);
+        self.requests.insert(id, message.clone());
+        ClientMessage::Request { id, message }
+    }
+
+    /// Whether we've entered a lobby, meaning a new connection would need to rejoin it.
+    fn joined(&self) -> bool {
+        self.join.is_some()
+    }
+
+    /// The message to send after connecting to get back into our lobby.
+    ///
+    /// Returns `Err` when we were in a lobby but have no way of getting back into it.
+    fn rejoin_message(&self) -> Result<Option<ClientMessage>, ()> {
+        match (self.resume_token, &self.join) {
+            (_, None) => Ok(None),
+            (Some(token), Some(_)) => Ok(Some(ClientMessage::Resume { token })),
+            // Spectators don't have a place in the lobby, so they can simply join it again
+            (None, Some(m @ ClientMessage::GameJoin { spectate: true, .. })) => Ok(Some(m.clone())),
+            (None, Some(_)) => Err(()),
         }
     }
-    recv_task.abort();
-    tracing::info!("Disconnected from server.")
 }
 
 #[instrument(skip_all, name = "Network")]
-async fn recv_task(
-    mut conn_rx: ConnectionRx,
+async fn net_task(
+    mut receiver: NetCommandReceiver,
+    logic_sender: Sender<ServerMessage>,
     error_sender: Sender<anyhow::Error>,
-    logic_sender: Sender<Message>,
+    mut gui_handle: GuiHandle,
 ) {
+    let mut session = Session::default();
+    let mut failures = 0;
     loop {
-        let incoming = match conn_rx.read_frame().await {
-            Ok(Some(x)) => {
-                tracing::debug!("Received message {x:#?}.");
-                x
-            }
-            Ok(None) => {
-                tracing::info!("Server closed connection. Disconnecting.");
-                break;
+        gui_handle.send(match session.joined() {
+            false => ConnectionState::Connecting,
+            true => ConnectionState::Reconnecting {
+                attempt: failures + 1,
+            },
+        });
+        session.accepted = false;
+        session.rejected = false;
+        // Requests sent on a previous connection will never be answered
+        session.requests.clear();
+
+        let connection = run_connection(
+            &mut session,
+            &mut receiver,
+            &logic_sender,
+            &error_sender,
+            &mut gui_handle,
+        );
+        let error = match connection.await {
+            Ok(()) => {
+                tracing::info!("Disconnected from server.");
+                return;
             }
-            Err(e) => {
-                tracing::error!("Error reading message from server. Disconnecting.\n{e}");
+            Err(e) => e,
+        };
+        tracing::error!("Lost connection to server. {error:#}");
+        if session.accepted {
+            failures = 0;
+        }
+
+        let delay = RECONNECT_BACKOFF.get(failures);
+        if delay.is_none() || session.rejected || session.rejoin_message().is_err() {
+            if !session.rejected {
                 error_sender
-                    .send(e.into())
+                    .send(error.context("Lost connection to server"))
                     .expect("GUI has crashed and so will we.");
-                break;
             }
-        };
+            gui_handle.send(ConnectionState::Failed);
+            break;
+        }
+        failures += 1;
 
-        match incoming {
-            Message::Lobby(act) => process_action(act, &logic_sender),
-            m @ Message::ConnectionAccept { player_id: _ } => {
-                tracing::debug!("ConnectionAccept message got :)");
-                logic_sender.send(m).unwrap();
-                continue;
-            }
-            m @ Message::GameLobbyInfo { lobby: _ } => {
-                logic_sender.send(m).unwrap();
-                continue;
+        // Keep accepting commands while we wait so that the GUI and game logic don't block on us
+        let sleep = tokio::time::sleep(*delay.expect("Checked above"));
+        tokio::pin!(sleep);
+        loop {
+            select! {
+                _ = &mut sleep => break,
+                command = receiver.recv() => match command {
+                    Some(NetCommand::Send(m)) => session.pending.push_back(m),
+                    Some(NetCommand::Disconnect) | None => return,
+                },
             }
-            Message::Error { error } => {
-                tracing::error!("Error from server:\n{error}");
-                error_sender
-                    .send(error.into())
-                    .expect("GUI has crashed and so will we.");
-                continue;
+        }
+    }
+
+    // We've given up, but the GUI still owns our command channel until it returns to the main menu
+    while let Some(command) = receiver.recv().await {
+        if let NetCommand::Disconnect = command {
+            break;
+        }
+    }
+}
+
+/// Serve a single connection to the server.
+///
+/// Returns `Ok` once we've been told to disconnect, or an error if the connection was lost.
+async fn run_connection(
+    session: &mut Session,
+    receiver: &mut NetCommandReceiver,
+    logic_sender: &Sender<ServerMessage>,
+    error_sender: &Sender<anyhow::Error>,
+    gui_handle: &mut GuiHandle,
+) -> anyhow::Result<()> {
+    let (mut conn_tx, mut conn_rx) = connect().await?;
+    if let Ok(Some(m)) = session.rejoin_message() {
+        conn_tx.write_frame(m).await?;
+    }
+    while let Some(m) = session.pending.pop_front() {
+        send(&mut conn_tx, session, error_sender, m).await?;
+    }
+
+    loop {
+        select! {
+            incoming = conn_rx.read_frame() => {
+                let incoming = match incoming {
+                    // The frame was skipped, so the connection is still usable
+                    Err(e @ FrameError::FrameLength { .. }) => {
+                        tracing::error!("{e}");
+                        continue;
+                    }
+                    incoming => incoming?,
+                };
+                let Some(incoming) = incoming else {
+                    anyhow::bail!("Server closed connection.");
+                };
+                tracing::debug!("Received message {incoming:#?}.");
+                match incoming {
+                    m @ ServerMessage::ConnectionAccept {
+                        capabilities,
+                        resume_token,
+                        max_frame_len,
+                        ..
+                    } => {
+                        tracing::info!("Connection accepted with capabilities {capabilities:?}");
+                        session.accepted = true;
+                        session.resume_token = resume_token;
+                        conn_tx.set_max_frame_len(max_frame_len);
+                        if capabilities.contains(Capabilities::COMPRESSION) {
+                            conn_rx.enable_compression();
+                        }
+                        gui_handle.send(ConnectionState::Connected);
+                        if let ServerMessage::ConnectionAccept {
+                            motd: Some(motd), ..
+                        } = &m
+                        {
+                            gui_handle.send(GuiMessage::Motd(motd.clone()));
+                        }
+                        logic_sender.send(m).unwrap();
+                    }
+                    m @ (ServerMessage::GameLobbyInfo { lobby: _ } | ServerMessage::LobbyDelta { .. }) => {
+                        logic_sender.send(m).unwrap();
+                    }
+                    ServerMessage::Ping { nonce } => {
+                        conn_tx.write_frame(ClientMessage::Pong { nonce }).await?;
+                    }
+                    ServerMessage::Response { id, result } => {
+                        let Some(action) = session.requests.remove(&id) else {
+                            tracing::warn!("Received a response to unknown request {id}");
+                            continue;
+                        };
+                        if let Err(error) = result {
+                            tracing::error!("Server rejected {action:?}: {error}");
+                            error_sender
+                                .send(error.into())
+                                .expect("GUI has crashed and so will we.");
+                            gui_handle.send(GuiMessage::ActionRejected { action, error });
+                        }
+                    }
+                    ServerMessage::Error { error } => {
+                        tracing::error!("Error from server:\n{error}");
+                        // An error before we're accepted means the server has refused us, and
+                        // there's no lobby to come back to after being kicked
+                        session.rejected |= !session.accepted
+                            || matches!(
+                                error,
+                                ProtocolError::Kicked
+                                    | ProtocolError::LobbyClosed
+                                    | ProtocolError::Lobby(LobbyError::Banned)
+                            );
+                        error_sender
+                            .send(error.into())
+                            .expect("GUI has crashed and so will we.");
+                    }
+                    ServerMessage::Announcement { message } => {
+                        tracing::info!("Announcement from server: {message}");
+                        gui_handle.send(GuiMessage::Announcement(message));
+                    }
+                    // We only ask for the lobby list from `list_lobbies`
+                    ServerMessage::LobbyList { .. } => {
+                        tracing::warn!("Received an unrequested lobby list");
+                    }
+                }
             }
-            _ => {
-                tracing::error!("Invalid message received from server");
-                continue;
+            command = receiver.recv() => {
+                let msg = match command {
+                    Some(NetCommand::Send(m)) => m,
+                    Some(NetCommand::Disconnect) | None => {
+                        // Let the server know it doesn't need to hold our place in the lobby
+                        let _ = conn_tx.write_frame(ClientMessage::Leave).await;
+                        return Ok(());
+                    }
+                };
+                tracing::debug!("Sending message {msg:#?}");
+                send(&mut conn_tx, session, error_sender, msg).await?;
             }
         }
     }
 }
 
-fn process_action(action: LobbyMessage, logic_sender: &Sender<Message>) {
-    match action {
-        m @ LobbyMessage::GameBegin => {
-            logic_sender.send(Message::Lobby(m)).unwrap();
+/// Send a message to the server, holding on to it to send again if the connection is lost.
+async fn send(
+    conn_tx: &mut ConnectionTx<ClientMessage>,
+    session: &mut Session,
+    error_sender: &Sender<anyhow::Error>,
+    msg: ClientMessage,
+) -> anyhow::Result<()> {
+    // Messages queued while we were disconnected come through here too, so this is the one place
+    // that sees every lobby we enter
+    session.record(&msg);
+    let request = session.track_request(msg.clone());
+    let id = match request {
+        ClientMessage::Request { id, .. } => Some(id),
+        _ => None,
+    };
+    match conn_tx.write_frame(request).await {
+        Ok(()) => Ok(()),
+        // Nothing was sent and trying again won't help, but the connection is still usable
+        Err(e @ FrameError::FrameLength { .. }) => {
+            if let Some(id) = id {
+                session.requests.remove(&id);
+            }
+            error_sender
+                .send(anyhow::Error::from(e).context("Failed to send message to server"))
+                .expect("GUI has crashed and so will we.");
+            Ok(())
         }
-        LobbyMessage::GameEnd => {
-            // This message isn't supposed to do anything until the GUI gets updated.
+        Err(e) => {
+            session.pending.push_front(msg);
+            Err(e.into())
         }
-        // We aren't yet doing partial updates
-        LobbyMessage::ResetLobby => todo!(),
-        LobbyMessage::PlayerOptions { options: _ } => todo!(),
-        LobbyMessage::PlayerCanStart(_) => todo!(),
-        LobbyMessage::GameOptions { options: _ } => todo!(),
-        LobbyMessage::GameCurrentLevel { level: _ } => todo!(),
-        LobbyMessage::GameItemCollected { item: _ } => todo!(),
-    }
-}
-
-fn load_ip_address() -> SocketAddr {
-    if let Ok(mut exe_path) = std::env::current_exe() {
-        exe_path.pop();
-        exe_path.push("ipaddress");
-        if let Ok(ip) = std::fs::read_to_string(exe_path) {
-            return ip
-                .trim()
-                .to_string()
-                .to_socket_addrs()
-                .expect("Invalid server address specified")
-                .next()
-                .expect("Couldn't resolve server address");
+    }
+}
+
+/// Where to find the server, and how to secure our connection to it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ServerAddress {
+    pub addr: SocketAddr,
+    /// When set, connect with TLS and verify the server under this name.
+    pub tls_domain: Option<String>,
+}
+
+impl FromStr for ServerAddress {
+    type Err = std::io::Error;
+
+    /// Parses `host:port` for plaintext connections or `tls://host:port` for encrypted ones.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host, tls) = match s.strip_prefix("tls://") {
+            Some(host) => (host, true),
+            None => (s, false),
+        };
+        let addr = host.to_socket_addrs()?.next().ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "Couldn't resolve server address",
+            )
+        })?;
+        let tls_domain = tls.then(|| {
+            let domain = host.rsplit_once(':').map_or(host, |(domain, _)| domain);
+            domain
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .to_owned()
+        });
+        Ok(Self { addr, tls_domain })
+    }
+}
+
+impl Display for ServerAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.tls_domain {
+            // IPv6 addresses need brackets to be told apart from the port
+            Some(domain) if domain.contains(':') => {
+                write!(f, "tls://[{domain}]:{}", self.addr.port())
+            }
+            Some(domain) => write!(f, "tls://{domain}:{}", self.addr.port()),
+            None => self.addr.fmt(f),
         }
     }
+}
+
+/// Open a connection to the server and introduce ourselves.
+async fn connect() -> anyhow::Result<(ConnectionTx<ClientMessage>, ConnectionRx<ServerMessage>)> {
+    let address = { SERVER_ADDRESS.lock().unwrap().clone() };
+    tracing::info!("Connecting to server at '{address}'");
+    let sock = TcpStream::connect(address.addr).await?;
+    let (mut conn_tx, conn_rx) = match address.tls_domain {
+        Some(domain) => {
+            let stream = tls_connector()?
+                .connect(ServerName::try_from(domain)?, sock)
+                .await?;
+            connection::from_stream::<ClientMessage, ServerMessage>(stream)
+        }
+        None => connection::from_socket(sock),
+    };
+
+    conn_tx
+        .write_frame(ClientMessage::Hello {
+            protocol_version: PROTOCOL_VERSION,
+            client_version: crate::VERSION.to_owned(),
+            capabilities: Capabilities::ALL,
+        })
+        .await?;
+    Ok((conn_tx, conn_rx))
+}
+
+/// Ask the server for its public lobbies.
+pub async fn list_lobbies() -> anyhow::Result<Vec<LobbySummary>> {
+    let (mut conn_tx, mut conn_rx) = connect().await?;
+    conn_tx.write_frame(ClientMessage::ListLobbies).await?;
+    match conn_rx.read_frame().await? {
+        Some(ServerMessage::LobbyList { lobbies }) => Ok(lobbies),
+        Some(ServerMessage::Error { error }) => Err(error.into()),
+        Some(m) => anyhow::bail!("Unexpected response to lobby list request: {m:?}"),
+        None => anyhow::bail!("Server closed connection."),
+    }
+}
+
+fn load_server_address() -> ServerAddress {
+    if let Some(ip) = exe_sibling("ipaddress").and_then(|p| std::fs::read_to_string(p).ok()) {
+        return ip.trim().parse().expect("Invalid server address specified");
+    }
+
+    "127.0.0.1:42932".parse().unwrap()
+}
 
-    "127.0.0.1:42932".to_socket_addrs().unwrap().next().unwrap()
+/// Path of a file that was placed next to our executable, if it exists.
+fn exe_sibling(name: &str) -> Option<PathBuf> {
+    let mut path = std::env::current_exe().ok()?;
+    path.pop();
+    path.push(name);
+    path.exists().then_some(path)
+}
+
+/// Create a connector for encrypted connections.
+///
+/// If a `server.pem` certificate was placed next to our executable, only a server presenting that
+/// exact certificate will be trusted. Otherwise the server is verified using the system's root
+/// certificates.
+fn tls_connector() -> anyhow::Result<TlsConnector> {
+    if let Some(path) = exe_sibling("server.pem") {
+        let cert = tls::load_certs(&path)?.swap_remove(0);
+        return Ok(tls::pinned_connector(cert)?);
+    }
+
+    let native = rustls_native_certs::load_native_certs();
+    for e in native.errors {
+        tracing::warn!("Failed to load system certificate: {e}");
+    }
+    Ok(tls::connector(native.certs)?)
 }
 
 /// Spawns a future on the Tokio runtime and returns a [`Promise`] for it.
@@ -224,3 +516,83 @@ pub fn spawn_promise<T: Send + 'static>(
     let _guard = RUNTIME.enter();
     Promise::spawn_async(future)
 }
+
+#[cfg(test)]
+mod tests {
+    use clash_lib::net::{connection, ClientMessage, ResumeToken, ServerMessage};
+    use tokio::io::duplex;
+
+    use super::{send, ServerAddress, Session};
+
+    #[test]
+    fn rejoin_message() {
+        // Nothing to rejoin before we've entered a lobby
+        let mut session = Session::default();
+        assert!(matches!(session.rejoin_message(), Ok(None)));
+
+        // Players can't get back into their lobby without a token
+        session.record(&ClientMessage::GameHost);
+        assert!(session.rejoin_message().is_err());
+        session.resume_token = Some(ResumeToken(1));
+        assert!(matches!(
+            session.rejoin_message(),
+            Ok(Some(ClientMessage::Resume {
+                token: ResumeToken(1)
+            }))
+        ));
+
+        // Spectators simply join again
+        let mut session = Session::default();
+        session.record(&ClientMessage::GameJoin {
+            lobby_id: 0.into(),
+            spectate: true,
+            password: None,
+        });
+        assert!(matches!(
+            session.rejoin_message(),
+            Ok(Some(ClientMessage::GameJoin { spectate: true, .. }))
+        ));
+    }
+
+    #[tokio::test]
+    async fn join_queued_while_reconnecting() {
+        // Hosting while the first connection attempt is still failing queues the message
+        let mut session = Session::default();
+        session.pending.push_back(ClientMessage::GameHost);
+
+        // It's sent once we're connected, and is remembered so that we can get back in later
+        let (client, _server) = duplex(1024);
+        let (mut conn_tx, _) = connection::from_stream::<ClientMessage, ServerMessage>(client);
+        let (error_sender, _errors) = std::sync::mpsc::channel();
+        while let Some(m) = session.pending.pop_front() {
+            send(&mut conn_tx, &mut session, &error_sender, m)
+                .await
+                .unwrap();
+        }
+        session.resume_token = Some(ResumeToken(1));
+        assert!(matches!(
+            session.rejoin_message(),
+            Ok(Some(ClientMessage::Resume {
+                token: ResumeToken(1)
+            }))
+        ));
+    }
+
+    #[test]
+    fn server_address() {
+        let plain: ServerAddress = "127.0.0.1:42932".parse().unwrap();
+        assert_eq!(plain.tls_domain, None);
+        assert_eq!(plain.to_string(), "127.0.0.1:42932");
+
+        let tls: ServerAddress = "tls://localhost:1234".parse().unwrap();
+        assert_eq!(tls.tls_domain.as_deref(), Some("localhost"));
+        assert_eq!(tls.addr.port(), 1234);
+        assert_eq!(tls.to_string(), "tls://localhost:1234");
+
+        let v6: ServerAddress = "tls://[::1]:1234".parse().unwrap();
+        assert_eq!(v6.tls_domain.as_deref(), Some("::1"));
+        assert_eq!(v6.to_string(), "tls://[::1]:1234");
+
+        assert!("tls://".parse::<ServerAddress>().is_err());
+    }
+}